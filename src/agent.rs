@@ -0,0 +1,381 @@
+//! The agent protocol, spoken between `idemsh` and `idemsh agent` running on a host.
+//!
+//! Every message is a header line `<verb> <argc>` followed by `argc` frames, each a
+//! line with the byte length of the argument followed by the argument itself.
+//! Requests use the `Exec` operation as the verb, responses use `ok` or `err`.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::Duration;
+
+use super::traits::*;
+use super::escalate::{shell_quote, Escalation};
+use super::errors::{Error, ErrorKind, Result as ExecResult};
+
+/// Starts the agent on a host. `{host}` is replaced with the host name and `{agent}`
+/// with the command running the agent itself, quoted as one argument for a command
+/// that runs it with the host's shell, as ssh does.
+pub const DEFAULT_AGENT_COMMAND: &str = "ssh {host} {agent}";
+
+/// The command running the agent on a host, as used for `{agent}`.
+pub const REMOTE_AGENT: &str = "idemsh agent";
+
+fn protocol_error<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+pub fn write_message<W: Write>(w: &mut W, verb: &str, args: &[&str]) -> io::Result<()> {
+    writeln!(w, "{} {}", verb, args.len())?;
+    for arg in args {
+        writeln!(w, "{}", arg.len())?;
+        w.write_all(arg.as_bytes())?;
+    }
+    w.flush()
+}
+
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 { return Ok(None); }
+    Ok(Some(line.trim_end_matches('\n').to_string()))
+}
+
+/// Reads the next message, or `None` once the other side has closed the stream.
+pub fn read_message<R: BufRead>(r: &mut R) -> io::Result<Option<(String, Vec<String>)>> {
+    let header = match read_line(r)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let mut parts = header.splitn(2, ' ');
+    let verb = parts.next().unwrap_or_default().to_string();
+    let argc: usize = parts.next().and_then(|n| n.parse().ok())
+        .ok_or_else(|| protocol_error(format!("Malformed message header: {:?}", header)))?;
+
+    let mut args = Vec::with_capacity(argc);
+    for _ in 0..argc {
+        let len: usize = read_line(r)?.and_then(|n| n.parse().ok())
+            .ok_or_else(|| protocol_error("Malformed argument length"))?;
+        let mut buf = vec![0; len];
+        r.read_exact(&mut buf)?;
+        args.push(String::from_utf8(buf).map_err(|_| protocol_error("Argument is not valid UTF-8"))?);
+    }
+
+    Ok(Some((verb, args)))
+}
+
+/// Encodes a process as pairs of a field name and its value.
+fn encode_process(process: &Process) -> Vec<String> {
+    let mut fields = vec!["program".to_string(), process.program.to_string()];
+
+    for arg in &process.args {
+        fields.extend(vec!["arg".to_string(), arg.to_string()]);
+    }
+    for (key, value) in &process.env {
+        fields.extend(vec!["env".to_string(), format!("{}={}", key, value)]);
+    }
+    if let Some(ref cwd) = process.cwd {
+        fields.extend(vec!["cwd".to_string(), cwd.to_string()]);
+    }
+    if let Some(ref stdin) = process.stdin {
+        fields.extend(vec!["stdin".to_string(), stdin.to_string()]);
+    }
+    if let Some(timeout) = process.timeout {
+        fields.extend(vec!["timeout".to_string(), timeout.as_millis().to_string()]);
+    }
+
+    fields
+}
+
+fn decode_process(fields: &[String]) -> ExecResult<Process> {
+    let mut process = Process::default();
+
+    for field in fields.chunks(2) {
+        match field {
+            [key, value] if key == "program" => process.program = value.to_string(),
+            [key, value] if key == "arg" => process.args.push(value.to_string()),
+            [key, value] if key == "env" => {
+                let mut parts = value.splitn(2, '=');
+                let name = parts.next().unwrap_or_default().to_string();
+                process.env.push((name, parts.next().unwrap_or_default().to_string()));
+            }
+            [key, value] if key == "cwd" => process.cwd = Some(value.to_string()),
+            [key, value] if key == "stdin" => process.stdin = Some(value.to_string()),
+            [key, value] if key == "timeout" => {
+                let millis = value.parse().map_err(|_| Error::message("Invalid process timeout"))?;
+                process.timeout = Some(Duration::from_millis(millis));
+            }
+            _ => return Err(Error::message(format!("Invalid process field: {:?}", field))),
+        }
+    }
+
+    Ok(process)
+}
+
+fn dispatch<E: Exec>(exec: &mut E, verb: &str, args: &[String]) -> ExecResult<Vec<String>> {
+    let arg = |n: usize| args.get(n).map(|s| s.as_str())
+        .ok_or_else(|| Error::message(format!("Missing argument {} for {}", n, verb)));
+
+    match verb {
+        "cd" => exec.change_directory(arg(0)?).map(|_| vec![]),
+        "mkdir" => exec.ensure_directory(arg(0)?).map(|changed| vec![changed.to_string()]),
+        "touch" => exec.ensure_file_exists(arg(0)?).map(|changed| vec![changed.to_string()]),
+        "write" => exec.ensure_file_contents(arg(0)?, FileContents::StaticString(arg(1)?.to_string())).map(|changed| vec![changed.to_string()]),
+        "pwd" => exec.get_cwd().map(|cwd| vec![cwd]),
+        "exists" => exec.path_exists(arg(0)?).map(|exists| vec![exists.to_string()]),
+        "read" => exec.read_file(arg(0)?).map(|contents| contents.into_iter().collect()),
+        "mktemp" => exec.create_temporary(arg(0)? == "dir", arg(1)?).map(|path| vec![path]),
+        "rm" => exec.remove_path(arg(0)?).map(|_| vec![]),
+        "exec" => exec.execute(&decode_process(args)?).map(|output| vec![
+            output.status.to_string(),
+            output.timed_out.to_string(),
+            output.stdout,
+            output.stderr,
+        ]),
+        _ => Err(Error::new(ErrorKind::Unsupported, format!("Unknown agent request: {}", verb))),
+    }
+}
+
+/// Serves requests from `r` against `exec` until the stream is closed or `bye` is received.
+pub fn serve<R: BufRead, W: Write, E: Exec>(r: &mut R, w: &mut W, exec: &mut E) -> io::Result<()> {
+    while let Some((verb, args)) = read_message(r)? {
+        if verb == "bye" { break; }
+
+        match dispatch(exec, &verb, &args) {
+            Ok(reply) => {
+                let reply: Vec<&str> = reply.iter().map(|s| s.as_str()).collect();
+                write_message(w, "ok", &reply)?
+            }
+            Err(e) => write_message(w, "err", &[&e.to_string(), e.kind().name()])?,
+        }
+    }
+
+    Ok(())
+}
+
+/// An `Exec` driver forwarding every operation to an agent over a pair of streams.
+pub struct AgentExec<R: BufRead, W: Write> {
+    reader: R,
+    writer: W,
+    child: Option<Child>,
+    /// The agent command and host this agent was started with.
+    spawned_as: Option<(String, String)>,
+}
+
+impl<R: BufRead, W: Write> AgentExec<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        AgentExec { reader, writer, child: None, spawned_as: None }
+    }
+
+    /// Makes a request, errors of the agent keep their kind and failing to talk to it
+    /// is a remote error.
+    fn request(&mut self, verb: &str, args: &[&str]) -> ExecResult<Vec<String>> {
+        let transport = |e: io::Error| Error::new(ErrorKind::Remote, format!("Lost the connection to the agent: {}", e));
+        write_message(&mut self.writer, verb, args).map_err(transport)?;
+        match read_message(&mut self.reader).map_err(transport)? {
+            Some((ref status, args)) if status == "ok" => Ok(args),
+            Some((ref status, args)) if status == "err" => {
+                let mut args = args.into_iter();
+                let message = args.next().unwrap_or_default();
+                let kind = args.next().map_or(ErrorKind::Other, |name| ErrorKind::from_name(&name));
+                Err(Error::new(kind, message))
+            }
+            Some((status, _)) => Err(Error::new(ErrorKind::Remote, format!("Unexpected agent response: {}", status))),
+            None => Err(Error::new(ErrorKind::Remote, "Agent closed the connection")),
+        }
+    }
+
+    /// Makes a request answered with whether it changed anything.
+    fn request_changed(&mut self, verb: &str, args: &[&str]) -> ExecResult<bool> {
+        Ok(self.request(verb, args)?.first().map(|s| s.as_str()) == Some("true"))
+    }
+}
+
+impl AgentExec<BufReader<ChildStdout>, ChildStdin> {
+    /// Starts the agent for `host` using `command`, see `DEFAULT_AGENT_COMMAND`.
+    pub fn spawn(command: &str, host: &str) -> ExecResult<Self> {
+        let shell = command.replace("{host}", host).replace("{agent}", &shell_quote(REMOTE_AGENT));
+        let mut agent = Self::spawn_shell(&shell, None)?;
+        agent.spawned_as = Some((command.to_string(), host.to_string()));
+        Ok(agent)
+    }
+
+    /// Starts an agent with a shell command, writing `password` as the first line of
+    /// its input when escalating with one.
+    pub fn spawn_shell(shell: &str, password: Option<&str>) -> ExecResult<Self> {
+        let mut command = Command::new("sh");
+        command.arg("-c")
+            .arg(shell)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+
+        // Ctrl-C must not stop the agent, which is still needed to remove temporary
+        // files as the script stops. An ignored SIGINT is kept by sh and ssh, which
+        // still have the terminal for prompts.
+        unsafe {
+            command.pre_exec(|| {
+                libc::signal(libc::SIGINT, libc::SIG_IGN);
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()
+            .map_err(|e| Error::new(ErrorKind::Remote, format!("Unable to start the agent with {}: {}", shell, e)))?;
+
+        let mut writer = child.stdin.take().unwrap();
+        let reader = BufReader::new(child.stdout.take().unwrap());
+
+        if let Some(password) = password {
+            writeln!(writer, "{}", password)?;
+        }
+
+        let mut agent = AgentExec::new(reader, writer);
+        agent.child = Some(child);
+        Ok(agent)
+    }
+}
+
+impl<R: BufRead, W: Write> Drop for AgentExec<R, W> {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = write_message(&mut self.writer, "bye", &[]);
+            let _ = child.wait();
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> Exec for AgentExec<R, W> {
+    fn change_directory(&mut self, dir: &str) -> ExecResult<()> {
+        self.request("cd", &[dir]).map(|_| ())
+    }
+
+    fn ensure_directory(&mut self, local_part: &str) -> ExecResult<bool> {
+        self.request_changed("mkdir", &[local_part])
+    }
+
+    fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+        self.request_changed("touch", &[local_part])
+    }
+
+    fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<bool> {
+        let FileContents::StaticString(contents) = contents;
+        self.request_changed("write", &[local_part, &contents])
+    }
+
+    fn get_cwd(&mut self) -> ExecResult<String> {
+        let reply = self.request("pwd", &[])?;
+        reply.into_iter().next().ok_or_else(|| Error::new(ErrorKind::Remote, "Agent did not return a directory"))
+    }
+
+    fn path_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+        let reply = self.request("exists", &[local_part])?;
+        Ok(reply.first().map(|s| s.as_str()) == Some("true"))
+    }
+
+    fn read_file(&mut self, local_part: &str) -> ExecResult<Option<String>> {
+        Ok(self.request("read", &[local_part])?.into_iter().next())
+    }
+
+    fn create_temporary(&mut self, directory: bool, suffix: &str) -> ExecResult<String> {
+        let kind = if directory { "dir" } else { "file" };
+        let reply = self.request("mktemp", &[kind, suffix])?;
+        reply.into_iter().next().ok_or_else(|| Error::new(ErrorKind::Remote, "Agent did not return a temporary path"))
+    }
+
+    fn remove_path(&mut self, local_part: &str) -> ExecResult<()> {
+        self.request("rm", &[local_part]).map(|_| ())
+    }
+
+    fn execute(&mut self, process: &Process) -> ExecResult<CommandOutput> {
+        let fields = encode_process(process);
+        let fields: Vec<&str> = fields.iter().map(|s| s.as_str()).collect();
+
+        match self.request("exec", &fields)?.as_slice() {
+            [status, timed_out, stdout, stderr] => Ok(CommandOutput {
+                status: status.parse().map_err(|_| Error::new(ErrorKind::Remote, "Agent returned an invalid exit status"))?,
+                timed_out: timed_out == "true",
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+            }),
+            _ => Err(Error::new(ErrorKind::Remote, "Agent returned an invalid process result")),
+        }
+    }
+
+    fn escalate(&mut self, escalation: &Escalation) -> ExecResult<Box<dyn Exec + Send>> {
+        let (command, host) = match self.spawned_as {
+            Some((ref command, ref host)) if command.contains("{agent}") => (command.clone(), host.clone()),
+            _ => return Err(Error::new(ErrorKind::Unsupported, "Cannot become another user, the agent command has no {agent} to wrap")),
+        };
+
+        let shell = command.replace("{host}", &host).replace("{agent}", &shell_quote(&escalation.wrap(REMOTE_AGENT)?));
+        let mut agent = AgentExec::spawn_shell(&shell, escalation.password.as_deref())?;
+        agent.spawned_as = Some((command, host));
+        agent.change_directory(&self.get_cwd()?)?;
+        Ok(Box::new(agent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use super::*;
+    use super::super::local_exec::LocalExec;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut buf = vec![];
+        write_message(&mut buf, "write", &["./afile", "two\nlines"]).unwrap();
+
+        let mut r = Cursor::new(buf);
+        assert_eq!(read_message(&mut r).unwrap(), Some((
+            "write".to_string(),
+            vec!["./afile".to_string(), "two\nlines".to_string()],
+        )));
+        assert_eq!(read_message(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn test_process_roundtrip() {
+        let process = Process {
+            args: vec!["-j4".to_string(), "all".to_string()],
+            env: vec![("CC".to_string(), "clang".to_string())],
+            cwd: Some("./build".to_string()),
+            stdin: Some("".to_string()),
+            timeout: Some(Duration::from_secs(60)),
+            ..Process::new("make")
+        };
+
+        assert_eq!(decode_process(&encode_process(&process)).unwrap(), process);
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut requests = vec![];
+        write_message(&mut requests, "mkdir", &["./agentdir"]).unwrap();
+        write_message(&mut requests, "write", &["./agentdir/afile", "contents"]).unwrap();
+        write_message(&mut requests, "read", &["./agentdir/afile"]).unwrap();
+        write_message(&mut requests, "read", &["./agentdir/missing"]).unwrap();
+        write_message(&mut requests, "frobnicate", &[]).unwrap();
+        write_message(&mut requests, "write", &["./agentdir/bfile", ""]).unwrap();
+        write_message(&mut requests, "rm", &["./agentdir/bfile"]).unwrap();
+
+        let mut local_exec = LocalExec::with_new_relative_working_dir(Path::new("./testing"));
+        let mut responses = vec![];
+        serve(&mut Cursor::new(requests), &mut responses, &mut local_exec).unwrap();
+
+        // Replay the responses through a client
+        let mut agent = AgentExec::new(Cursor::new(responses), vec![]);
+        assert!(agent.ensure_directory("./agentdir").is_ok());
+        assert!(agent.ensure_file_contents("./agentdir/afile", FileContents::StaticString("contents".to_string())).is_ok());
+        assert_eq!(agent.read_file("./agentdir/afile").unwrap(), Some("contents".to_string()));
+        assert_eq!(agent.read_file("./agentdir/missing").unwrap(), None);
+        assert_eq!(agent.ensure_file_exists("./agentdir/afile").unwrap_err().kind(), &ErrorKind::Unsupported);
+        assert!(agent.ensure_file_contents("./agentdir/bfile", FileContents::StaticString("".to_string())).is_ok());
+        assert!(agent.remove_path("./agentdir/bfile").is_ok());
+        assert_eq!(agent.get_cwd().unwrap_err().kind(), &ErrorKind::Remote);
+
+        assert_eq!(std::fs::read_to_string("./testing/agentdir/afile").unwrap(), "contents");
+        assert!(!Path::new("./testing/agentdir/bfile").exists());
+    }
+}
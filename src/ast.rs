@@ -1,3 +1,67 @@
+use std::fmt;
+use std::ops::Deref;
+
+/// Where something is in a script: the file, by its index in the files of the run,
+/// the bytes it covers, and the line and column it starts at, counted from 1.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The span of `start..end` in `source`.
+    pub fn new(file: usize, source: &str, start: usize, end: usize) -> Self {
+        let before = &source[..start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        Span { file, start, end, line, column }
+    }
+}
+
+/// A node of a script with where it was parsed from. Nodes are equal when they are
+/// the same whatever their spans, and are printed without them.
+#[derive(Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+/// A node that was not parsed from a script, with an empty span.
+impl<T> From<T> for Spanned<T> {
+    fn from(node: T) -> Self {
+        Spanned::new(node, Span::default())
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.node.fmt(f)
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum IdemPathLocalPartType {
@@ -11,52 +75,362 @@ pub struct IdemPath(pub Option<String>, pub IdemPathLocalPartType);
 #[derive(Debug, PartialEq, Clone)]
 pub enum IdemResourceType {
     Directory(String),
+    /// A `with` block on a host, which the parser does not produce yet.
+    #[allow(dead_code)]
     Host(String),
     File(String),
+    /// `~~name/` or `(( temporary(dir suffix = "name") ))`, created for a `with` block
+    /// and removed after it.
+    Temporary(Vec<Spanned<IdemParamType>>),
+}
+
+/// `.key` or `[index]` after a variable name.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemAccessor {
+    Key(String),
+    Index(i64),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum IdemValueType {
     LitString(String),
     ExtendedString(String),
-    PathSpec(IdemPath),
+    Integer(i64),
+    Boolean(bool),
+    PathSpec(Spanned<IdemPath>),
+    /// `(())`
+    Empty,
+    List(Vec<Spanned<IdemValueType>>),
+    Map(Vec<(String, Spanned<IdemValueType>)>),
+    /// `$name.key[0]`
+    Variable(String, Vec<IdemAccessor>),
+    /// `(( <command> ))`, evaluated for its result.
+    Command(Box<Spanned<IdemRawCommandType>>),
 }
 
+/// `s/regexp/replacement/g`, the replacement refers to groups with `$1` or `\1`.
 #[derive(Debug, PartialEq, Clone)]
 pub struct IdemReplace {
-    regexp: String,
-    replacement: String,
-    global: bool,
+    pub regexp: String,
+    pub replacement: String,
+    pub global: bool,
+}
+
+/// Restricts a substitution to some lines.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemLineSelector {
+    /// `@#line#`, lines equal to the text.
+    Line(String),
+    /// `/^regexp/`, lines matching the regexp.
+    Regexp(String),
+}
+
+/// Where `$+` adds a line that is not found.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemLineInsert {
+    /// `insertbefore = BOF`
+    Start,
+    /// `insertafter = EOF`, the default.
+    End,
+    /// `insertafter = /regexp/`, after the last matching line.
+    After(String),
+    /// `insertbefore = /regexp/`, before the first matching line.
+    Before(String),
+}
+
+/// `$+ line (/^regexp/)` ensures the line is present, replacing the last line matching
+/// the regexp. `$- line` removes the line, or every line matching the regexp.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemLineInFile {
+    pub line: String,
+    pub present: bool,
+    pub regexp: Option<String>,
+    pub insert: IdemLineInsert,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum IdemEditCommandType {
+    /// Inserts a line at the start of the file, which the parser does not produce yet.
+    #[allow(dead_code)]
     InsertStart(String),
     InsertEnd(String),
+    /// Inserts the second line after the first.
     InsertAfter(String, String),
+    /// Inserts the second line before the first.
+    InsertBefore(String, String),
+    Remove(String),
+    /// A line that must be found, the following commands apply after it.
+    Context(String),
+    /// Substitutes in the lines up to the next context line.
+    Replace(Option<IdemLineSelector>, IdemReplace),
+    /// Like substitutions, applies up to the next context line.
+    LineInFile(IdemLineInFile),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct IdemEdit {
-    commands: Vec<IdemEditCommandType>,
+    pub commands: Vec<IdemEditCommandType>,
+}
+
+/// An edit of a JSON document at a JSON pointer such as `/path/to/object`, the
+/// values are JSON text.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemJsonEdit {
+    /// `@/pointer` followed by a value, an object is merged into an existing object.
+    Merge(String, String),
+    /// `@/pointer = value`
+    Set(String, String),
+    /// `@/pointer (removed)`
+    Remove(String),
+}
+
+/// The body of a `with <file> (edit)` block.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemEditBody {
+    /// Blocks separated by blank lines, merged into the blocks of the file.
+    Blocks(String),
+    /// Lines added with `+` and removed with `-`, anchored by context lines.
+    Lines(IdemEdit),
+    /// Values set at JSON pointers, starting with a line such as `@/path/to/object`.
+    Json(Vec<IdemJsonEdit>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum IdemParamType {
     FlagKeyword(String),
     ShortFlags(Vec<char>),
-    KeyValue(String, IdemValueType),
+    KeyValue(String, Spanned<IdemValueType>),
+    /// A value passed by position to a command.
+    Positional(Spanned<IdemValueType>),
+    /// `(<command> <args>)`, a call to a command defined with `def`.
+    Call(String, Vec<Spanned<IdemParamType>>),
+    /// `when = <condition>`, or `unless = <condition>` with the condition negated.
+    When(IdemCondition),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IdemCompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// The condition of `if`, `unless` and `when =`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemCondition {
+    /// True unless the value is empty, false, 0, an empty string or collection, or a
+    /// failed command.
+    Value(Spanned<IdemValueType>),
+    Compare(Spanned<IdemValueType>, IdemCompareOp, Spanned<IdemValueType>),
+    /// `<path> exists`
+    Exists(Spanned<IdemValueType>),
+    Not(Box<IdemCondition>),
+    And(Box<IdemCondition>, Box<IdemCondition>),
+    Or(Box<IdemCondition>, Box<IdemCondition>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct IdemRawCommandWithPaths {
-    pub paths: Vec<IdemPath>,
-    pub params: Vec<IdemParamType>,
+    pub paths: Vec<Spanned<IdemPath>>,
+    pub params: Vec<Spanned<IdemParamType>>,
+}
+
+/// `using <interpreter> (<params>) ... end`, the body is kept verbatim.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandUsing {
+    pub interpreter: String,
+    pub params: Vec<Spanned<IdemParamType>>,
+    pub script: String,
+}
+
+/// `system (<command>) (<params>)` captures the output of an external command,
+/// `$((<command>))` only returns its result.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandSystem {
+    pub command: String,
+    pub capture: bool,
+    pub params: Vec<Spanned<IdemParamType>>,
+}
+
+/// `with <file> (edit) ... end`
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandEdit {
+    pub path: Spanned<IdemPath>,
+    pub params: Vec<Spanned<IdemParamType>>,
+    pub body: IdemEditBody,
+}
+
+/// A parameter of a `def`. Alternatives such as `(recurse|-r)` bind to the first name.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemDefParam {
+    pub names: Vec<String>,
+    pub default: Option<Spanned<IdemValueType>>,
+    /// `...name` collects the remaining arguments.
+    pub splat: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemDefParams {
+    /// `(pos1, pos2)`
+    Positional(Vec<IdemDefParam>),
+    /// `({ arg1, arg2 })`
+    Keyword(Vec<IdemDefParam>),
+}
+
+/// `def <name>(<params>) ... end`
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandDef {
+    pub name: String,
+    pub params: IdemDefParams,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// A word in the header of a `defblock`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemBlockWord {
+    /// `name`, bound to the word given in its place.
+    Param(String),
+    /// `"keyword"`, which must be given as is.
+    Tag(String),
+}
+
+/// `defblock <name> [<words>] (<params>) [do] ... end`. With `do` the command is
+/// invoked with a block, which its body runs with `call $block`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandDefBlock {
+    pub name: String,
+    pub words: Vec<IdemBlockWord>,
+    pub params: IdemDefParams,
+    pub takes_block: bool,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// `<name> <words> (<args>) ... end`, an invocation of a `defblock`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandBlockCall {
+    pub name: String,
+    pub words: Vec<Spanned<IdemValueType>>,
+    pub args: Vec<Spanned<IdemParamType>>,
+    pub block: Option<Vec<Spanned<IdemRawCommandType>>>,
+}
+
+/// `if <condition> ... else ... end`. `unless` is an `if` with the condition negated,
+/// and `else if` an `if` alone in the `else` branch.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandIf {
+    pub condition: IdemCondition,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+    pub otherwise: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// `handler <name> ... end`, run once after the script when a statement with
+/// `notify = <name>` changed something.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandHandler {
+    pub name: String,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// `try ... rescue [<var>] ... always ... end`. Without a `rescue` clause the error
+/// is raised again once `always` has run.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandTry {
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+    pub rescue: Option<IdemRescue>,
+    pub always: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// The statements run when a `try` block fails, with the error bound to `var`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRescue {
+    pub var: Option<String>,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// `include <file>`, whose statements run in place of it, in the same scope. They
+/// are read by the loader once the including file is parsed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandInclude {
+    pub path: String,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// `import <file> as <name>`, which runs the file in a scope of its own and names
+/// the commands it defines `<name>.<command>`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandImport {
+    pub path: String,
+    pub name: String,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// `remotes [<var> in] <hosts> [as <user>[:<group>]] (<params>) ... end`
+#[derive(Debug, PartialEq, Clone)]
+pub struct IdemRawCommandRemotes {
+    pub var: Option<String>,
+    pub hosts: String,
+    pub become_user: Option<String>,
+    pub params: Vec<Spanned<IdemParamType>>,
+    pub statements: Vec<Spanned<IdemRawCommandType>>,
+}
+
+/// What `each` takes out of the file it loops over, instead of the file itself.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdemExtractor {
+    /// `@/parent/child[*]`, values of a JSON or YAML file where `*` matches any key or index.
+    Path(String),
+    /// `@~/regexp/`, the capture groups of each matching line.
+    Regexp(String),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum IdemRawCommandType {
-    Each(String, IdemValueType, Vec<Box<IdemRawCommandType>>),
+    Each(String, Option<IdemExtractor>, Spanned<IdemValueType>, Vec<Spanned<IdemRawCommandType>>),
     WithPaths(IdemRawCommandWithPaths),
-    WithBlock(IdemResourceType, Option<String>, Vec<Box<IdemRawCommandType>>),
+    WithBlock(IdemResourceType, Option<String>, Vec<Spanned<IdemRawCommandType>>),
+    Remotes(IdemRawCommandRemotes),
+    Using(IdemRawCommandUsing),
+    System(IdemRawCommandSystem),
+    Def(IdemRawCommandDef),
+    Return(Spanned<IdemValueType>),
+    DefBlock(IdemRawCommandDefBlock),
+    BlockCall(IdemRawCommandBlockCall),
+    /// `call $block`
+    Call(Spanned<IdemValueType>),
+    Edit(IdemRawCommandEdit),
+    /// `let name = value`, bound until the end of the enclosing block.
+    Let(String, Spanned<IdemValueType>),
+    If(IdemRawCommandIf),
+    Handler(IdemRawCommandHandler),
+    Try(IdemRawCommandTry),
+    /// `assert <condition>`, with the condition as written.
+    Assert(IdemCondition, String),
+    Include(IdemRawCommandInclude),
+    Import(IdemRawCommandImport),
+}
+
+impl IdemRawCommandType {
+    /// The blocks of statements of the command, for the loader to find the files they
+    /// include. Those of an included file are not among them.
+    pub fn blocks_mut(&mut self) -> Vec<&mut Vec<Spanned<IdemRawCommandType>>> {
+        match self {
+            IdemRawCommandType::Each(_, _, _, statements)
+            | IdemRawCommandType::WithBlock(_, _, statements) => vec![statements],
+            IdemRawCommandType::Remotes(remotes) => vec![&mut remotes.statements],
+            IdemRawCommandType::Def(def) => vec![&mut def.statements],
+            IdemRawCommandType::DefBlock(def) => vec![&mut def.statements],
+            IdemRawCommandType::BlockCall(call) => call.block.iter_mut().collect(),
+            IdemRawCommandType::If(obj) => vec![&mut obj.statements, &mut obj.otherwise],
+            IdemRawCommandType::Handler(handler) => vec![&mut handler.statements],
+            IdemRawCommandType::Try(obj) => {
+                let mut blocks = vec![&mut obj.statements, &mut obj.always];
+                blocks.extend(obj.rescue.as_mut().map(|rescue| &mut rescue.statements));
+                blocks
+            }
+            _ => vec![],
+        }
+    }
 }
@@ -0,0 +1,231 @@
+//! Block-oriented edits, for files made of blocks separated by blank lines.
+//!
+//! The blocks of a template are matched against the blocks of the file by their
+//! first line. A matched block is an anchor: lines of the template missing from it
+//! are inserted below its first line. A template block that does not match is new,
+//! and is inserted before the next anchor in the template, or after the last one.
+
+use std::collections::HashMap;
+
+use crate::errors::{Error, Result as ExecResult};
+
+/// Files with more blocks than this are not searched.
+pub const MAX_BLOCKS: usize = 1000;
+
+#[derive(Debug, PartialEq, Clone)]
+struct Block {
+    /// Blank lines before the block.
+    gap: Vec<String>,
+    lines: Vec<String>,
+}
+
+impl Block {
+    fn key(&self) -> &str {
+        self.lines[0].trim()
+    }
+
+    fn contains(&self, line: &str) -> bool {
+        self.lines.iter().any(|l| l.trim() == line.trim())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Blocks {
+    blocks: Vec<Block>,
+    /// Blank lines after the last block.
+    trailing: Vec<String>,
+}
+
+fn split_blocks(text: &str) -> Blocks {
+    let mut blocks = vec![];
+    let mut gap = vec![];
+    let mut lines: Vec<String> = vec![];
+
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !lines.is_empty() {
+                blocks.push(Block { gap: std::mem::take(&mut gap), lines: std::mem::take(&mut lines) });
+            }
+            gap.push(line.to_string());
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    if !lines.is_empty() {
+        blocks.push(Block { gap: std::mem::take(&mut gap), lines });
+    }
+
+    Blocks { blocks, trailing: gap }
+}
+
+fn join_blocks(blocks: &Blocks, final_newline: bool) -> String {
+    let lines: Vec<&str> = blocks.blocks.iter()
+        .flat_map(|block| block.gap.iter().chain(block.lines.iter()))
+        .chain(blocks.trailing.iter())
+        .map(|line| line.as_str())
+        .collect();
+
+    let mut text = lines.join("\n");
+    if final_newline && !text.is_empty() {
+        text.push('\n');
+    }
+    text
+}
+
+/// Merges the blocks of `template` into `text`, returning the new text.
+pub fn merge_blocks(text: &str, template: &str) -> ExecResult<String> {
+    let mut file = split_blocks(text);
+    let template = split_blocks(template);
+
+    if file.blocks.len() > MAX_BLOCKS {
+        return Err(Error::message(format!("File has {} blocks, more than the {} that can be matched", file.blocks.len(), MAX_BLOCKS)));
+    }
+
+    let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, block) in file.blocks.iter().enumerate() {
+        index.entry(block.key().to_string()).or_default().push(i);
+    }
+
+    // Template blocks sharing a key would be inserted again on every run
+    let mut keys: HashMap<&str, usize> = HashMap::new();
+    for block in &template.blocks {
+        *keys.entry(block.key()).or_default() += 1;
+    }
+    for block in &template.blocks {
+        if keys[block.key()] > 1 {
+            return Err(Error::message(format!("{} template blocks start with {:?}, unable to tell which one to edit", keys[block.key()], block.key())));
+        }
+    }
+
+    // Find the block each template block anchors to, if any
+    let mut anchors = vec![];
+    for block in &template.blocks {
+        match index.get(block.key()).map(|found| found.as_slice()) {
+            None => anchors.push(None),
+            Some([i]) => anchors.push(Some(*i)),
+            Some(found) => {
+                return Err(Error::message(format!("{} blocks start with {:?}, unable to tell which one to edit", found.len(), block.key())));
+            }
+        }
+    }
+
+    // Lines missing from anchored blocks go below the first line
+    for (block, anchor) in template.blocks.iter().zip(&anchors) {
+        if let Some(i) = *anchor {
+            let existing = &mut file.blocks[i];
+            let missing: Vec<String> = block.lines[1..].iter()
+                .filter(|line| !existing.contains(line))
+                .cloned()
+                .collect();
+            existing.lines.splice(1..1, missing);
+        }
+    }
+
+    // New blocks go before the next anchor, inserting from the end keeps indexes valid
+    let mut inserts: Vec<(usize, Block)> = vec![];
+    for (t, (block, anchor)) in template.blocks.iter().zip(&anchors).enumerate() {
+        if anchor.is_some() {
+            continue;
+        }
+
+        let next = anchors[t..].iter().flatten().next();
+        let previous = anchors[..t].iter().flatten().last();
+        let at = match (next, previous) {
+            (Some(&next), _) => next,
+            (None, Some(&previous)) => previous + 1,
+            (None, None) => file.blocks.len(),
+        };
+        inserts.push((at, Block { gap: vec![String::new()], lines: block.lines.clone() }));
+    }
+
+    inserts.sort_by_key(|(at, _)| *at);
+    for (at, mut block) in inserts.into_iter().rev() {
+        if at == 0 {
+            // The new first block takes over the file's leading blank lines
+            if let Some(first) = file.blocks.first_mut() {
+                block.gap = std::mem::replace(&mut first.gap, vec![String::new()]);
+            } else {
+                block.gap = vec![];
+            }
+        }
+        file.blocks.insert(at, block);
+    }
+
+    Ok(join_blocks(&file, text.is_empty() || text.ends_with('\n')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"[main]
+config1 = "value"
+config2 = "value"
+
+[blockb]
+b1 = "value"
+b2 = "value"
+"#;
+
+    #[test]
+    fn test_insert_between_blocks() {
+        let template = "[blocka]\na1 = \"value\"\na2 = \"value\"\n\n[blockb]\n";
+        let merged = merge_blocks(CONFIG, template).unwrap();
+
+        assert_eq!(merged, r#"[main]
+config1 = "value"
+config2 = "value"
+
+[blocka]
+a1 = "value"
+a2 = "value"
+
+[blockb]
+b1 = "value"
+b2 = "value"
+"#);
+        assert_eq!(merge_blocks(&merged, template).unwrap(), merged);
+    }
+
+    #[test]
+    fn test_merge_into_block() {
+        let template = "[blockb]\nb0 = \"value\"\nb2 = \"value\"\n\n[blockc]\nc1 = 1\n";
+        let merged = merge_blocks(CONFIG, template).unwrap();
+
+        assert_eq!(merged, r#"[main]
+config1 = "value"
+config2 = "value"
+
+[blockb]
+b0 = "value"
+b1 = "value"
+b2 = "value"
+
+[blockc]
+c1 = 1
+"#);
+        assert_eq!(merge_blocks(&merged, template).unwrap(), merged);
+    }
+
+    #[test]
+    fn test_insert_first_and_empty_file() {
+        assert_eq!(merge_blocks("\n[main]\nx = 1", "[first]\n\n[main]").unwrap(), "\n[first]\n\n[main]\nx = 1");
+        assert_eq!(merge_blocks("", "[main]\nx = 1\n").unwrap(), "[main]\nx = 1\n");
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        let err = merge_blocks("[a]\nx = 1\n\n[a]\ny = 2\n", "[a]\nz = 3\n").unwrap_err();
+        assert_eq!(err.to_string(), r#"2 blocks start with "[a]", unable to tell which one to edit"#);
+
+        let err = merge_blocks(CONFIG, "[new]\nx = 1\n\n[new]\ny = 2\n").unwrap_err();
+        assert_eq!(err.to_string(), r#"2 template blocks start with "[new]", unable to tell which one to edit"#);
+    }
+
+    #[test]
+    fn test_merge_twice() {
+        let template = "[blocka]\na1 = \"value\"\n\n[blockb]\nb0 = \"value\"\n\n[blockd]\nd1 = 1\n";
+        let merged = merge_blocks(CONFIG, template).unwrap();
+        assert_eq!(merge_blocks(&merged, template).unwrap(), merged);
+    }
+}
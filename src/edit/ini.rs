@@ -0,0 +1,410 @@
+//! Key-aware edits of INI and TOML files.
+//!
+//! A template of `[section]` headers and `key = value` lines is merged into the file.
+//! A key found in its section has its value overwritten, keeping the spacing and the
+//! comment of its line, and a missing key is added after the last key of the section.
+//! A missing section is added after the section before it in the template, before the
+//! section after it, or at the end of the file. Everything else is left as it was.
+
+use crate::errors::{Error, Result as ExecResult};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Syntax {
+    /// `key = value` or `key: value`, `;` and `#` start comments.
+    Ini,
+    /// Values can span lines in arrays, inline tables and multi-line strings.
+    Toml,
+}
+
+impl Syntax {
+    fn is_comment(self, line: &str) -> bool {
+        let line = line.trim_start();
+        line.starts_with('#') || (self == Syntax::Ini && line.starts_with(';'))
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    /// Without the comment, `None` for a line with only a key.
+    value: Option<String>,
+    line: usize,
+    /// The line after the last line of the value.
+    end: usize,
+    /// Where the value starts in the first line.
+    value_start: usize,
+    /// The comment after the value, with the whitespace before it.
+    comment: String,
+}
+
+#[derive(Debug)]
+struct Section {
+    /// `None` for the keys before the first header.
+    name: Option<String>,
+    header: Option<usize>,
+    entries: Vec<Entry>,
+    /// The line after the header or the last key.
+    end: usize,
+}
+
+/// Where the value starting at `start` of line `line` ends, as the index of its
+/// last line and the position of its end within that line.
+fn value_end(lines: &[String], line: usize, start: usize, syntax: Syntax) -> (usize, usize) {
+    let mut depth = 0;
+    let mut quote: Option<&str> = None;
+
+    for (l, text) in lines.iter().enumerate().skip(line) {
+        let from = if l == line { start } else { 0 };
+        let mut end = text.len();
+        let mut i = from;
+        while i < text.len() {
+            let rest = &text[i..];
+            match quote {
+                Some(q) if rest.starts_with(q) => {
+                    quote = None;
+                    i += q.len();
+                    continue;
+                }
+                Some("\"") | Some("\"\"\"") if rest.starts_with('\\') => i += 1,
+                Some(_) => {},
+                None if syntax == Syntax::Toml && (rest.starts_with("\"\"\"") || rest.starts_with("'''")) => {
+                    quote = Some(&rest[..3]);
+                    i += 3;
+                    continue;
+                }
+                None if rest.starts_with('"') || rest.starts_with('\'') => quote = Some(&rest[..1]),
+                None if syntax == Syntax::Toml && (rest.starts_with('[') || rest.starts_with('{')) => depth += 1,
+                None if syntax == Syntax::Toml && (rest.starts_with(']') || rest.starts_with('}')) => depth -= 1,
+                None if syntax == Syntax::Toml && rest.starts_with('#') => {
+                    end = i;
+                    break;
+                }
+                // A comment in an INI value must follow whitespace, `color=#fff` is a value
+                None if (rest.starts_with('#') || rest.starts_with(';')) && text[..i].ends_with(char::is_whitespace) => {
+                    end = i;
+                    break;
+                }
+                None => {},
+            }
+            i += rest.chars().next().map_or(1, char::len_utf8);
+        }
+
+        // Single quoted strings end with the line
+        match quote {
+            Some("\"") | Some("'") => quote = None,
+            _ => {},
+        }
+        if depth <= 0 && quote.is_none() {
+            return (l, end);
+        }
+    }
+
+    (lines.len() - 1, lines[lines.len() - 1].len())
+}
+
+fn parse_entry(lines: &[String], line: usize, syntax: Syntax) -> Entry {
+    let text = &lines[line];
+    let indent = text.len() - text.trim_start().len();
+
+    let mut quote = None;
+    let separator = text.char_indices().skip_while(|&(i, _)| i < indent).find(|&(_, c)| match quote {
+        Some(q) => {
+            if c == q {
+                quote = None;
+            }
+            false
+        }
+        None if c == '"' || c == '\'' => {
+            quote = Some(c);
+            false
+        }
+        None => c == '=' || (syntax == Syntax::Ini && c == ':'),
+    });
+
+    let separator = match separator {
+        Some((i, _)) => i,
+        None => {
+            let (_, end) = value_end(lines, line, indent, syntax);
+            return Entry {
+                key: text[..end].trim().to_string(),
+                value: None,
+                line,
+                end: line + 1,
+                value_start: text.len(),
+                comment: text[text[..end].trim_end().len()..].to_string(),
+            };
+        }
+    };
+
+    let after = &text[separator + 1..];
+    let value_start = separator + 1 + after.len() - after.trim_start().len();
+    let (last, end) = value_end(lines, line, value_start, syntax);
+    let value = if last == line {
+        text[value_start..end].to_string()
+    } else {
+        let mut value = vec![&text[value_start..]];
+        value.extend(lines[line + 1..last].iter().map(|l| l.as_str()));
+        value.push(&lines[last][..end]);
+        value.join("\n")
+    };
+    let value = value.trim_end().to_string();
+    let last_text = &lines[last];
+    let value_end = if last == line { value_start + value.len() } else { value.rsplit('\n').next().map_or(0, str::len) };
+
+    Entry {
+        key: text[indent..separator].trim().to_string(),
+        value: Some(value),
+        line,
+        end: last + 1,
+        value_start,
+        comment: last_text[value_end..].to_string(),
+    }
+}
+
+/// `[name]`, with an optional comment after it.
+fn parse_header(line: &str, syntax: Syntax) -> Option<String> {
+    let line = line.trim();
+    if !line.starts_with('[') {
+        return None;
+    }
+    let close = line.rfind(']')?;
+    let rest = line[close + 1..].trim();
+    if !rest.is_empty() && !syntax.is_comment(rest) {
+        return None;
+    }
+    Some(line[1..close].trim().to_string())
+}
+
+fn parse_sections(lines: &[String], syntax: Syntax) -> Vec<Section> {
+    let mut sections = vec![Section { name: None, header: None, entries: vec![], end: 0 }];
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = &lines[i];
+        if line.trim().is_empty() || syntax.is_comment(line) {
+            i += 1;
+        } else if let Some(name) = parse_header(line, syntax) {
+            sections.push(Section { name: Some(name), header: Some(i), entries: vec![], end: i + 1 });
+            i += 1;
+        } else {
+            let entry = parse_entry(lines, i, syntax);
+            let section = sections.last_mut().unwrap();
+            i = entry.end;
+            section.end = entry.end;
+            section.entries.push(entry);
+        }
+    }
+
+    sections
+}
+
+fn section_label(name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("[{}]", name),
+        None => "the top of the file".to_string(),
+    }
+}
+
+/// The only section named `name`.
+fn find_section<'s>(sections: &'s [Section], name: &Option<String>) -> ExecResult<Option<&'s Section>> {
+    let found: Vec<&Section> = sections.iter().filter(|s| s.name == *name).collect();
+    match found.len() {
+        0 | 1 => Ok(found.first().cloned()),
+        n => Err(Error::message(format!("{} sections are named {}, unable to tell which one to edit", n, section_label(name)))),
+    }
+}
+
+/// Where keys are added to a section, the top of the file goes before the first header.
+fn insert_point(lines: &[String], sections: &[Section], section: &Section) -> usize {
+    if section.name.is_some() || !section.entries.is_empty() {
+        return section.end;
+    }
+    let mut at = sections.get(1).and_then(|s| s.header).unwrap_or(lines.len());
+    while at > 0 && lines[at - 1].trim().is_empty() {
+        at -= 1;
+    }
+    at
+}
+
+/// Sets one key of the template in `section`, `list` keys appear more than once in
+/// the template and each of their values is added.
+fn merge_entry(lines: &mut Vec<String>, template: &[String], entry: &Entry, list: bool, syntax: Syntax, name: &Option<String>) -> ExecResult<()> {
+    let sections = parse_sections(lines, syntax);
+    let section = find_section(&sections, name)?.expect("the section was found before");
+    let found: Vec<&Entry> = section.entries.iter().filter(|e| e.key == entry.key).collect();
+
+    if found.iter().any(|e| e.value == entry.value) {
+        return Ok(());
+    }
+
+    let existing = match (found.as_slice(), list, &entry.value) {
+        ([], _, _) | (_, true, _) => None,
+        ([existing], false, _) => Some(*existing),
+        (_, false, _) => {
+            return Err(Error::message(format!("{} appears {} times in {}, unable to tell which one to set", entry.key, found.len(), section_label(name))));
+        }
+    };
+
+    let new_lines: Vec<String> = match (existing, &entry.value) {
+        (Some(existing), Some(value)) if existing.value.is_some() => {
+            let first = &lines[existing.line][..existing.value_start];
+            if value.is_empty() {
+                vec![format!("{}{}", first.trim_end(), existing.comment)]
+            } else {
+                let mut replaced: Vec<String> = value.lines().map(|l| l.to_string()).collect();
+                replaced[0] = format!("{}{}", first, replaced[0]);
+                replaced.last_mut().unwrap().push_str(&existing.comment);
+                replaced
+            }
+        }
+        _ => template[entry.line..entry.end].to_vec(),
+    };
+
+    match existing {
+        Some(existing) => {
+            lines.splice(existing.line..existing.end, new_lines);
+        }
+        None => {
+            let at = insert_point(lines, &sections, section);
+            lines.splice(at..at, new_lines);
+        }
+    }
+    Ok(())
+}
+
+/// Adds a section of the template the file does not have.
+fn insert_section(lines: &mut Vec<String>, template: &[String], template_sections: &[Section], t: usize, syntax: Syntax) -> ExecResult<()> {
+    let sections = parse_sections(lines, syntax);
+    let added = &template_sections[t];
+    let mut new_lines: Vec<String> = added.header.iter().map(|&h| template[h].to_string()).collect();
+    for entry in &added.entries {
+        new_lines.extend_from_slice(&template[entry.line..entry.end]);
+    }
+
+    // After the section before it in the template
+    for previous in template_sections[..t].iter().rev().filter(|s| s.name.is_some()) {
+        if let Some(section) = find_section(&sections, &previous.name)? {
+            let at = insert_point(lines, &sections, section);
+            if at < lines.len() && !lines[at].trim().is_empty() {
+                new_lines.push(String::new());
+            }
+            new_lines.insert(0, String::new());
+            lines.splice(at..at, new_lines);
+            return Ok(());
+        }
+    }
+
+    // Before the section after it, and the comments above it
+    for next in &template_sections[t + 1..] {
+        if let Some(&Section { header: Some(mut at), .. }) = find_section(&sections, &next.name)? {
+            while at > 0 && syntax.is_comment(&lines[at - 1]) {
+                at -= 1;
+            }
+            new_lines.push(String::new());
+            lines.splice(at..at, new_lines);
+            return Ok(());
+        }
+    }
+
+    while lines.last().is_some_and(|l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    if !lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines.extend(new_lines);
+    Ok(())
+}
+
+/// Merges the sections and keys of `template` into `text`, returning the new text.
+pub fn merge_keys(text: &str, template: &str, syntax: Syntax) -> ExecResult<String> {
+    let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+    let template: Vec<String> = template.lines().map(|l| l.to_string()).collect();
+    let template_sections = parse_sections(&template, syntax);
+
+    for (t, added) in template_sections.iter().enumerate() {
+        if added.name.is_none() && added.entries.is_empty() {
+            continue;
+        }
+        if find_section(&parse_sections(&lines, syntax), &added.name)?.is_none() {
+            insert_section(&mut lines, &template, &template_sections, t, syntax)?;
+            continue;
+        }
+        for entry in &added.entries {
+            let list = added.entries.iter().filter(|e| e.key == entry.key).count() > 1;
+            merge_entry(&mut lines, &template, entry, list, syntax, &added.name)?;
+        }
+    }
+
+    let mut merged = lines.join("\n");
+    if (text.is_empty() || text.ends_with('\n')) && !merged.is_empty() {
+        merged.push('\n');
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edit::Format;
+
+    const PHP_INI: &str = "; PHP settings
+[PHP]
+memory_limit = 128M ; per script
+extension=mysqli
+extension=gd
+
+; Dates
+[Date]
+date.timezone = Europe/Paris
+";
+
+    #[test]
+    fn test_merge_ini() {
+        let template = "[PHP]\nmemory_limit = 256M\nextension=gd\nextension=intl\n\n[Session]\nsession.save_path = /tmp\n\n[Date]\ndate.timezone = UTC\n";
+        let merged = merge_keys(PHP_INI, template, Syntax::Ini).unwrap();
+
+        assert_eq!(merged, "; PHP settings
+[PHP]
+memory_limit = 256M ; per script
+extension=mysqli
+extension=gd
+extension=intl
+
+[Session]
+session.save_path = /tmp
+
+; Dates
+[Date]
+date.timezone = UTC
+");
+        assert_eq!(merge_keys(&merged, template, Syntax::Ini).unwrap(), merged);
+
+        // An empty value clears the existing one
+        let template = "[PHP]\nopen_basedir =\n";
+        let cleared = merge_keys("[PHP]\nopen_basedir = /var/www ; jail\n", template, Syntax::Ini).unwrap();
+        assert_eq!(cleared, "[PHP]\nopen_basedir = ; jail\n");
+        assert_eq!(merge_keys(&cleared, template, Syntax::Ini).unwrap(), cleared);
+
+        let err = merge_keys(PHP_INI, "[PHP]\nextension=intl\n", Syntax::Ini).unwrap_err();
+        assert_eq!(err.to_string(), "extension appears 2 times in [PHP], unable to tell which one to set");
+    }
+
+    #[test]
+    fn test_merge_toml() {
+        let cargo = "[package]\nname = \"app\" # the name\nversion = \"0.1.0\"\nauthors = [\n    \"a\",\n]\n\n[dependencies]\nnom = \"4\"\n";
+        let template = "[package]\nversion = \"0.2.0\"\nauthors = [\"a\", \"b\"]\n\n[features]\ndefault = []\n";
+        let merged = merge_keys(cargo, template, Syntax::Toml).unwrap();
+
+        assert_eq!(merged, "[package]\nname = \"app\" # the name\nversion = \"0.2.0\"\nauthors = [\"a\", \"b\"]\n\n[features]\ndefault = []\n\n[dependencies]\nnom = \"4\"\n");
+        assert_eq!(merge_keys(&merged, template, Syntax::Toml).unwrap(), merged);
+    }
+
+    #[test]
+    fn test_top_level_and_empty() {
+        let merged = merge_keys("# settings\n\n[a]\nx = 1\n", "title = \"t\"\n\n[b]\ny = 2\n", Syntax::Toml).unwrap();
+        assert_eq!(merged, "# settings\ntitle = \"t\"\n\n[a]\nx = 1\n\n[b]\ny = 2\n");
+        assert_eq!(merge_keys("", "[Unit]\nDescription=App\n", Syntax::Ini).unwrap(), "[Unit]\nDescription=App\n");
+        assert_eq!(Format::from_path("/etc/systemd/system/app.service"), Some(Format::Ini));
+    }
+}
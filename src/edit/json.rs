@@ -0,0 +1,529 @@
+//! JSON edits at JSON pointers, keeping the formatting of the document.
+//!
+//! The document is parsed with the position of every value, and each edit replaces,
+//! inserts or removes only the text of the values it changes. New values are written
+//! with the indentation of the document. A value that is already equal is left alone,
+//! so applying an edit again changes nothing.
+
+use std::fmt;
+
+use crate::ast::IdemJsonEdit;
+use crate::errors::{Error, Result as ExecResult};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// Kept as written, `1.0` and `1` are different.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// A value of the document and where its text starts and ends.
+#[derive(Debug)]
+struct Node {
+    start: usize,
+    end: usize,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Scalar(Json),
+    Array(Vec<Node>),
+    Object(Vec<Member>),
+}
+
+#[derive(Debug)]
+struct Member {
+    key: String,
+    /// Where the quoted key starts.
+    start: usize,
+    value: Node,
+}
+
+impl Node {
+    fn to_json(&self) -> Json {
+        match self.kind {
+            NodeKind::Scalar(ref value) => value.clone(),
+            NodeKind::Array(ref items) => Json::Array(items.iter().map(Node::to_json).collect()),
+            NodeKind::Object(ref members) => Json::Object(members.iter().map(|m| (m.key.to_string(), m.value.to_json())).collect()),
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> Error {
+        let line = self.text[..self.pos.min(self.text.len())].matches('\n').count() + 1;
+        Error::message(format!("Invalid JSON on line {}: {}", line, message))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> ExecResult<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> ExecResult<Node> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let kind = match self.peek() {
+            Some(b'{') => NodeKind::Object(self.object()?),
+            Some(b'[') => NodeKind::Array(self.array()?),
+            Some(b'"') => NodeKind::Scalar(Json::String(self.string()?)),
+            Some(b'-') | Some(b'0'..=b'9') => NodeKind::Scalar(self.number()),
+            Some(_) => NodeKind::Scalar(self.literal()?),
+            None => return Err(self.error("expected a value")),
+        };
+        Ok(Node { start, end: self.pos, kind })
+    }
+
+    fn object(&mut self) -> ExecResult<Vec<Member>> {
+        let mut members = vec![];
+        self.expect(b'{')?;
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(members);
+        }
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push(Member { key, start, value: self.value()? });
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(members);
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> ExecResult<Vec<Node>> {
+        let mut items = vec![];
+        self.expect(b'[')?;
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> ExecResult<String> {
+        self.pos += 1;
+        let mut s = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| self.error("invalid \\u escape"))?;
+                        s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    Some(c) => s.push(c),
+                    None => break,
+                },
+                c => s.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn number(&mut self) -> Json {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        Json::Number(self.text[start..self.pos].to_string())
+    }
+
+    fn literal(&mut self) -> ExecResult<Json> {
+        for (word, value) in &[("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+            if self.text[self.pos..].starts_with(word) {
+                self.pos += word.len();
+                return Ok(value.clone());
+            }
+        }
+        Err(self.error("expected a value"))
+    }
+
+    fn document(&mut self) -> ExecResult<Node> {
+        let node = self.value()?;
+        self.skip_whitespace();
+        if self.pos < self.text.len() {
+            return Err(self.error("unexpected text after the value"));
+        }
+        Ok(node)
+    }
+}
+
+fn parse_document(text: &str) -> ExecResult<Node> {
+    Parser { text, pos: 0 }.document()
+}
+
+pub fn parse_json(text: &str) -> ExecResult<Json> {
+    parse_document(text).map(|node| node.to_json())
+}
+
+/// `/a/b~1c` is `["a", "b/c"]`, `/` alone is the whole document.
+pub fn parse_pointer(pointer: &str) -> ExecResult<Vec<String>> {
+    match pointer {
+        "" | "/" => Ok(vec![]),
+        _ if pointer.starts_with('/') => Ok(pointer[1..].split('/').map(|key| key.replace("~1", "/").replace("~0", "~")).collect()),
+        _ => Err(Error::message(format!("Invalid JSON pointer {:?}, it must start with '/'", pointer))),
+    }
+}
+
+pub fn format_pointer(path: &[String]) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.iter().map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1"))).collect()
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes `value` on several lines indented by `unit` from `indent`, or on one line
+/// without a unit.
+fn write_json(value: &Json, unit: Option<&str>, indent: &str, out: &mut String) {
+    let (open, close, items): (char, char, Vec<(Option<&str>, &Json)>) = match value {
+        Json::Null => return out.push_str("null"),
+        Json::Bool(b) => return out.push_str(&b.to_string()),
+        Json::Number(n) => return out.push_str(n),
+        Json::String(s) => return write_string(s, out),
+        Json::Array(items) => ('[', ']', items.iter().map(|item| (None, item)).collect()),
+        Json::Object(members) => ('{', '}', members.iter().map(|(key, value)| (Some(key.as_str()), value)).collect()),
+    };
+
+    out.push(open);
+    let inner = format!("{}{}", indent, unit.unwrap_or(""));
+    for (i, (key, item)) in items.iter().enumerate() {
+        out.push_str(if i > 0 { "," } else { "" });
+        match unit {
+            Some(_) => {
+                out.push('\n');
+                out.push_str(&inner);
+            }
+            None if i > 0 => out.push(' '),
+            None => {},
+        }
+        if let Some(key) = key {
+            write_string(key, out);
+            out.push_str(": ");
+        }
+        write_json(item, unit, &inner, out);
+    }
+    if unit.is_some() && !items.is_empty() {
+        out.push('\n');
+        out.push_str(indent);
+    }
+    out.push(close);
+}
+
+/// The value on one line, `{"a": [1, 2]}`.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        write_json(self, None, "", &mut out);
+        f.write_str(&out)
+    }
+}
+
+/// The indentation of the first indented line, two spaces if there is none.
+fn indent_unit(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .find(|indent| !indent.is_empty())
+        .unwrap_or("  ")
+        .to_string()
+}
+
+/// The indentation of the line `pos` is on.
+fn line_indent(text: &str, pos: usize) -> &str {
+    let line = &text[text[..pos].rfind('\n').map_or(0, |i| i + 1)..];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+struct Document<'a> {
+    text: &'a str,
+    root: Node,
+}
+
+impl<'a> Document<'a> {
+    fn parse(text: &'a str) -> ExecResult<Self> {
+        Ok(Document { text, root: parse_document(text)? })
+    }
+
+    /// Documents on one line stay on one line, unless they are an empty object.
+    fn unit(&self) -> Option<String> {
+        if self.text.trim().contains('\n') || self.text.trim() == "{}" { Some(indent_unit(self.text)) } else { None }
+    }
+
+    fn write(&self, value: &Json, at: usize) -> String {
+        let mut out = String::new();
+        write_json(value, self.unit().as_deref(), line_indent(self.text, at), &mut out);
+        out
+    }
+
+    fn splice(&self, start: usize, end: usize, with: &str) -> String {
+        format!("{}{}{}", &self.text[..start], with, &self.text[end..])
+    }
+
+    /// The node at `path`, or the object the first missing key would be added to
+    /// and the number of keys found.
+    fn find(&self, path: &[String]) -> ExecResult<(&Node, usize)> {
+        let mut node = &self.root;
+        for (i, key) in path.iter().enumerate() {
+            match node.kind {
+                NodeKind::Object(ref members) => match members.iter().find(|m| m.key == *key) {
+                    Some(member) => node = &member.value,
+                    None => return Ok((node, i)),
+                },
+                _ => return Err(Error::message(format!("{} crosses {}, which is not an object", format_pointer(path), format_pointer(&path[..i])))),
+            }
+        }
+        Ok((node, path.len()))
+    }
+
+    fn set(&self, path: &[String], value: &Json) -> ExecResult<String> {
+        let (node, found) = self.find(path)?;
+        if found == path.len() {
+            if node.to_json() == *value {
+                return Ok(self.text.to_string());
+            }
+            return Ok(self.splice(node.start, node.end, &self.write(value, node.start)));
+        }
+
+        // Missing objects are created along with the value
+        let value = path[found + 1..].iter().rev().fold(value.clone(), |value, key| Json::Object(vec![(key.to_string(), value)]));
+        let key = &path[found];
+        let members = match node.kind {
+            NodeKind::Object(ref members) => members,
+            _ => unreachable!(),
+        };
+
+        let (first, last) = match (members.first(), members.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(self.splice(node.start, node.end, &self.write(&Json::Object(vec![(key.to_string(), value)]), node.start))),
+        };
+        let mut member = String::new();
+        if self.text[node.start..first.start].contains('\n') {
+            let indent = line_indent(self.text, first.start);
+            member.push_str(",\n");
+            member.push_str(indent);
+            write_string(key, &mut member);
+            member.push_str(": ");
+            write_json(&value, self.unit().as_deref(), indent, &mut member);
+        } else {
+            member.push_str(", ");
+            write_string(key, &mut member);
+            member.push_str(": ");
+            write_json(&value, None, "", &mut member);
+        }
+        Ok(self.splice(last.value.end, last.value.end, &member))
+    }
+
+    fn remove(&self, path: &[String]) -> ExecResult<String> {
+        let (key, parent) = match path.split_last() {
+            Some(split) => split,
+            None => return Err(Error::message("The whole document can not be removed")),
+        };
+        let (node, found) = self.find(parent)?;
+        let members = match node.kind {
+            NodeKind::Object(ref members) if found == parent.len() => members,
+            NodeKind::Object(_) => return Ok(self.text.to_string()),
+            _ => return Err(Error::message(format!("{} is not an object", format_pointer(parent)))),
+        };
+
+        let i = match members.iter().position(|m| m.key == *key) {
+            Some(i) => i,
+            None => return Ok(self.text.to_string()),
+        };
+        let (start, end) = match (members.get(i + 1), i.checked_sub(1).map(|p| &members[p])) {
+            (Some(next), _) => (members[i].start, next.start),
+            (None, Some(previous)) => (previous.value.end, members[i].value.end),
+            (None, None) => (node.start + 1, node.end - 1),
+        };
+        Ok(self.splice(start, end, ""))
+    }
+}
+
+/// Sets the value at `path`, objects are merged into existing objects key by key.
+fn merge(text: &str, path: &[String], value: &Json) -> ExecResult<String> {
+    let document = Document::parse(text)?;
+    let (node, found) = document.find(path)?;
+    match (&node.kind, value) {
+        (NodeKind::Object(_), Json::Object(members)) if found == path.len() => {
+            let mut text = text.to_string();
+            for (key, value) in members {
+                let mut path = path.to_vec();
+                path.push(key.to_string());
+                text = merge(&text, &path, value)?;
+            }
+            Ok(text)
+        }
+        _ => document.set(path, value),
+    }
+}
+
+/// Applies the edits to the JSON document in `text`, an empty file is an empty object.
+pub fn apply_json_edits(text: &str, edits: &[IdemJsonEdit]) -> ExecResult<String> {
+    let mut edited = if text.trim().is_empty() { "{}\n".to_string() } else { text.to_string() };
+    for edit in edits {
+        edited = match edit {
+            IdemJsonEdit::Merge(pointer, value) => merge(&edited, &parse_pointer(pointer)?, &parse_json(value)?)?,
+            IdemJsonEdit::Set(pointer, value) => Document::parse(&edited)?.set(&parse_pointer(pointer)?, &parse_json(value)?)?,
+            IdemJsonEdit::Remove(pointer) => Document::parse(&edited)?.remove(&parse_pointer(pointer)?)?,
+        };
+    }
+    Ok(edited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+    "name": "app",
+    "path": {
+        "to": {
+            "object": {
+                "a1": "value"
+            }
+        }
+    },
+    "tags": ["a", "b"]
+}
+"#;
+
+    fn merge(pointer: &str, value: &str) -> IdemJsonEdit {
+        IdemJsonEdit::Merge(pointer.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_merge() {
+        let edits = vec![
+            merge("/path/to/object", r#"{ "a1": "value", "a4": "value", "a5": { "x": 1 } }"#),
+            merge("/", r#"{ "name": "web", "inline": {} }"#),
+            merge("/inline/deep/key", "true"),
+        ];
+        let edited = apply_json_edits(CONFIG, &edits).unwrap();
+
+        assert_eq!(edited, r#"{
+    "name": "web",
+    "path": {
+        "to": {
+            "object": {
+                "a1": "value",
+                "a4": "value",
+                "a5": {
+                    "x": 1
+                }
+            }
+        }
+    },
+    "tags": ["a", "b"],
+    "inline": {
+        "deep": {
+            "key": true
+        }
+    }
+}
+"#);
+        assert_eq!(apply_json_edits(&edited, &edits).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let edits = vec![
+            IdemJsonEdit::Set("/path".to_string(), "[1, 2]".to_string()),
+            IdemJsonEdit::Remove("/tags".to_string()),
+            IdemJsonEdit::Remove("/missing".to_string()),
+        ];
+        assert_eq!(apply_json_edits(CONFIG, &edits).unwrap(), "{\n    \"name\": \"app\",\n    \"path\": [\n        1,\n        2\n    ]\n}\n");
+
+        let edited = apply_json_edits(r#"{"a": 1, "b": {"c": 2}}"#, &[
+            IdemJsonEdit::Remove("/a".to_string()),
+            IdemJsonEdit::Remove("/b/c".to_string()),
+            merge("/b", r#"{"d": "e", "f": [true]}"#),
+        ]).unwrap();
+        assert_eq!(edited, r#"{"b": {"d": "e", "f": [true]}}"#);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = apply_json_edits(CONFIG, &[merge("/name/first", "1")]).unwrap_err();
+        assert_eq!(err.to_string(), "/name/first crosses /name, which is not an object");
+
+        let err = apply_json_edits("{\n  \"a\": 1,\n}", &[merge("/a", "2")]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid JSON on line 3: expected a key");
+
+        assert_eq!(apply_json_edits("", &[merge("/a~1b", "\"c\"")]).unwrap(), "{\n  \"a/b\": \"c\"\n}\n");
+    }
+}
@@ -0,0 +1,363 @@
+//! Line-oriented edits, written like a diff.
+//!
+//! Commands are applied in order with a cursor that moves down the file. A context
+//! line must be found below the cursor and moves it past the match. Lines to insert
+//! are only inserted when they are not already in place, and lines to remove are
+//! removed up to the next context line, so applying an edit again changes nothing.
+//! Substitutions and `$+` or `$-` lines apply to the same lines as removals, and to
+//! the whole file when there is no context.
+
+use regex::Regex;
+
+use crate::ast::{IdemEdit, IdemEditCommandType, IdemLineInFile, IdemLineInsert, IdemLineSelector};
+use crate::errors::{Error, Result as ExecResult};
+
+/// How lines of the file are compared with lines of the edit.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Matching {
+    /// Leading and trailing whitespace is ignored.
+    Trimmed,
+    /// All whitespace is ignored, `a=1` matches `a = 1`.
+    IgnoreWhitespace,
+}
+
+impl Matching {
+    fn normalize(self, line: &str) -> String {
+        match self {
+            Matching::Trimmed => line.trim().to_string(),
+            Matching::IgnoreWhitespace => line.split_whitespace().collect(),
+        }
+    }
+
+    fn matches(self, a: &str, b: &str) -> bool {
+        self.normalize(a) == self.normalize(b)
+    }
+}
+
+struct Lines {
+    lines: Vec<String>,
+    matching: Matching,
+}
+
+impl Lines {
+    fn find(&self, line: &str, from: usize, to: usize) -> Option<usize> {
+        (from..to).find(|&i| self.matching.matches(&self.lines[i], line))
+    }
+
+    fn matches_at(&self, at: usize, line: &str) -> bool {
+        self.lines.get(at).is_some_and(|existing| self.matching.matches(existing, line))
+    }
+
+    /// The line of the file closest to `line`, for reporting a missing context line.
+    fn nearest(&self, line: &str) -> Option<(usize, &str)> {
+        let wanted = self.matching.normalize(line);
+        self.lines.iter()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+            .min_by_key(|(_, l)| distance(&self.matching.normalize(l), &wanted))
+            .map(|(i, l)| (i, l.as_str()))
+    }
+
+    /// The line the next context line in `commands` is found at, or the end of the file.
+    fn scope_end(&self, commands: &[IdemEditCommandType], cursor: usize) -> usize {
+        let next = commands.iter().find_map(|command| match command {
+            IdemEditCommandType::Context(next) => Some(next),
+            _ => None,
+        });
+        next.and_then(|next| self.find(next, cursor, self.lines.len())).unwrap_or(self.lines.len())
+    }
+
+    fn missing_context(&self, line: &str) -> Error {
+        match self.nearest(line) {
+            Some((i, nearest)) => Error::message(format!("Context line {:?} not found, the nearest is line {}: {:?}", line.trim(), i + 1, nearest.trim())),
+            None => Error::message(format!("Context line {:?} not found, the file is empty", line.trim())),
+        }
+    }
+}
+
+enum Selector<'a> {
+    Line(&'a str),
+    Regexp(Regex),
+}
+
+fn compile(regexp: &str) -> ExecResult<Regex> {
+    Regex::new(regexp).map_err(|e| Error::message(format!("Invalid regular expression {:?}: {}", regexp, e)))
+}
+
+/// Turns the `\1` groups of a sed replacement into the `${1}` of `Regex::replace`.
+fn sed_groups(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(group)) if group.is_ascii_digit() => {
+                converted.push_str(&format!("${{{}}}", group));
+                chars.next();
+            }
+            _ => converted.push(c),
+        }
+    }
+    converted
+}
+
+/// The number of characters to insert, remove or change to turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb { diagonal } else { 1 + diagonal.min(above).min(row[j]) };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Ensures a line is present or absent within the lines from `start` to `end`.
+fn apply_line_in_file(file: &mut Lines, line_in_file: &IdemLineInFile, start: usize, end: usize) -> ExecResult<()> {
+    let regexp = line_in_file.regexp.as_ref().map(|regexp| compile(regexp)).transpose()?;
+    let matched = |file: &Lines, i: usize| match regexp {
+        Some(ref regexp) => regexp.is_match(&file.lines[i]),
+        None => file.matching.matches(&file.lines[i], &line_in_file.line),
+    };
+
+    if !line_in_file.present {
+        let mut end = end;
+        let mut i = start;
+        while i < end {
+            if matched(file, i) {
+                file.lines.remove(i);
+                end -= 1;
+            } else {
+                i += 1;
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(found) = (start..end).rev().find(|&i| matched(file, i)) {
+        file.lines[found] = line_in_file.line.to_string();
+        return Ok(());
+    }
+
+    let at = match line_in_file.insert {
+        IdemLineInsert::Start => Some(start),
+        IdemLineInsert::End => None,
+        IdemLineInsert::After(ref after) => {
+            let after = compile(after)?;
+            (start..end).rev().find(|&i| after.is_match(&file.lines[i])).map(|i| i + 1)
+        }
+        IdemLineInsert::Before(ref before) => {
+            let before = compile(before)?;
+            (start..end).find(|&i| before.is_match(&file.lines[i]))
+        }
+    };
+    file.lines.insert(at.unwrap_or(end), line_in_file.line.to_string());
+    Ok(())
+}
+
+/// Applies the commands of `edit` to `text`, returning the new text.
+pub fn apply_edit(text: &str, edit: &IdemEdit, matching: Matching) -> ExecResult<String> {
+    let mut file = Lines { lines: text.lines().map(|line| line.to_string()).collect(), matching };
+    let mut cursor = 0;
+
+    for (c, command) in edit.commands.iter().enumerate() {
+        match command {
+            IdemEditCommandType::Context(line) => {
+                let found = file.find(line, cursor, file.lines.len()).ok_or_else(|| file.missing_context(line))?;
+                cursor = found + 1;
+            }
+
+            IdemEditCommandType::InsertStart(line) => {
+                if !file.matches_at(0, line) {
+                    file.lines.insert(0, line.to_string());
+                }
+                cursor = cursor.max(1);
+            }
+
+            IdemEditCommandType::InsertEnd(line) => {
+                cursor = match file.find(line, cursor, file.lines.len()) {
+                    Some(found) => found + 1,
+                    None => {
+                        file.lines.push(line.to_string());
+                        file.lines.len()
+                    }
+                };
+            }
+
+            IdemEditCommandType::InsertAfter(anchor, line) => {
+                let after = if cursor > 0 && file.matches_at(cursor - 1, anchor) {
+                    cursor
+                } else {
+                    file.find(anchor, cursor, file.lines.len()).ok_or_else(|| file.missing_context(anchor))? + 1
+                };
+                if !file.matches_at(after, line) {
+                    file.lines.insert(after, line.to_string());
+                }
+                cursor = after + 1;
+            }
+
+            // The line may already be anywhere between the cursor and the anchor
+            IdemEditCommandType::InsertBefore(anchor, line) => {
+                let before = file.find(anchor, cursor, file.lines.len()).ok_or_else(|| file.missing_context(anchor))?;
+                cursor = match file.find(line, cursor, before) {
+                    Some(found) => found + 1,
+                    None => {
+                        file.lines.insert(before, line.to_string());
+                        before + 1
+                    }
+                };
+            }
+
+            // Matching lines are removed up to the next context line
+            IdemEditCommandType::Remove(line) => {
+                let mut end = file.scope_end(&edit.commands[c + 1..], cursor);
+                while let Some(found) = file.find(line, cursor, end) {
+                    file.lines.remove(found);
+                    end -= 1;
+                }
+            }
+
+            IdemEditCommandType::Replace(selector, replace) => {
+                let end = file.scope_end(&edit.commands[c + 1..], cursor);
+                let selector = match selector {
+                    Some(IdemLineSelector::Regexp(regexp)) => Some(Selector::Regexp(compile(regexp)?)),
+                    Some(IdemLineSelector::Line(line)) => Some(Selector::Line(line)),
+                    None => None,
+                };
+                let regexp = compile(&replace.regexp)?;
+                let replacement = sed_groups(&replace.replacement);
+
+                for i in cursor..end {
+                    let selected = match selector {
+                        Some(Selector::Regexp(ref regexp)) => regexp.is_match(&file.lines[i]),
+                        Some(Selector::Line(line)) => file.matching.matches(&file.lines[i], line),
+                        None => true,
+                    };
+                    if selected {
+                        let limit = if replace.global { 0 } else { 1 };
+                        file.lines[i] = regexp.replacen(&file.lines[i], limit, replacement.as_str()).into_owned();
+                    }
+                }
+            }
+
+            IdemEditCommandType::LineInFile(line_in_file) => {
+                let end = file.scope_end(&edit.commands[c + 1..], cursor);
+                apply_line_in_file(&mut file, line_in_file, cursor, end)?;
+            }
+        }
+    }
+
+    let mut edited = file.lines.join("\n");
+    if (text.is_empty() || text.ends_with('\n')) && !edited.is_empty() {
+        edited.push('\n');
+    }
+    Ok(edited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::IdemReplace;
+    use IdemEditCommandType::*;
+
+    const CONFIG: &str = "[main]\nconfig1 = \"value\"\nconfig2 = \"value\"\n\n[blockb]\nb1 = \"value\"\n";
+
+    fn edit(commands: Vec<IdemEditCommandType>) -> IdemEdit {
+        IdemEdit { commands }
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let edit = edit(vec![
+            Context("[main]".to_string()),
+            Remove("config1 = \"value\"".to_string()),
+            InsertAfter("[main]".to_string(), "config0 = \"value\"".to_string()),
+            Context("[blockb]".to_string()),
+            InsertAfter("[blockb]".to_string(), "b0 = \"value\"".to_string()),
+        ]);
+        let edited = apply_edit(CONFIG, &edit, Matching::Trimmed).unwrap();
+
+        assert_eq!(edited, "[main]\nconfig0 = \"value\"\nconfig2 = \"value\"\n\n[blockb]\nb0 = \"value\"\nb1 = \"value\"\n");
+        assert_eq!(apply_edit(&edited, &edit, Matching::Trimmed).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_insert_before_and_end() {
+        let edit = edit(vec![
+            InsertBefore("[blockb]".to_string(), "[blocka]".to_string()),
+            InsertBefore("[blockb]".to_string(), "".to_string()),
+            Context("[blockb]".to_string()),
+            InsertEnd("b2 = \"value\"".to_string()),
+        ]);
+        let edited = apply_edit(CONFIG, &edit, Matching::Trimmed).unwrap();
+
+        assert_eq!(edited, "[main]\nconfig1 = \"value\"\nconfig2 = \"value\"\n\n[blocka]\n\n[blockb]\nb1 = \"value\"\nb2 = \"value\"\n");
+        assert_eq!(apply_edit(&edited, &edit, Matching::Trimmed).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_ignore_whitespace() {
+        let edit = edit(vec![
+            Context("config2=\"value\"".to_string()),
+            InsertAfter("config2=\"value\"".to_string(), "config3 = \"value\"".to_string()),
+        ]);
+        assert!(apply_edit(CONFIG, &edit, Matching::Trimmed).is_err());
+
+        let edited = apply_edit(CONFIG, &edit, Matching::IgnoreWhitespace).unwrap();
+        assert_eq!(edited, "[main]\nconfig1 = \"value\"\nconfig2 = \"value\"\nconfig3 = \"value\"\n\n[blockb]\nb1 = \"value\"\n");
+    }
+
+    #[test]
+    fn test_replace() {
+        let replace = |regexp: &str, replacement: &str, global| IdemReplace {
+            regexp: regexp.to_string(),
+            replacement: replacement.to_string(),
+            global,
+        };
+        let edit = edit(vec![
+            Replace(Some(IdemLineSelector::Line("config1 = \"value\"".to_string())), replace("value", "other", false)),
+            Context("[blockb]".to_string()),
+            Replace(Some(IdemLineSelector::Regexp("^b".to_string())), replace(r#"(\w+) = "(\w+)""#, r#"\1 = "$2-\2""#, true)),
+        ]);
+        let edited = apply_edit(CONFIG, &edit, Matching::Trimmed).unwrap();
+
+        assert_eq!(edited, "[main]\nconfig1 = \"other\"\nconfig2 = \"value\"\n\n[blockb]\nb1 = \"value-value\"\n");
+
+        let err = apply_edit(CONFIG, &IdemEdit { commands: vec![Replace(None, replace("(", "", false))] }, Matching::Trimmed).unwrap_err();
+        assert!(err.to_string().starts_with(r#"Invalid regular expression "(""#));
+    }
+
+    #[test]
+    fn test_line_in_file() {
+        let line = |line: &str, present, regexp: Option<&str>, insert| LineInFile(IdemLineInFile {
+            line: line.to_string(),
+            present,
+            regexp: regexp.map(|regexp| regexp.to_string()),
+            insert,
+        });
+        let edit = edit(vec![
+            line("config2 = \"other\"", true, Some("^config2 ="), IdemLineInsert::End),
+            line("config3 = \"value\"", true, None, IdemLineInsert::After("^config".to_string())),
+            line("# managed", true, None, IdemLineInsert::Start),
+            line("b2 = \"value\"", true, None, IdemLineInsert::Before("^b1".to_string())),
+            line("", false, Some("^b1"), IdemLineInsert::End),
+        ]);
+        let edited = apply_edit(CONFIG, &edit, Matching::Trimmed).unwrap();
+
+        assert_eq!(edited, "# managed\n[main]\nconfig1 = \"value\"\nconfig2 = \"other\"\nconfig3 = \"value\"\n\n[blockb]\nb2 = \"value\"\n");
+        assert_eq!(apply_edit(&edited, &edit, Matching::Trimmed).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_missing_context() {
+        let err = apply_edit(CONFIG, &edit(vec![Context("[block]".to_string())]), Matching::Trimmed).unwrap_err();
+        assert_eq!(err.to_string(), r#"Context line "[block]" not found, the nearest is line 5: "[blockb]""#);
+
+        let err = apply_edit("", &edit(vec![Context("[main]".to_string())]), Matching::Trimmed).unwrap_err();
+        assert_eq!(err.to_string(), r#"Context line "[main]" not found, the file is empty"#);
+    }
+}
@@ -0,0 +1,88 @@
+//! Managed blocks between marker lines, for files without a structure to edit.
+//!
+//! The marker is a line with `{mark}` in it, such as `# {mark} IDEMSH team-x`, which
+//! is written with `BEGIN` before the block and `END` after it. Everything between
+//! them belongs to the block and is replaced as a whole, so each marker names its own
+//! block and a file can have several. A missing block is added at the end of the file.
+
+use crate::errors::{Error, Result as ExecResult};
+
+pub const DEFAULT_MARKER: &str = "# {mark} IDEMSH MANAGED BLOCK";
+
+/// The lines of the block named by `marker`, from its `BEGIN` to its `END` line.
+fn find_block(lines: &[&str], begin: &str, end: &str) -> ExecResult<Option<(usize, usize)>> {
+    let starts: Vec<usize> = lines.iter().enumerate().filter(|(_, l)| l.trim() == begin).map(|(i, _)| i).collect();
+    let start = match starts.as_slice() {
+        [] => return Ok(None),
+        [start] => *start,
+        _ => return Err(Error::message(format!("{:?} appears {} times, unable to tell which block to manage", begin, starts.len()))),
+    };
+
+    match lines[start + 1..].iter().position(|l| l.trim() == end) {
+        Some(i) => Ok(Some((start, start + 1 + i))),
+        None => Err(Error::message(format!("{:?} on line {} has no {:?} after it", begin, start + 1, end))),
+    }
+}
+
+/// Sets the contents of the block named by `marker`, or removes it with `None`.
+pub fn apply_block(text: &str, marker: &str, contents: Option<&str>) -> ExecResult<String> {
+    if !marker.contains("{mark}") {
+        return Err(Error::message(format!("The marker {:?} must contain {{mark}}", marker)));
+    }
+    let begin = marker.replace("{mark}", "BEGIN");
+    let end = marker.replace("{mark}", "END");
+
+    let mut lines: Vec<&str> = text.lines().collect();
+    let found = find_block(&lines, &begin, &end)?;
+    let block: Vec<&str> = match contents {
+        Some(contents) => {
+            let contents = contents.strip_suffix('\n').unwrap_or(contents);
+            let body = if contents.is_empty() { vec![] } else { contents.split('\n').collect() };
+            [vec![begin.as_str()], body, vec![end.as_str()]].concat()
+        }
+        None => vec![],
+    };
+
+    match found {
+        Some((start, last)) => {
+            lines.splice(start..=last, block);
+        }
+        None => lines.extend(block),
+    }
+
+    let mut edited = lines.join("\n");
+    if (text.is_empty() || text.ends_with('\n')) && !edited.is_empty() {
+        edited.push('\n');
+    }
+    Ok(edited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASHRC: &str = "# ~/.bashrc\nexport EDITOR=vi\n# BEGIN team-x\nalias old=true\n# END team-x\nalias ll='ls -l'\n";
+
+    #[test]
+    fn test_apply_block() {
+        let edited = apply_block(BASHRC, "# {mark} team-x", Some("alias k=kubectl\nalias g=git\n")).unwrap();
+        assert_eq!(edited, "# ~/.bashrc\nexport EDITOR=vi\n# BEGIN team-x\nalias k=kubectl\nalias g=git\n# END team-x\nalias ll='ls -l'\n");
+        assert_eq!(apply_block(&edited, "# {mark} team-x", Some("alias k=kubectl\nalias g=git\n")).unwrap(), edited);
+
+        // Another marker is another block, added at the end
+        let edited = apply_block(&edited, "# {mark} team-y", Some("export Y=1")).unwrap();
+        assert!(edited.ends_with("alias ll='ls -l'\n# BEGIN team-y\nexport Y=1\n# END team-y\n"));
+
+        let removed = apply_block(&edited, "# {mark} team-x", None).unwrap();
+        assert_eq!(removed, "# ~/.bashrc\nexport EDITOR=vi\nalias ll='ls -l'\n# BEGIN team-y\nexport Y=1\n# END team-y\n");
+        assert_eq!(apply_block(&removed, "# {mark} team-x", None).unwrap(), removed);
+    }
+
+    #[test]
+    fn test_apply_block_errors() {
+        let err = apply_block("# BEGIN x\nexport A=1\n", "# {mark} x", Some("")).unwrap_err();
+        assert_eq!(err.to_string(), "\"# BEGIN x\" on line 1 has no \"# END x\" after it");
+        assert!(apply_block("", "# managed", None).is_err());
+        assert_eq!(apply_block("", DEFAULT_MARKER, Some("")).unwrap(), "# BEGIN IDEMSH MANAGED BLOCK\n# END IDEMSH MANAGED BLOCK\n");
+    }
+}
@@ -0,0 +1,47 @@
+//! Editors that change the text of an existing file in place.
+//!
+//! Each editor takes the current contents and returns the new contents, leaving
+//! everything it does not need to touch as it was. Applying an edit a second time
+//! returns the contents unchanged.
+
+pub mod blocks;
+pub mod ini;
+pub mod json;
+pub mod lines;
+pub mod marker;
+pub mod yaml;
+
+use std::path::Path;
+
+/// The format of a file edited by key or by path.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    Ini,
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// The format given with `format = ...`.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "ini" => Some(Format::Ini),
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    /// The format of a file from its extension, systemd units are INI.
+    pub fn from_path(path: &str) -> Option<Format> {
+        match Path::new(path).extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "ini" | "cfg" | "service" | "socket" | "timer" | "mount" | "path" | "target" | "desktop" => Some(Format::Ini),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
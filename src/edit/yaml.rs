@@ -0,0 +1,947 @@
+//! YAML edits at paths, keeping comments, key order and quoting.
+//!
+//! The document is parsed line by line into the mappings and sequences of its block
+//! style, with where each value starts and ends. Edits only rewrite the lines of the
+//! values they change: a scalar is replaced within its line, keeping the comment after
+//! it and the quotes of the old value, and new keys are added after the last key of
+//! their mapping. Flow collections such as `[a, b]` are rewritten as a whole. A value
+//! that is already equal is left alone, so applying an edit again changes nothing.
+
+use std::iter;
+
+use crate::ast::IdemJsonEdit;
+use crate::edit::json::{format_pointer, parse_pointer};
+use crate::errors::{Error, Result as ExecResult};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scalar {
+    pub text: String,
+    /// `'` or `"` for quoted scalars, `|` or `>` for block scalars, `None` for plain ones.
+    pub style: Option<char>,
+}
+
+impl Scalar {
+    fn plain(text: &str) -> Self {
+        Scalar { text: text.to_string(), style: None }
+    }
+
+    /// Plain scalars that are not numbers, booleans or null are strings.
+    fn is_string(&self) -> bool {
+        self.style.is_some() || !looks_typed(&self.text)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Yaml {
+    Scalar(Scalar),
+    Seq(Vec<Yaml>),
+    Map(Vec<(String, Yaml)>),
+}
+
+fn looks_typed(text: &str) -> bool {
+    let number = text.trim_start_matches(['-', '+']);
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" | "true" | "True" | "TRUE" | "false" | "False" | "FALSE" => true,
+        _ => number.starts_with(|c: char| c.is_ascii_digit() || c == '.') && number.parse::<f64>().is_ok(),
+    }
+}
+
+/// Equal data, `8080` and `"8080"` are different but `web` and `"web"` are not.
+fn same(a: &Yaml, b: &Yaml) -> bool {
+    match (a, b) {
+        (Yaml::Scalar(a), Yaml::Scalar(b)) => a.text == b.text && a.is_string() == b.is_string(),
+        (Yaml::Seq(a), Yaml::Seq(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b)),
+        (Yaml::Map(a), Yaml::Map(b)) => a.len() == b.len() && a.iter().all(|(key, a)| b.iter().any(|(k, b)| k == key && same(a, b))),
+        _ => false,
+    }
+}
+
+/// A value of the document, from `col` of `line` up to the line `end`.
+#[derive(Debug)]
+struct Node {
+    line: usize,
+    col: usize,
+    end: usize,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    /// A scalar or a flow collection, ending at `to` on the last line.
+    Inline { to: usize, value: Yaml, flow: bool },
+    /// Nothing after a key or a dash, a null.
+    Empty,
+    Map(Vec<Entry>),
+    Seq(Vec<Item>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    line: usize,
+    col: usize,
+    /// Where the line continues after the `:`.
+    colon: usize,
+    value: Node,
+}
+
+#[derive(Debug)]
+struct Item {
+    line: usize,
+    col: usize,
+    value: Node,
+}
+
+impl Node {
+    fn data(&self) -> Yaml {
+        match self.kind {
+            NodeKind::Inline { ref value, .. } => value.clone(),
+            NodeKind::Empty => Yaml::Scalar(Scalar::plain("")),
+            NodeKind::Map(ref entries) => Yaml::Map(entries.iter().map(|e| (e.key.to_string(), e.value.data())).collect()),
+            NodeKind::Seq(ref items) => Yaml::Seq(items.iter().map(|i| i.value.data()).collect()),
+        }
+    }
+}
+
+fn is_dash(text: &str) -> bool {
+    text == "-" || text.starts_with("- ") || text.starts_with("-\t")
+}
+
+/// Where a comment starts, a `#` after whitespace outside of quotes.
+fn comment_start(text: &str) -> usize {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if (c == '"' || c == '\'') && (previous.is_whitespace() || "[{,:".contains(previous)) => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return i,
+            None => {},
+        }
+        previous = c;
+    }
+    text.len()
+}
+
+/// A quoted scalar at the start of `text` and the length of its text with the quotes.
+fn parse_quoted(text: &str) -> Option<(Scalar, usize)> {
+    let quote = text.chars().next()?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            ('\'', '\'') if text[i + 1..].starts_with('\'') => {
+                value.push('\'');
+                chars.next();
+            }
+            ('"', '\\') => match chars.next().map(|(_, c)| c)? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                '0' => value.push('\0'),
+                c => value.push(c),
+            },
+            (q, c) if c == q => return Some((Scalar { text: value, style: Some(quote) }, i + 1)),
+            (_, c) => value.push(c),
+        }
+    }
+    None
+}
+
+/// `key:` at the start of `text`, and where the text continues after the `:`.
+fn parse_key(text: &str) -> Option<(String, usize)> {
+    let (key, after) = match text.chars().next()? {
+        '"' | '\'' => {
+            let (key, len) = parse_quoted(text)?;
+            let spaces = text[len..].len() - text[len..].trim_start().len();
+            (key.text, len + spaces)
+        }
+        '[' | '{' | '#' | '-' | '|' | '>' => return None,
+        _ => {
+            let content = &text[..comment_start(text)];
+            let colon = content.char_indices()
+                .find(|&(i, c)| c == ':' && content[i + 1..].chars().next().is_none_or(char::is_whitespace))?
+                .0;
+            (content[..colon].trim_end().to_string(), colon)
+        }
+    };
+    if !text[after..].starts_with(':') {
+        return None;
+    }
+    let rest = &text[after + 1..];
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some((key, after + 1))
+}
+
+/// Flow collections and scalars within them, such as `{ "a": [1, 2] }`.
+struct Flow<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Flow<'a> {
+    fn skip_whitespace(&mut self) {
+        self.pos = self.text.len() - self.text[self.pos..].trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.text[self.pos..].starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn scalar(&mut self, stop: &str) -> Option<Scalar> {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        if rest.starts_with('"') || rest.starts_with('\'') {
+            let (scalar, len) = parse_quoted(rest)?;
+            self.pos += len;
+            return Some(scalar);
+        }
+        let len = rest.find(|c| stop.contains(c)).unwrap_or(rest.len());
+        self.pos += len;
+        Some(Scalar::plain(rest[..len].trim()))
+    }
+
+    fn value(&mut self) -> Option<Yaml> {
+        if self.eat('[') {
+            let mut items = vec![];
+            while !self.eat(']') {
+                items.push(self.value()?);
+                if !self.eat(',') && !self.text[self.pos..].trim_start().starts_with(']') {
+                    return None;
+                }
+            }
+            Some(Yaml::Seq(items))
+        } else if self.eat('{') {
+            let mut entries = vec![];
+            while !self.eat('}') {
+                let key = self.scalar(":,}")?.text;
+                if !self.eat(':') {
+                    return None;
+                }
+                entries.push((key, self.value()?));
+                if !self.eat(',') && !self.text[self.pos..].trim_start().starts_with('}') {
+                    return None;
+                }
+            }
+            Some(Yaml::Map(entries))
+        } else {
+            self.scalar(",]}").map(Yaml::Scalar)
+        }
+    }
+}
+
+fn parse_flow(text: &str) -> Option<Yaml> {
+    let mut flow = Flow { text, pos: 0 };
+    let value = flow.value()?;
+    flow.skip_whitespace();
+    if flow.pos == text.len() { Some(value) } else { None }
+}
+
+struct Parser<'a> {
+    lines: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, line: usize, message: &str) -> Error {
+        Error::message(format!("Invalid YAML on line {}: {}", line + 1, message))
+    }
+
+    /// The line and column of the next line with content.
+    fn next_content(&self, from: usize) -> Option<(usize, usize)> {
+        (from..self.lines.len()).find_map(|l| {
+            let trimmed = self.lines[l].trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                None
+            } else {
+                Some((l, self.lines[l].len() - trimmed.len()))
+            }
+        })
+    }
+
+    /// The block on the lines after `line` indented more than `indent`, or a sequence
+    /// at `indent` after a key.
+    fn nested(&self, line: usize, indent: usize, after_key: bool, col: usize) -> ExecResult<Node> {
+        match self.next_content(line + 1) {
+            Some((l, c)) if c > indent || (after_key && c == indent && is_dash(&self.lines[l][c..])) => self.block(l, c, indent),
+            _ => Ok(Node { line, col, end: line + 1, kind: NodeKind::Empty }),
+        }
+    }
+
+    fn block(&self, line: usize, col: usize, indent: usize) -> ExecResult<Node> {
+        let text = &self.lines[line][col..];
+        if is_dash(text) {
+            self.seq(line, col)
+        } else if parse_key(text).is_some() {
+            self.map(line, col)
+        } else {
+            self.inline(line, col, indent)
+        }
+    }
+
+    fn map(&self, line: usize, col: usize) -> ExecResult<Node> {
+        let mut entries = vec![];
+        let mut l = line;
+        loop {
+            let text = &self.lines[l];
+            let (key, colon) = parse_key(&text[col..]).ok_or_else(|| self.error(l, "expected a key"))?;
+            let colon = col + colon;
+            let after = &text[colon..];
+            let value = if after.trim().is_empty() || after.trim_start().starts_with('#') {
+                self.nested(l, col, true, colon)?
+            } else {
+                self.inline(l, colon + after.len() - after.trim_start().len(), col)?
+            };
+
+            let end = value.end;
+            entries.push(Entry { key, line: l, col, colon, value });
+            match self.next_content(end) {
+                Some((n, c)) if c == col && parse_key(&self.lines[n][c..]).is_some() => l = n,
+                Some((n, c)) if c > col => return Err(self.error(n, "unexpected indentation")),
+                _ => break,
+            }
+        }
+
+        let end = entries.last().map_or(line + 1, |e| e.value.end);
+        Ok(Node { line, col, end, kind: NodeKind::Map(entries) })
+    }
+
+    fn seq(&self, line: usize, col: usize) -> ExecResult<Node> {
+        let mut items = vec![];
+        let mut l = line;
+        loop {
+            let text = &self.lines[l];
+            let after = &text[col + 1..];
+            let value = if after.trim().is_empty() || after.trim_start().starts_with('#') {
+                self.nested(l, col, false, col + 1)?
+            } else {
+                let value_col = col + 1 + after.len() - after.trim_start().len();
+                self.block(l, value_col, col)?
+            };
+
+            let end = value.end;
+            items.push(Item { line: l, col, value });
+            match self.next_content(end) {
+                Some((n, c)) if c == col && is_dash(&self.lines[n][c..]) => l = n,
+                _ => break,
+            }
+        }
+
+        let end = items.last().map_or(line + 1, |i| i.value.end);
+        Ok(Node { line, col, end, kind: NodeKind::Seq(items) })
+    }
+
+    /// A value starting on a line of its own or after a key or dash, whose owner is
+    /// at `indent`.
+    fn inline(&self, line: usize, col: usize, indent: usize) -> ExecResult<Node> {
+        let text = &self.lines[line];
+        let rest = &text[col..];
+        let (end, to, value, flow) = match rest.chars().next() {
+            Some(style @ '|') | Some(style @ '>') => {
+                // The lines indented more than the owner, up to the last one with content
+                let mut end = line + 1;
+                for (l, next) in self.lines.iter().enumerate().skip(line + 1) {
+                    if next.trim().is_empty() {
+                        continue;
+                    }
+                    if next.len() - next.trim_start().len() <= indent {
+                        break;
+                    }
+                    end = l + 1;
+                }
+                let body: Vec<&str> = self.lines[line + 1..end].iter().map(|l| l.trim()).collect();
+                let mut value = body.join(if style == '|' { "\n" } else { " " });
+                if !rest[..comment_start(rest)].contains('-') {
+                    value.push('\n');
+                }
+                (end, self.lines[end - 1].len(), Yaml::Scalar(Scalar { text: value, style: Some(style) }), false)
+            }
+
+            Some('[') | Some('{') => {
+                let (last, to) = self.flow_end(line, col).ok_or_else(|| self.error(line, "unterminated flow collection"))?;
+                let flow: Vec<&str> = (line..=last).map(|l| {
+                    let from = if l == line { col } else { 0 };
+                    let until = if l == last { to } else { self.lines[l].len() };
+                    &self.lines[l][from..until]
+                }).collect();
+                let value = parse_flow(&flow.join("\n")).ok_or_else(|| self.error(line, "invalid flow collection"))?;
+                (last + 1, to, value, true)
+            }
+
+            Some('"') | Some('\'') => {
+                let (scalar, len) = parse_quoted(rest).ok_or_else(|| self.error(line, "unterminated quoted scalar"))?;
+                (line + 1, col + len, Yaml::Scalar(scalar), false)
+            }
+
+            _ => {
+                let value = rest[..comment_start(rest)].trim_end();
+                (line + 1, col + value.len(), Yaml::Scalar(Scalar::plain(value)), false)
+            }
+        };
+
+        Ok(Node { line, col, end, kind: NodeKind::Inline { to, value, flow } })
+    }
+
+    /// The line and column after the bracket closing the flow collection at `col`.
+    fn flow_end(&self, line: usize, col: usize) -> Option<(usize, usize)> {
+        let mut depth = 0;
+        let mut quote = None;
+        for (l, text) in self.lines.iter().enumerate().skip(line) {
+            let from = if l == line { col } else { 0 };
+            for (i, c) in text[from..].char_indices() {
+                match (quote, c) {
+                    (Some(q), c) if c == q => quote = None,
+                    (Some(_), _) => {},
+                    (None, '"') | (None, '\'') => quote = Some(c),
+                    (None, '[') | (None, '{') => depth += 1,
+                    (None, ']') | (None, '}') => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some((l, from + i + 1));
+                        }
+                    }
+                    (None, '#') if text[..from + i].ends_with(char::is_whitespace) => break,
+                    _ => {},
+                }
+            }
+        }
+        None
+    }
+
+    fn root(&self) -> ExecResult<Option<Node>> {
+        match self.next_content(0) {
+            Some((l, c)) => self.block(l, c, 0).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn parse_yaml(text: &str) -> ExecResult<Yaml> {
+    let lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+    match (Parser { lines: &lines }).root()? {
+        Some(node) => Ok(node.data()),
+        None => Err(Error::message("Expected a YAML value")),
+    }
+}
+
+fn needs_quotes(text: &str) -> bool {
+    text.is_empty()
+        || text != text.trim()
+        || text.starts_with(|c| "-?:,[]{}#&*!|>'\"%@`".contains(c))
+        || text.contains(": ")
+        || text.contains(" #")
+        || text.ends_with(':')
+        || text.contains('\n')
+}
+
+/// Writes a scalar with the quotes of the value it replaces, when it is a string.
+fn write_scalar(scalar: &Scalar, existing: Option<char>) -> String {
+    if !scalar.is_string() {
+        return scalar.text.to_string();
+    }
+    let quote = match (existing, scalar.style) {
+        (Some('\''), _) if !scalar.text.contains('\n') => '\'',
+        (Some('"'), _) => '"',
+        (None, _) if !needs_quotes(&scalar.text) && !looks_typed(&scalar.text) => return scalar.text.to_string(),
+        (_, Some('\'')) if !scalar.text.contains('\n') => '\'',
+        _ => '"',
+    };
+
+    if quote == '\'' {
+        return format!("'{}'", scalar.text.replace('\'', "''"));
+    }
+    let mut out = String::from("\"");
+    for c in scalar.text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn write_key(key: &str) -> String {
+    write_scalar(&Scalar::plain(key), None)
+}
+
+fn is_inline(value: &Yaml) -> bool {
+    match value {
+        Yaml::Scalar(_) => true,
+        Yaml::Seq(items) => items.is_empty(),
+        Yaml::Map(entries) => entries.is_empty(),
+    }
+}
+
+fn write_inline(value: &Yaml, existing: Option<char>) -> String {
+    match value {
+        Yaml::Scalar(scalar) => write_scalar(scalar, existing),
+        Yaml::Seq(items) => format!("[{}]", items.iter().map(|item| write_inline(item, None)).collect::<Vec<_>>().join(", ")),
+        Yaml::Map(entries) => {
+            let entries: Vec<String> = entries.iter().map(|(key, value)| format!("{}: {}", write_key(key), write_inline(value, None))).collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+    }
+}
+
+fn pad(indent: usize) -> String {
+    " ".repeat(indent)
+}
+
+fn write_block(value: &Yaml, indent: usize, unit: usize) -> Vec<String> {
+    match value {
+        _ if is_inline(value) => vec![format!("{}{}", pad(indent), write_inline(value, None))],
+        Yaml::Map(entries) => entries.iter().flat_map(|(key, value)| write_entry(key, value, indent, unit)).collect(),
+        Yaml::Seq(items) => items.iter().flat_map(|item| write_item(item, indent, unit)).collect(),
+        Yaml::Scalar(_) => unreachable!(),
+    }
+}
+
+fn write_entry(key: &str, value: &Yaml, indent: usize, unit: usize) -> Vec<String> {
+    if is_inline(value) {
+        return vec![format!("{}{}: {}", pad(indent), write_key(key), write_inline(value, None))];
+    }
+    iter::once(format!("{}{}:", pad(indent), write_key(key))).chain(write_block(value, indent + unit, unit)).collect()
+}
+
+/// `- value`, the first line of a collection goes after the dash.
+fn write_item(value: &Yaml, indent: usize, unit: usize) -> Vec<String> {
+    let mut lines = write_block(value, indent + 2, unit);
+    lines[0] = format!("{}- {}", pad(indent), lines[0].trim_start());
+    lines
+}
+
+/// The value at a path, nested in maps for the keys that are missing.
+fn nest(path: &[String], value: &Yaml) -> Yaml {
+    path.iter().rev().fold(value.clone(), |value, key| Yaml::Map(vec![(key.to_string(), value)]))
+}
+
+fn merge_data(existing: &Yaml, value: &Yaml) -> Yaml {
+    match (existing, value) {
+        (Yaml::Map(existing), Yaml::Map(entries)) => {
+            let mut merged = existing.clone();
+            for (key, value) in entries {
+                match merged.iter_mut().find(|(k, _)| k == key) {
+                    Some((_, existing)) => *existing = merge_data(existing, value),
+                    None => merged.push((key.to_string(), value.clone())),
+                }
+            }
+            Yaml::Map(merged)
+        }
+        _ => value.clone(),
+    }
+}
+
+/// Sets the value at `path` within data, for values inside flow collections.
+fn set_data(data: &Yaml, path: &[String], value: &Yaml) -> ExecResult<Yaml> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Ok(value.clone()),
+    };
+    match data {
+        Yaml::Map(entries) => {
+            let mut entries = entries.clone();
+            match entries.iter_mut().find(|(k, _)| k == key) {
+                Some((_, existing)) => *existing = set_data(existing, rest, value)?,
+                None => entries.push((key.to_string(), nest(rest, value))),
+            }
+            Ok(Yaml::Map(entries))
+        }
+        Yaml::Seq(items) => {
+            let mut items = items.clone();
+            match key.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                Some(item) => *item = set_data(item, rest, value)?,
+                None => return Err(Error::message(format!("There is no item {} in the sequence", key))),
+            }
+            Ok(Yaml::Seq(items))
+        }
+        Yaml::Scalar(scalar) if scalar.text.is_empty() && scalar.style.is_none() => Ok(nest(path, value)),
+        Yaml::Scalar(_) => Err(Error::message(format!("{} is not a mapping", key))),
+    }
+}
+
+fn remove_data(data: &Yaml, path: &[String]) -> Yaml {
+    match (data, path) {
+        (Yaml::Map(entries), [key]) => Yaml::Map(entries.iter().filter(|(k, _)| k != key).cloned().collect()),
+        (Yaml::Seq(items), [key]) => Yaml::Seq(items.iter().enumerate().filter(|(i, _)| i.to_string() != *key).map(|(_, item)| item.clone()).collect()),
+        (Yaml::Map(entries), [key, rest @ ..]) => Yaml::Map(entries.iter().map(|(k, v)| (k.to_string(), if k == key { remove_data(v, rest) } else { v.clone() })).collect()),
+        (Yaml::Seq(items), [key, rest @ ..]) => Yaml::Seq(items.iter().enumerate().map(|(i, v)| if i.to_string() == *key { remove_data(v, rest) } else { v.clone() }).collect()),
+        _ => data.clone(),
+    }
+}
+
+/// What holds a value, to know how to write it.
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Root,
+    Entry { line: usize, col: usize, colon: usize },
+    Item { line: usize, col: usize },
+}
+
+enum Found<'n> {
+    Value(&'n Node, Slot),
+    /// The map the first missing key is added to, and how many keys were found.
+    Missing(&'n Node, usize),
+    /// A flow collection or a null holding the rest of the path after the keys found.
+    Within(&'n Node, Slot, usize),
+}
+
+fn find<'n>(root: &'n Node, path: &[String]) -> ExecResult<Found<'n>> {
+    let mut node = root;
+    let mut slot = Slot::Root;
+    for (i, key) in path.iter().enumerate() {
+        match node.kind {
+            NodeKind::Map(ref entries) => match entries.iter().find(|e| e.key == *key) {
+                Some(entry) => {
+                    slot = Slot::Entry { line: entry.line, col: entry.col, colon: entry.colon };
+                    node = &entry.value;
+                }
+                None => return Ok(Found::Missing(node, i)),
+            },
+            NodeKind::Seq(ref items) => match key.parse::<usize>().ok().and_then(|n| items.get(n)) {
+                Some(item) => {
+                    slot = Slot::Item { line: item.line, col: item.col };
+                    node = &item.value;
+                }
+                None => {
+                    return Err(Error::message(format!("{} is past the end of the sequence at {}", format_pointer(&path[..=i]), format_pointer(&path[..i]))));
+                }
+            },
+            NodeKind::Inline { flow: true, .. } | NodeKind::Empty => return Ok(Found::Within(node, slot, i)),
+            NodeKind::Inline { .. } => {
+                return Err(Error::message(format!("{} crosses {}, which is not a mapping", format_pointer(path), format_pointer(&path[..i]))));
+            }
+        }
+    }
+    Ok(Found::Value(node, slot))
+}
+
+struct Document {
+    lines: Vec<String>,
+    unit: usize,
+}
+
+impl Document {
+    fn new(lines: Vec<String>, unit: usize) -> Self {
+        Document { lines, unit }
+    }
+
+    fn root(&self) -> ExecResult<Option<Node>> {
+        Parser { lines: &self.lines }.root()
+    }
+
+    fn replace(&mut self, slot: Slot, node: &Node, value: &Yaml) {
+        let existing = match node.kind {
+            NodeKind::Inline { value: Yaml::Scalar(ref scalar), .. } => scalar.style.filter(|&c| c == '"' || c == '\''),
+            _ => None,
+        };
+        let flow = matches!(node.kind, NodeKind::Inline { flow: true, .. });
+        let inline = if is_inline(value) || flow { Some(write_inline(value, existing)) } else { None };
+
+        match (slot, &node.kind, inline) {
+            (Slot::Root, _, _) => {
+                self.lines.splice(node.line..node.end, write_block(value, node.col, self.unit));
+            }
+            (_, NodeKind::Inline { to, .. }, Some(text)) => {
+                let line = format!("{}{}{}", &self.lines[node.line][..node.col], text, &self.lines[node.end - 1][*to..]);
+                self.lines.splice(node.line..node.end, iter::once(line));
+            }
+            (Slot::Item { line, col }, _, _) => {
+                self.lines.splice(line..node.end, write_item(value, col, self.unit));
+            }
+            (Slot::Entry { line, colon, .. }, NodeKind::Empty, Some(text)) | (Slot::Entry { line, colon, .. }, _, Some(text)) => {
+                if node.line > line {
+                    self.lines.drain(node.line..node.end);
+                }
+                let key_line = &self.lines[line];
+                self.lines[line] = format!("{} {}{}", &key_line[..colon], text, &key_line[colon..]);
+            }
+            (Slot::Entry { .. }, NodeKind::Map(_), None) | (Slot::Entry { .. }, NodeKind::Seq(_), None) => {
+                self.lines.splice(node.line..node.end, write_block(value, node.col, self.unit));
+            }
+            (Slot::Entry { line, col, colon }, _, None) => {
+                let comment = match node.kind {
+                    NodeKind::Inline { to, .. } => {
+                        let comment = self.lines[node.end - 1][to..].to_string();
+                        self.lines.drain(line + 1..node.end);
+                        comment
+                    }
+                    _ => self.lines[line][colon..].to_string(),
+                };
+                self.lines[line] = format!("{}{}", &self.lines[line][..colon], comment);
+                let block = write_block(value, col + self.unit, self.unit);
+                self.lines.splice(line + 1..line + 1, block);
+            }
+        }
+    }
+
+    fn set(&mut self, path: &[String], value: &Yaml) -> ExecResult<()> {
+        let root = match self.root()? {
+            Some(root) => root,
+            None => {
+                self.lines.extend(write_block(&nest(path, value), 0, self.unit));
+                return Ok(());
+            }
+        };
+
+        match find(&root, path)? {
+            Found::Value(node, _) if same(&node.data(), value) => {},
+            Found::Value(node, slot) => self.replace(slot, node, value),
+            Found::Within(node, slot, found) => {
+                let value = set_data(&node.data(), &path[found..], value)?;
+                self.replace(slot, node, &value);
+            }
+            Found::Missing(map, found) => {
+                let entries = match map.kind {
+                    NodeKind::Map(ref entries) => entries,
+                    _ => unreachable!(),
+                };
+                let at = entries.last().map_or(map.end, |e| e.value.end);
+                let entry = write_entry(&path[found], &nest(&path[found + 1..], value), map.col, self.unit);
+                self.lines.splice(at..at, entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, path: &[String], value: &Yaml) -> ExecResult<()> {
+        let root = match self.root()? {
+            Some(root) => root,
+            None => return self.set(path, value),
+        };
+
+        match (find(&root, path)?, value) {
+            (Found::Value(&Node { kind: NodeKind::Map(_), .. }, _), Yaml::Map(entries)) => {
+                for (key, value) in entries {
+                    let path: Vec<String> = path.iter().chain(iter::once(key)).cloned().collect();
+                    self.merge(&path, value)?;
+                }
+                Ok(())
+            }
+            (Found::Value(node, _), _) => self.set(path, &merge_data(&node.data(), value)),
+            _ => self.set(path, value),
+        }
+    }
+
+    fn remove(&mut self, path: &[String]) -> ExecResult<()> {
+        let (key, parent_path) = match path.split_last() {
+            Some(split) => split,
+            None => return Err(Error::message("The whole document can not be removed")),
+        };
+        let root = match self.root()? {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+
+        let (parent, slot) = match find(&root, parent_path)? {
+            Found::Value(parent, slot) => (parent, slot),
+            Found::Within(node, slot, found) => {
+                let data = remove_data(&node.data(), &path[found..]);
+                if !same(&data, &node.data()) {
+                    self.replace(slot, node, &data);
+                }
+                return Ok(());
+            }
+            Found::Missing(..) => return Ok(()),
+        };
+
+        match parent.kind {
+            NodeKind::Map(ref entries) => {
+                let i = match entries.iter().position(|e| e.key == *key) {
+                    Some(i) => i,
+                    None => return Ok(()),
+                };
+                let entry = &entries[i];
+                if entries.len() == 1 {
+                    self.replace(slot, parent, &Yaml::Map(vec![]));
+                } else if i == 0 && parent.col > self.lines[parent.line].len() - self.lines[parent.line].trim_start().len() {
+                    // The first key of a map after a dash, the next key takes its place
+                    let next = entries[1].line;
+                    self.lines[entry.line] = format!("{}{}", &self.lines[entry.line][..parent.col], &self.lines[next][parent.col..]);
+                    self.lines.drain(entry.line + 1..=next);
+                } else {
+                    self.lines.drain(entry.line..entry.value.end);
+                }
+            }
+            NodeKind::Seq(ref items) => {
+                let i = match key.parse::<usize>().ok().filter(|&i| i < items.len()) {
+                    Some(i) => i,
+                    None => return Ok(()),
+                };
+                if items.len() == 1 {
+                    self.replace(slot, parent, &Yaml::Seq(vec![]));
+                } else {
+                    self.lines.drain(items[i].line..items[i].value.end);
+                }
+            }
+            NodeKind::Empty => {},
+            NodeKind::Inline { flow: true, .. } => {
+                let data = remove_data(&parent.data(), &path[parent_path.len()..]);
+                if !same(&data, &parent.data()) {
+                    self.replace(slot, parent, &data);
+                }
+            }
+            NodeKind::Inline { .. } => {
+                return Err(Error::message(format!("{} is not a mapping", format_pointer(parent_path))));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The indentation of the first indented line, two spaces if there is none.
+fn indent_unit(lines: &[String]) -> usize {
+    lines.iter()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|line| line.len() - line.trim_start().len())
+        .find(|&indent| indent > 0)
+        .unwrap_or(2)
+}
+
+/// The lines of each document, between `---` separators. Comments before the first
+/// separator are not a document.
+fn documents(lines: &[String]) -> Vec<(usize, usize)> {
+    let mut documents = vec![];
+    let mut start = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim_end();
+        if line == "---" || line.starts_with("--- ") || line == "..." {
+            documents.push((start, i));
+            start = i + 1;
+        }
+    }
+    documents.push((start, lines.len()));
+
+    let content = |&(start, end): &(usize, usize)| lines[start..end].iter().any(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'));
+    if documents.len() > 1 && !content(&documents[0]) {
+        documents.remove(0);
+    }
+    documents
+}
+
+/// Applies the edits to a document of the YAML file in `text`, the first one is 0.
+pub fn apply_yaml_edits(text: &str, edits: &[IdemJsonEdit], document: usize) -> ExecResult<String> {
+    let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+    let documents = documents(&lines);
+    let (start, end) = match documents.get(document) {
+        Some(&range) => range,
+        None => return Err(Error::message(format!("There is no document {}, the file has {}", document, documents.len()))),
+    };
+
+    let mut edited = Document::new(lines[start..end].to_vec(), indent_unit(&lines));
+    for edit in edits {
+        match edit {
+            IdemJsonEdit::Merge(pointer, value) => edited.merge(&parse_pointer(pointer)?, &parse_yaml(value)?)?,
+            IdemJsonEdit::Set(pointer, value) => edited.set(&parse_pointer(pointer)?, &parse_yaml(value)?)?,
+            IdemJsonEdit::Remove(pointer) => edited.remove(&parse_pointer(pointer)?)?,
+        }
+    }
+    lines.splice(start..end, edited.lines);
+
+    let mut text_out = lines.join("\n");
+    if (text.is_empty() || text.ends_with('\n')) && !text_out.is_empty() {
+        text_out.push('\n');
+    }
+    Ok(text_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSE: &str = r#"# Services of the app
+version: "3.8"
+services:
+  web:
+    image: 'nginx:1.19'  # pinned
+    ports:
+      - "80:80"
+    environment: { DEBUG: "false" }
+  db:
+    image: postgres
+"#;
+
+    fn edit(pointer: &str, value: &str) -> IdemJsonEdit {
+        IdemJsonEdit::Merge(pointer.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_merge() {
+        let edits = vec![
+            edit("/services/web", "image: nginx:1.21\nenvironment:\n  LOG: info\nvolumes:\n  - ./html:/usr/share/nginx/html\n"),
+            edit("/services/cache", r#"{ "image": "redis", "ports": [] }"#),
+        ];
+        let edited = apply_yaml_edits(COMPOSE, &edits, 0).unwrap();
+
+        assert_eq!(edited, r#"# Services of the app
+version: "3.8"
+services:
+  web:
+    image: 'nginx:1.21'  # pinned
+    ports:
+      - "80:80"
+    environment: {DEBUG: "false", LOG: info}
+    volumes:
+      - ./html:/usr/share/nginx/html
+  db:
+    image: postgres
+  cache:
+    image: redis
+    ports: []
+"#);
+        assert_eq!(apply_yaml_edits(&edited, &edits, 0).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let edits = vec![
+            IdemJsonEdit::Set("/services/web/ports".to_string(), "8080:80".to_string()),
+            IdemJsonEdit::Set("/services/db".to_string(), "[1, 2]".to_string()),
+            IdemJsonEdit::Remove("/version".to_string()),
+            IdemJsonEdit::Remove("/services/web/environment/DEBUG".to_string()),
+        ];
+        let edited = apply_yaml_edits(COMPOSE, &edits, 0).unwrap();
+
+        assert_eq!(edited, r#"# Services of the app
+services:
+  web:
+    image: 'nginx:1.19'  # pinned
+    ports: 8080:80
+    environment: {}
+  db:
+    - 1
+    - 2
+"#);
+        assert_eq!(apply_yaml_edits(&edited, &edits, 0).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_sequences_and_documents() {
+        let manifests = "---\nkind: Deployment\nspec:\n  containers:\n  - name: app\n    image: app:1\n  - name: sidecar\n    image: proxy:1\n---\nkind: Service\n";
+        let edits = vec![
+            edit("/spec/containers/1/image", "proxy:2"),
+            IdemJsonEdit::Remove("/spec/containers/0/name".to_string()),
+        ];
+        let edited = apply_yaml_edits(manifests, &edits, 0).unwrap();
+        assert_eq!(edited, "---\nkind: Deployment\nspec:\n  containers:\n  - image: app:1\n  - name: sidecar\n    image: proxy:2\n---\nkind: Service\n");
+
+        let edited = apply_yaml_edits(manifests, &[edit("/metadata/name", "web")], 1).unwrap();
+        assert!(edited.ends_with("---\nkind: Service\nmetadata:\n  name: web\n"));
+
+        let err = apply_yaml_edits(manifests, &[edit("/kind/name", "x")], 0).unwrap_err();
+        assert_eq!(err.to_string(), "/kind/name crosses /kind, which is not a mapping");
+        let err = apply_yaml_edits(manifests, &[edit("/a", "x")], 2).unwrap_err();
+        assert_eq!(err.to_string(), "There is no document 2, the file has 2");
+    }
+}
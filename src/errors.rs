@@ -1,37 +1,203 @@
 
 use std::fmt;
 use std::result;
-use std::io::Error as IOError;
+use std::io::{self, Error as IOError};
+
+use super::ast::Span;
+use super::value::Failure;
 
 pub type Result<T> = result::Result<T, Error>;
 
-#[derive(Debug)]
-pub enum ErrorType {
-    IOError(Box<IOError>),
-    Message(String),
+/// What went wrong, for callers that handle some errors differently than others.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ErrorKind {
+    /// A script or template that can not be parsed.
+    Parse,
+    /// Reading or writing failed, on the path when it is known.
+    Io(Option<String>),
+    /// Reading or writing was not permitted, on the path when it is known.
+    Permission(Option<String>),
+    /// The connection to the agent of a remote host failed.
+    Remote,
+    /// A command that ran and failed, its failure can be rescued as a value.
+    CommandFailed(Failure),
+    /// An operation the driver, or the way it was started, does not support.
+    Unsupported,
+    /// An `assert` in the script that does not hold.
+    Assertion,
+    /// Anything else, such as an undefined variable.
+    Other,
 }
 
-pub struct  Error {
-    repr: ErrorType
+impl ErrorKind {
+    /// How the kind is named in reports, and in the replies of an agent.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Parse => "parse error",
+            ErrorKind::Io(_) => "io error",
+            ErrorKind::Permission(_) => "permission denied",
+            ErrorKind::Remote => "remote error",
+            ErrorKind::CommandFailed(_) => "command failed",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Assertion => "assertion failed",
+            ErrorKind::Other => "error",
+        }
+    }
+
+    /// The kind an agent replied with, without the path or failure it does not send.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "parse error" => ErrorKind::Parse,
+            "io error" => ErrorKind::Io(None),
+            "permission denied" => ErrorKind::Permission(None),
+            "remote error" => ErrorKind::Remote,
+            "unsupported" => ErrorKind::Unsupported,
+            "assertion failed" => ErrorKind::Assertion,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// An error with its kind, where in the script it was raised, and the error that
+/// caused it if any.
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+    span: Option<Span>,
+    cause: Option<Box<Error>>,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(kind: ErrorKind, s: S) -> Self {
+        Error { kind, message: s.into(), span: None, cause: None }
+    }
+
+    pub fn message<S: Into<String>>(s: S) -> Self {
+        Error::new(ErrorKind::Other, s)
+    }
+
+    pub fn failed<S: Into<String>>(s: S, failure: Failure) -> Self {
+        Error::new(ErrorKind::CommandFailed(failure), s)
+    }
+
+    /// An IO error on `path`, a permission error when that is why it failed.
+    pub fn io(path: &str, e: IOError) -> Self {
+        let message = format!("{}: {}", path, e);
+        let path = Some(path.to_string());
+        let kind = match e.kind() {
+            io::ErrorKind::PermissionDenied => ErrorKind::Permission(path),
+            _ => ErrorKind::Io(path),
+        };
+        Error::new(kind, message)
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn cause(&self) -> Option<&Error> {
+        self.cause.as_deref()
+    }
+
+    /// Sets where the error was raised, unless a statement inside it already has. The
+    /// empty span of a node that was not parsed from a script is ignored.
+    pub fn with_span(mut self, span: Span) -> Self {
+        if span.line > 0 {
+            self.span.get_or_insert(span);
+        }
+        self
+    }
+
+    /// Wraps the error in a new one of the same kind, saying what was being done.
+    pub fn context<S: Into<String>>(self, s: S) -> Self {
+        Error {
+            kind: self.kind.clone(),
+            message: s.into(),
+            span: self.span,
+            cause: Some(Box::new(self)),
+        }
+    }
+
+    /// The error as a value, the failure of the command when there is one.
+    pub fn failure(&self) -> Failure {
+        match self.kind {
+            ErrorKind::CommandFailed(ref failure) => failure.clone(),
+            _ => Failure::Error(self.to_string()),
+        }
+    }
+
+    /// The error as the command line reports it: the kind and message, the line of
+    /// the script it was raised at, and its causes. `files` are the names and sources
+    /// of the script files, indexed by the file of a span.
+    pub fn render(&self, files: &[(String, String)]) -> String {
+        let mut report = format!("{}: {}\n", self.kind.name(), self.message);
+        if let Some(span) = self.span {
+            if let Some((name, source)) = files.get(span.file) {
+                let line = source.lines().nth(span.line - 1).unwrap_or_default();
+                let number = span.line.to_string();
+                let gutter = " ".repeat(number.len());
+                report.push_str(&format!("{}--> {}:{}:{}\n", gutter, name, span.line, span.column));
+                report.push_str(&format!("{} |\n{} | {}\n", gutter, number, line));
+                report.push_str(&format!("{} | {}^\n", gutter, " ".repeat(span.column - 1)));
+            }
+        }
+
+        let mut cause = self.cause();
+        while let Some(e) = cause {
+            report.push_str(&format!("caused by: {}\n", e.message));
+            cause = e.cause();
+        }
+        report
+    }
 }
 
 impl fmt::Debug for Error {
     fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(&self.repr, f)
+        write!(f, "{:?}({:?})", self.kind, self.message)?;
+        if let Some(ref cause) = self.cause {
+            write!(f, " caused by {:?}", cause)?;
+        }
+        Ok(())
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.repr {
-            ErrorType::IOError(ref e) => e.fmt(fmt),
-            ErrorType::Message(ref s) => write!(fmt, "{}", s)
-        }
+        write!(fmt, "{}", self.message)
     }
 }
 
 impl From<IOError> for Error {
     fn from(e: IOError) -> Self {
-        Error { repr: ErrorType::IOError(Box::new(e)) }
+        let kind = match e.kind() {
+            io::ErrorKind::PermissionDenied => ErrorKind::Permission(None),
+            _ => ErrorKind::Io(None),
+        };
+        Error::new(kind, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "./etc/ (exists)\nsystem (deploy)\n";
+        let span = Span { file: 0, start: 16, end: 31, line: 2, column: 1 };
+        let e = Error::io("./etc/app.conf", IOError::from(io::ErrorKind::PermissionDenied))
+            .context("Unable to read template ./etc/app.conf")
+            .with_span(span);
+
+        assert_eq!(e.kind(), &ErrorKind::Permission(Some("./etc/app.conf".to_string())));
+        assert_eq!(e.to_string(), "Unable to read template ./etc/app.conf");
+        assert_eq!(e.render(&[("site.idem".to_string(), source.to_string())]), "\
+permission denied: Unable to read template ./etc/app.conf
+ --> site.idem:2:1
+  |
+2 | system (deploy)
+  | ^
+caused by: ./etc/app.conf: permission denied
+");
     }
 }
@@ -0,0 +1,186 @@
+//! Privilege escalation for `Exec` drivers.
+//!
+//! A driver escalates by starting a second agent on the same host with the agent
+//! command wrapped by `sudo`, `su` or a configured command. All operations for that
+//! user then go through the one agent, so a password is only sent when it starts.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::errors::{Error, ErrorKind, Result as ExecResult};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EscalationMethod {
+    Sudo,
+    Su,
+    /// A shell command template using `{user}`, `{group}` and `{command}`.
+    Command(String),
+}
+
+impl EscalationMethod {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "sudo" => EscalationMethod::Sudo,
+            "su" => EscalationMethod::Su,
+            _ => EscalationMethod::Command(s.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Escalation {
+    pub user: String,
+    pub group: Option<String>,
+    pub method: EscalationMethod,
+    pub password: Option<String>,
+}
+
+/// Quotes `s` for use as a single word in a POSIX shell command.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl Escalation {
+    /// Builds an escalation from a `user` or `user:group` target.
+    pub fn new(target: &str, method: EscalationMethod) -> ExecResult<Self> {
+        let mut parts = target.splitn(2, ':');
+        let user = parts.next().unwrap_or_default().to_string();
+        let group = parts.next().map(|g| g.to_string());
+
+        if user.is_empty() || group.as_ref().is_some_and(|g| g.is_empty()) {
+            return Err(Error::message(format!("Invalid user:group for become: {:?}", target)));
+        }
+
+        Ok(Escalation { user, group, method, password: None })
+    }
+
+    /// Wraps a shell command so that it runs as the target user.
+    ///
+    /// When a password is set it must be written as the first line of the command's
+    /// standard input. `sudo -v` reads it from a separate pipe, so the line is consumed
+    /// even when sudo turns out not to need it.
+    pub fn wrap(&self, command: &str) -> ExecResult<String> {
+        let user = shell_quote(&self.user);
+        let group = self.group.as_ref().map(|g| shell_quote(g));
+
+        let wrapped = match self.method {
+            EscalationMethod::Sudo => {
+                let group = group.map(|g| format!(" -g {}", g)).unwrap_or_default();
+                let sudo = format!("sudo -n -u {}{} -- {}", user, group, command);
+                match self.password {
+                    Some(_) => format!("IFS= read -r p; printf '%s\\n' \"$p\" | sudo -S -p '' -v && exec {}", sudo),
+                    None => sudo,
+                }
+            }
+            EscalationMethod::Su => {
+                if self.password.is_some() {
+                    return Err(Error::new(ErrorKind::Unsupported, "su reads passwords from a terminal, use sudo to become with a password"));
+                }
+                let group = group.map(|g| format!(" -g {}", g)).unwrap_or_default();
+                format!("su -s /bin/sh{} {} -c {}", group, user, shell_quote(command))
+            }
+            EscalationMethod::Command(ref template) => {
+                let template = if template.contains("{command}") {
+                    template.clone()
+                } else {
+                    format!("{} {{command}}", template)
+                };
+                template
+                    .replace("{user}", &user)
+                    .replace("{group}", &group.unwrap_or_default())
+                    .replace("{command}", command)
+            }
+        };
+
+        Ok(format!("sh -c {}", shell_quote(&wrapped)))
+    }
+}
+
+/// Asks for the password used to become another user on the given host.
+pub type PasswordPrompt = Arc<dyn Fn(&str) -> ExecResult<String> + Send + Sync>;
+
+/// How statements and blocks with `become` escalate, shared by all hosts.
+#[derive(Clone)]
+pub struct EscalationConfig {
+    pub method: EscalationMethod,
+    pub prompt: Option<PasswordPrompt>,
+    passwords: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Default for EscalationConfig {
+    fn default() -> Self {
+        EscalationConfig::new(EscalationMethod::Sudo, None)
+    }
+}
+
+impl EscalationConfig {
+    pub fn new(method: EscalationMethod, prompt: Option<PasswordPrompt>) -> Self {
+        EscalationConfig {
+            method,
+            prompt,
+            passwords: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Builds the escalation to `target` on `host`, asking for the host's password the
+    /// first time it is needed.
+    pub fn escalation(&self, host: &str, target: &str) -> ExecResult<Escalation> {
+        let mut escalation = Escalation::new(target, self.method.clone())?;
+
+        if let Some(ref prompt) = self.prompt {
+            let mut passwords = self.passwords.lock().unwrap();
+            let password = match passwords.get(host) {
+                Some(password) => password.clone(),
+                None => {
+                    let password = prompt(host)?;
+                    passwords.insert(host.to_string(), password.clone());
+                    password
+                }
+            };
+            escalation.password = Some(password);
+        }
+
+        Ok(escalation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let sudo = Escalation::new("app:www", EscalationMethod::Sudo).unwrap();
+        assert_eq!(sudo.wrap("idemsh agent").unwrap(), r#"sh -c 'sudo -n -u '\''app'\'' -g '\''www'\'' -- idemsh agent'"#);
+
+        let su = Escalation::new("root", EscalationMethod::Su).unwrap();
+        assert_eq!(su.wrap("idemsh agent").unwrap(), r#"sh -c 'su -s /bin/sh '\''root'\'' -c '\''idemsh agent'\'''"#);
+
+        let doas = Escalation::new("root", EscalationMethod::parse("doas -u {user}")).unwrap();
+        assert_eq!(doas.wrap("idemsh agent").unwrap(), r#"sh -c 'doas -u '\''root'\'' idemsh agent'"#);
+
+        assert!(Escalation::new("root:", EscalationMethod::Sudo).is_err());
+    }
+
+    #[test]
+    fn test_password_prompted_once_per_host() {
+        let prompts = Arc::new(AtomicUsize::new(0));
+        let counter = prompts.clone();
+        let config = EscalationConfig::new(EscalationMethod::Sudo, Some(Arc::new(move |host: &str| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(format!("secret-{}", host))
+        })));
+
+        let first = config.escalation("web-1", "root").unwrap();
+        let second = config.escalation("web-1", "app").unwrap();
+        let other = config.escalation("web-2", "root").unwrap();
+
+        assert_eq!(first.password, Some("secret-web-1".to_string()));
+        assert_eq!(second.password, Some("secret-web-1".to_string()));
+        assert_eq!(other.password, Some("secret-web-2".to_string()));
+        assert_eq!(prompts.load(Ordering::SeqCst), 2);
+        assert!(first.wrap("idemsh agent").unwrap().contains("sudo -S -p '\\'''\\'' -v"));
+    }
+}
@@ -0,0 +1,138 @@
+//! Values taken out of files by `each @/path as c in file` and `each @~/regexp/ as c in file`.
+
+use std::collections::BTreeMap;
+
+use regex::Regex;
+
+use super::ast::IdemAccessor;
+use super::edit::json::Json;
+use super::edit::yaml::Yaml;
+use super::errors::{Error, Result as ExecResult};
+use super::value::Value;
+
+/// One step of a path, `name`, `[1]` or `*`.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Key(String),
+    Index(i64),
+    Any,
+}
+
+/// `/parent/child[*]` is `parent`, `child` and any item of it.
+fn parse_path(path: &str) -> ExecResult<Vec<Step>> {
+    let invalid = || Error::message(format!("Invalid path {:?}, expected keys such as /parent/child[*]", path));
+    let mut steps = vec![];
+
+    for segment in path.strip_prefix('/').ok_or_else(invalid)?.split('/').filter(|s| !s.is_empty()) {
+        let (key, mut indexes) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        match key {
+            "" => {},
+            "*" => steps.push(Step::Any),
+            key => steps.push(Step::Key(key.replace("~1", "/").replace("~0", "~"))),
+        }
+        while !indexes.is_empty() {
+            let end = indexes.find(']').filter(|_| indexes.starts_with('[')).ok_or_else(invalid)?;
+            steps.push(match &indexes[1..end] {
+                "*" => Step::Any,
+                index => Step::Index(index.trim().parse().map_err(|_| invalid())?),
+            });
+            indexes = &indexes[end + 1..];
+        }
+    }
+
+    Ok(steps)
+}
+
+/// The values at `path`. Each match of a path with `*` is an item, and the value at a
+/// path without one is iterated like any other collection.
+pub fn extract_path(data: &Value, path: &str) -> ExecResult<Vec<Value>> {
+    let steps = parse_path(path)?;
+    let mut values = vec![data];
+
+    for step in &steps {
+        values = values.into_iter().flat_map(|value| -> Vec<&Value> {
+            match (step, value) {
+                (Step::Any, Value::Map(map)) => map.values().collect(),
+                (Step::Any, Value::List(list)) => list.iter().collect(),
+                (Step::Key(key), Value::Map(map)) => map.get(key).into_iter().collect(),
+                (Step::Key(key), Value::List(list)) => key.parse().ok().and_then(|i: usize| list.get(i)).into_iter().collect(),
+                (Step::Index(i), Value::List(_)) => value.get(&IdemAccessor::Index(*i)).into_iter().collect(),
+                _ => vec![],
+            }
+        }).collect();
+    }
+
+    if steps.contains(&Step::Any) {
+        Ok(values.into_iter().cloned().collect())
+    } else {
+        Ok(values.into_iter().flat_map(|value| value.clone().into_items()).collect())
+    }
+}
+
+/// A list of the match and its groups for each line matching `regexp`, so `c[1]` is the
+/// first group. Groups that did not take part in the match are empty strings.
+pub fn extract_lines(text: &str, regexp: &str) -> ExecResult<Vec<Value>> {
+    let regexp = Regex::new(regexp)
+        .map_err(|e| Error::message(format!("Invalid regular expression {:?}: {}", regexp, e)))?;
+
+    Ok(text.lines()
+        .filter_map(|line| regexp.captures(line))
+        .map(|captures| Value::List(captures.iter()
+            .map(|group| Value::String(group.map_or("", |m| m.as_str()).to_string()))
+            .collect()))
+        .collect())
+}
+
+pub fn json_value(json: &Json) -> Value {
+    match json {
+        Json::Null => Value::Empty,
+        Json::Bool(b) => Value::Bool(*b),
+        Json::Number(n) => n.parse().map(Value::Integer).unwrap_or_else(|_| Value::String(n.to_string())),
+        Json::String(s) => Value::String(s.to_string()),
+        Json::Array(items) => Value::List(items.iter().map(json_value).collect()),
+        Json::Object(entries) => Value::Map(entries.iter().map(|(key, value)| (key.to_string(), json_value(value))).collect::<BTreeMap<_, _>>()),
+    }
+}
+
+/// Plain scalars are typed like JSON values, quoted ones are always strings.
+pub fn yaml_value(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Scalar(scalar) if scalar.style.is_some() => Value::String(scalar.text.to_string()),
+        Yaml::Scalar(scalar) => match scalar.text.as_str() {
+            "" | "~" | "null" | "Null" | "NULL" => Value::Empty,
+            "true" | "True" | "TRUE" => Value::Bool(true),
+            "false" | "False" | "FALSE" => Value::Bool(false),
+            text => text.parse().map(Value::Integer).unwrap_or_else(|_| Value::String(text.to_string())),
+        },
+        Yaml::Seq(items) => Value::List(items.iter().map(yaml_value).collect()),
+        Yaml::Map(entries) => Value::Map(entries.iter().map(|(key, value)| (key.to_string(), yaml_value(value))).collect::<BTreeMap<_, _>>()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edit::json::parse_json;
+
+    #[test]
+    fn test_extract_path() {
+        let data = json_value(&parse_json(r#"{ "parent": { "child": [{ "name": "a" }, { "name": "b" }] }, "port": 80 }"#).unwrap());
+
+        let names: Vec<Value> = extract_path(&data, "/parent/child[*]/name").unwrap();
+        assert_eq!(names, vec![Value::String("a".to_string()), Value::String("b".to_string())]);
+        assert_eq!(extract_path(&data, "/parent/child[-1]/name").unwrap(), vec![Value::String("b".to_string())]);
+        assert_eq!(extract_path(&data, "/parent/child").unwrap().len(), 2);
+        assert_eq!(extract_path(&data, "/*/child/0/name").unwrap(), vec![Value::String("a".to_string())]);
+        assert_eq!(extract_path(&data, "/missing[*]").unwrap(), vec![]);
+        assert!(extract_path(&data, "/parent/child[x]").is_err());
+    }
+
+    #[test]
+    fn test_extract_lines() {
+        let lines = extract_lines("name=web\n# name=old\nname=db\n", "^name=(.*)").unwrap();
+        assert_eq!(lines, vec![
+            Value::List(vec![Value::String("name=web".to_string()), Value::String("web".to_string())]),
+            Value::List(vec![Value::String("name=db".to_string()), Value::String("db".to_string())]),
+        ]);
+    }
+}
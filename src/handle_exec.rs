@@ -1,194 +1,2820 @@
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nom::types::CompleteStr;
+
 use super::ast::*;
-use super::parser::*;
 use super::traits::*;
-use super::errors::Result as ExecResult;
+use super::remote::*;
+use super::scope::Scope;
+use super::edit::blocks::merge_blocks;
+use super::edit::ini::{merge_keys, Syntax};
+use super::edit::json::{apply_json_edits, parse_json};
+use super::edit::yaml::{apply_yaml_edits, parse_yaml};
+use super::edit::Format;
+use super::edit::lines::{apply_edit, Matching};
+use super::edit::marker::{apply_block, DEFAULT_MARKER};
+use super::extract::{extract_lines, extract_path, json_value, yaml_value};
+use super::parser::parse_interpolation;
+use super::template::render_template;
+use super::value::{find_value, Failure, Param, Value};
+use super::errors::{Error, ErrorKind, Result as ExecResult};
+
+/// Set by Ctrl-C, scripts stop before their next statement.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops the running scripts before their next statement, returning whether they had
+/// already been interrupted.
+pub fn interrupt() -> bool {
+    INTERRUPTED.swap(true, atomic::Ordering::SeqCst)
+}
 
-#[derive(Debug, PartialEq)]
 pub struct HandleExec<'e, E: Exec> {
     driver: &'e mut E,
-    cwd: String
+    output: Option<&'e mut (dyn Write + Send)>,
+    stdout: io::Stdout,
+    remote: RemoteConfig,
+    host: String,
+    /// Drivers for statements with `become`, opened once per user.
+    escalated: HashMap<String, Box<dyn Exec + Send>>,
+    scope: Scope,
+    /// The statement being run, to say where an undefined variable was used.
+    statement: Option<String>,
+    /// The names of the files of the script, by their index in spans.
+    files: Vec<String>,
+    /// The file of the statement being run, which templates are relative to.
+    file: usize,
+    /// Commands defined with `def`, those of imported files named `<name>.<command>`.
+    commands: HashMap<String, Arc<IdemRawCommandDef>>,
+    /// The name of the file being imported, or of the one that defined the command
+    /// being run, whose commands are called without it.
+    namespace: Option<String>,
+    /// Block commands defined with `defblock`.
+    blocks: HashMap<String, Arc<IdemRawCommandDefBlock>>,
+    /// Set by `return` until the command returning it has finished.
+    returned: Option<Value>,
+    handlers: Vec<Arc<IdemRawCommandHandler>>,
+    /// Handlers to run after the script.
+    notified: HashSet<String>,
+    /// Whether the statement being run has changed anything so far.
+    changed: bool,
+    /// Run notified handlers even after a statement failed.
+    force_handlers: bool,
+}
+
+/// The variable a `def` param is bound to, its first name that is not a short flag.
+fn param_var(param: &IdemDefParam) -> String {
+    param.names.iter()
+        .find(|name| !name.starts_with('-'))
+        .unwrap_or(&param.names[0])
+        .trim_start_matches('-')
+        .to_string()
+}
+
+/// Names a command by its params, for messages about it.
+fn describe_params(params: &[Spanned<IdemParamType>]) -> String {
+    match params.iter().map(|param| &param.node).collect::<Vec<_>>()[..] {
+        [IdemParamType::Call(name, _)] | [IdemParamType::FlagKeyword(name)] => name.to_string(),
+        _ => "statement".to_string(),
+    }
+}
+
+/// Names a statement for messages about it, such as `let version` or `./etc/motd`.
+fn describe_statement(cmd: &IdemRawCommandType) -> String {
+    let path = |path: &Spanned<IdemPath>| match &path.1 {
+        IdemPathLocalPartType::File(file) => file.to_string(),
+        IdemPathLocalPartType::Directory(dir) => format!("{}/", dir),
+    };
+
+    match cmd {
+        IdemRawCommandType::Each(var, _, _, _) => format!("each {}", var),
+        IdemRawCommandType::WithPaths(obj) => obj.paths.iter().map(path).collect::<Vec<_>>().join(" "),
+        IdemRawCommandType::WithBlock(_, _, _) => "with".to_string(),
+        IdemRawCommandType::Remotes(remotes) => format!("remotes {}", remotes.hosts),
+        IdemRawCommandType::Using(using) => format!("using {}", using.interpreter),
+        IdemRawCommandType::System(system) => format!("system ({})", system.command),
+        IdemRawCommandType::Def(def) => format!("def {}", def.name),
+        IdemRawCommandType::Return(_) => "return".to_string(),
+        IdemRawCommandType::DefBlock(def) => format!("defblock {}", def.name),
+        IdemRawCommandType::BlockCall(call) => call.name.to_string(),
+        IdemRawCommandType::Call(_) => "call".to_string(),
+        IdemRawCommandType::Edit(edit) => format!("{} (edit)", path(&edit.path)),
+        IdemRawCommandType::Let(name, _) => format!("let {}", name),
+        IdemRawCommandType::If(_) => "if".to_string(),
+        IdemRawCommandType::Handler(handler) => format!("handler {}", handler.name),
+        IdemRawCommandType::Try(_) => "try".to_string(),
+        IdemRawCommandType::Assert(_, text) => format!("assert {}", text),
+        IdemRawCommandType::Include(include) => format!("include {}", include.path),
+        IdemRawCommandType::Import(import) => format!("import {} as {}", import.path, import.name),
+    }
+}
+
+fn statement_params(cmd: &IdemRawCommandType) -> &[Spanned<IdemParamType>] {
+    match cmd {
+        IdemRawCommandType::WithPaths(obj) => &obj.params,
+        IdemRawCommandType::System(system) => &system.params,
+        IdemRawCommandType::Using(using) => &using.params,
+        IdemRawCommandType::Remotes(remotes) => &remotes.params,
+        IdemRawCommandType::Edit(edit) => &edit.params,
+        IdemRawCommandType::BlockCall(call) => &call.args,
+        _ => &[],
+    }
+}
+
+/// The `when =` and `unless =` guards of a statement.
+fn statement_guards(cmd: &IdemRawCommandType) -> Vec<&IdemCondition> {
+    statement_params(cmd).iter().filter_map(|param| match param.node {
+        IdemParamType::When(ref condition) => Some(condition),
+        _ => None,
+    }).collect()
+}
+
+/// Params that change how a statement runs rather than what it does.
+const CONTROL_PARAMS: &[&str] = &["notify", "ignore_errors", "retries", "delay"];
+
+fn is_control_param(param: &IdemParamType) -> bool {
+    match param {
+        IdemParamType::KeyValue(key, _) | IdemParamType::FlagKeyword(key) => CONTROL_PARAMS.contains(&key.as_str()),
+        _ => false,
+    }
+}
+
+/// The control params named `key` of a statement, also found among the arguments of
+/// a call.
+fn statement_control<'a>(cmd: &'a IdemRawCommandType, key: &str) -> Vec<&'a IdemParamType> {
+    let named = |param: &&IdemParamType| match param {
+        IdemParamType::KeyValue(name, _) | IdemParamType::FlagKeyword(name) => name == key,
+        _ => false,
+    };
+    let mut params = vec![];
+    for param in statement_params(cmd) {
+        match &param.node {
+            IdemParamType::Call(_, args) => params.extend(args.iter().map(|arg| &arg.node).filter(named)),
+            param => params.extend(Some(param).filter(named)),
+        }
+    }
+    params
+}
+
+/// The `notify =` values of a statement.
+fn statement_notify(cmd: &IdemRawCommandType) -> Vec<&IdemValueType> {
+    statement_control(cmd, "notify").into_iter().filter_map(|param| match param {
+        IdemParamType::KeyValue(_, value) => Some(&value.node),
+        _ => None,
+    }).collect()
+}
+
+/// What `rescue <var>` binds: the error `message` and `kind`, the `failure` and, for
+/// commands that exited with a status, its `code`.
+fn error_value(error: &Error) -> Value {
+    let failure = error.failure();
+    let mut map = BTreeMap::new();
+    map.insert("message".to_string(), Value::String(error.to_string()));
+    map.insert("kind".to_string(), Value::from(error.kind().name()));
+    if let Failure::ResultCode(code) = failure {
+        map.insert("code".to_string(), Value::Integer(code.into()));
+    }
+    map.insert("failure".to_string(), Value::Failure(failure));
+    Value::Map(map)
 }
 
-#[inline(always)]
-fn find_single_path<'a>(obj: &'a IdemRawCommandWithPaths) -> Option<&'a IdemPath> {
-    if obj.paths.len() != 1 { return None; }
-    obj.paths.iter().next()
+/// What a statement did to a path, reported as `changed: <what>` when it modified it
+/// and `ok: <what>` when it was already as wanted.
+struct Outcome {
+    changed: bool,
+    what: String,
 }
 
-#[inline(always)]
-fn find_single_flag_keyword(params: &[IdemParamType]) -> Option<&str> {
-    if params.len() != 1 { return None; }
+impl Outcome {
+    fn new(changed: bool, what: String) -> Self {
+        Outcome { changed, what }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", if self.changed { "changed" } else { "ok" }, self.what)
+    }
+}
+
+/// Orders integers, and strings holding them, by value and anything else by its text.
+fn compare(a: &Value, op: IdemCompareOp, b: &Value) -> bool {
+    let (a, b) = (a.to_string(), b.to_string());
+    let ordering = match (a.parse::<i64>(), b.parse::<i64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(&b),
+    };
+
+    match op {
+        IdemCompareOp::Eq => ordering == Ordering::Equal,
+        IdemCompareOp::Ne => ordering != Ordering::Equal,
+        IdemCompareOp::Lt => ordering == Ordering::Less,
+        IdemCompareOp::Le => ordering != Ordering::Greater,
+        IdemCompareOp::Gt => ordering == Ordering::Greater,
+        IdemCompareOp::Ge => ordering != Ordering::Less,
+    }
+}
+
+/// Splits a command line into words the way a shell would, honouring quotes and
+/// backslash escapes but without expanding anything.
+fn split_words(command: &str) -> ExecResult<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                let escaped = chars.next()
+                    .ok_or_else(|| Error::message(format!("Trailing backslash in {:?}", command)))?;
+                word.get_or_insert_with(String::new).push(escaped);
+            },
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            },
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(Error::message(format!("Unterminated quote in {:?}", command)));
+    }
+    words.extend(word);
+    Ok(words)
+}
 
-    match params[0] {
-        IdemParamType::FlagKeyword(ref s) => {
-            Some(s)
+/// Applies the params of a paths statement to each path, returning what was done.
+fn apply_with_paths(driver: &mut dyn Exec, paths: &[IdemPath], params: &[Param]) -> ExecResult<Vec<Outcome>> {
+    let mut report = vec![];
+    let marker = find_value(params, "marker").map_or_else(|| DEFAULT_MARKER.to_string(), |marker| marker.to_string());
+    let absent = params.iter().any(|param| matches!(param, Param::Flag(ref flag) if flag == "absent"));
+
+    for param in params {
+        match param {
+            Param::Flag(ref flag) if flag == "exists" => {
+                for IdemPath(_, path) in paths {
+                    match path {
+                        IdemPathLocalPartType::Directory(ref dir) => {
+                            report.push(Outcome::new(driver.ensure_directory(dir)?, format!("{}/ (exists)", dir)));
+                        },
+                        IdemPathLocalPartType::File(ref path) => {
+                            report.push(Outcome::new(driver.ensure_file_exists(path)?, format!("{} (exists)", path)));
+                        },
+                    }
+                }
+            },
+
+            // A template has been rendered by the caller
+            Param::KeyValue(ref key, ref value) if key == "content" || key == "template" => {
+                for IdemPath(_, path) in paths {
+                    match path {
+                        IdemPathLocalPartType::File(ref path) => {
+                            let changed = driver.ensure_file_contents(path, FileContents::StaticString(value.to_string()))?;
+                            report.push(Outcome::new(changed, format!("{} ({})", path, key)));
+                        },
+                        IdemPathLocalPartType::Directory(ref dir) => {
+                            return Err(Error::message(format!("{} can not be set on directory {}/", key, dir)));
+                        },
+                    }
+                }
+            },
+
+            Param::KeyValue(ref key, _) if key == "block" && absent => {
+                return Err(Error::message("block = ... sets the block, it can not be absent"));
+            },
+
+            Param::KeyValue(ref key, ref value) if key == "block" => {
+                report.extend(ensure_blocks(driver, paths, &marker, Some(&value.to_string()))?);
+            },
+
+            Param::Flag(ref flag) if flag == "absent" => {
+                report.extend(ensure_blocks(driver, paths, &marker, None)?);
+            },
+
+            // Handled with block or absent
+            Param::KeyValue(ref key, _) if key == "marker" => {},
+
+            // Handled by the caller
+            Param::KeyValue(ref key, _) if key == "become" => {},
+
+            Param::Flag(ref flag) => return Err(Error::message(format!("Unknown flag: {}", flag))),
+            Param::KeyValue(ref key, _) => return Err(Error::message(format!("Unknown param: {}", key))),
+            Param::Positional(ref value) => return Err(Error::message(format!("Unexpected value: {}", value.literal()))),
         }
+    }
+
+    Ok(report)
+}
+
+/// Sets or removes the block between the marker lines of each file, writing it only
+/// when it changes.
+fn ensure_blocks(driver: &mut dyn Exec, paths: &[IdemPath], marker: &str, contents: Option<&str>) -> ExecResult<Vec<Outcome>> {
+    let mut report = vec![];
 
-        _ => None
+    for IdemPath(_, path) in paths {
+        let path = match path {
+            IdemPathLocalPartType::File(ref path) => path,
+            IdemPathLocalPartType::Directory(ref dir) => {
+                return Err(Error::message(format!("A block can not be managed in directory {}/", dir)));
+            },
+        };
+
+        let current = match driver.read_file(path)? {
+            Some(current) => current,
+            None if contents.is_none() => {
+                report.push(Outcome::new(false, format!("{} (block)", path)));
+                continue;
+            },
+            None => String::new(),
+        };
+        let edited = apply_block(&current, marker, contents)
+            .map_err(|e| Error::message(format!("Unable to edit {}: {}", path, e)))?;
+
+        let changed = edited != current && driver.ensure_file_contents(path, FileContents::StaticString(edited))?;
+        report.push(Outcome::new(changed, format!("{} (block)", path)));
     }
+
+    Ok(report)
 }
 
 impl<'e, E: Exec> HandleExec<'e, E> {
     pub fn new(driver: &'e mut E) -> Self {
         HandleExec {
-            driver: driver,
-            cwd: "./".to_string(),
+            driver,
+            output: None,
+            stdout: io::stdout(),
+            remote: RemoteConfig::default(),
+            host: "localhost".to_string(),
+            escalated: HashMap::new(),
+            scope: Scope::default(),
+            statement: None,
+            files: vec![],
+            file: 0,
+            commands: HashMap::new(),
+            namespace: None,
+            blocks: HashMap::new(),
+            returned: None,
+            handlers: vec![],
+            notified: HashSet::new(),
+            changed: false,
+            force_handlers: false,
         }
     }
 
-    pub fn execute_raw_script_command(&mut self, cmd: &IdemRawCommandType) -> ExecResult<()> {
-        match cmd {
-            IdemRawCommandType::WithPaths(obj) => {
-                let flag = find_single_flag_keyword(&obj.params);
-                if flag.is_some() {
-                    eprintln!("Found single flag keyword: {:?}", flag);
-                    match flag.unwrap() {
-                        "exists" => {
-                            let path = find_single_path(obj);
-                            assert!(path.is_some(), "exists flag expects to follow a single path");
-                            let IdemPath(_, path) = path.unwrap();
-
-                            match path {
-                                IdemPathLocalPartType::Directory(ref dir) => {
-                                    self.driver.ensure_directory(dir)
-                                },
-                                IdemPathLocalPartType::File(ref path) => {
-                                    self.driver.ensure_file_exists(path)
-                                },
-                            }
-                        },
+    pub fn with_output(mut self, output: &'e mut (dyn Write + Send)) -> Self {
+        self.output = Some(output);
+        self
+    }
 
-                        _ => unimplemented!("Flag keyword not implemented")
-                    };
-                };
+    pub fn with_remote_config(mut self, remote: RemoteConfig) -> Self {
+        self.remote = remote;
+        self
+    }
 
-                Ok(())
-            }
+    /// The name of the host the driver operates on.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn with_var<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.scope.bind(name, value.into());
+        self
+    }
+
+    /// The names of the files the script was read from, by their index in spans.
+    pub fn with_files(mut self, files: Vec<String>) -> Self {
+        self.files = files;
+        self
+    }
 
-            _ => unimplemented!("Not implemented")
+    pub fn with_force_handlers(mut self, force_handlers: bool) -> Self {
+        self.force_handlers = force_handlers;
+        self
+    }
+
+    /// A variable given on the command line, which `let` in the script does not change.
+    pub fn with_override<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.scope.bind_override(name, value.into());
+        self
+    }
+
+    fn output(&mut self) -> &mut (dyn Write + Send) {
+        match self.output {
+            Some(ref mut output) => *output,
+            None => &mut self.stdout,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use nom::types::CompleteStr;
+    /// The driver running operations as `target`, a `user` or `user:group`.
+    fn escalated_driver(&mut self, target: &str) -> ExecResult<&mut (dyn Exec + Send)> {
+        if !self.escalated.contains_key(target) {
+            let escalation = self.remote.escalation.escalation(&self.host, target)?;
+            let driver = self.driver.escalate(&escalation)?;
+            self.escalated.insert(target.to_string(), driver);
+        }
 
-    #[macro_use]
-    use super::*;
+        Ok(&mut **self.escalated.get_mut(target).unwrap())
+    }
 
-    #[derive(Debug, PartialEq, Clone)]
-    pub struct TestExec {
-        cwd: String,
-        pub created_dirs: Vec<String>,
-        pub created_files: Vec<String>,
+    /// The driver a statement runs with, taking its `become` param into account.
+    fn statement_driver(&mut self, params: &[Param]) -> ExecResult<&mut dyn Exec> {
+        match find_value(params, "become") {
+            Some(target) => Ok(self.escalated_driver(&target.to_string())?),
+            None => Ok(self.driver),
+        }
     }
 
-    impl TestExec {
-        pub fn new(cwd: &str) -> Self {
-            TestExec {
-                cwd: cwd.to_string(),
-                created_dirs: vec![],
-                created_files: vec![],
+    /// Looks up a variable and follows its accessors.
+    fn lookup(&self, name: &str, accessors: &[IdemAccessor]) -> ExecResult<Value> {
+        let mut value = self.scope.get(name).ok_or_else(|| match self.statement {
+            Some(ref statement) => Error::message(format!("Undefined variable ${} in {}", name, statement)),
+            None => Error::message(format!("Undefined variable ${}", name)),
+        })?;
+
+        for accessor in accessors {
+            value = value.get(accessor).ok_or_else(|| match accessor {
+                IdemAccessor::Key(key) => Error::message(format!("{} has no key {:?}", name, key)),
+                IdemAccessor::Index(i) => Error::message(format!("{} has no index {}", name, i)),
+            })?;
+        }
+
+        Ok(value.clone())
+    }
+
+    /// Replaces a leading `$name` in a path with the value of the variable.
+    fn resolve_path(&self, path: &IdemPath) -> ExecResult<IdemPath> {
+        let IdemPath(ref host, ref local_part) = *path;
+        let s = match local_part {
+            IdemPathLocalPartType::File(s) => s.to_string(),
+            IdemPathLocalPartType::Directory(s) => format!("{}/", s),
+        };
+        if !s.starts_with('$') {
+            return Ok(path.clone());
+        }
+
+        let name_end = s[1..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map(|i| i + 1)
+            .unwrap_or(s.len());
+        let value = self.lookup(&s[1..name_end], &[])?.to_string();
+        let rest = &s[name_end..];
+        let resolved = match (value.ends_with('/'), rest.strip_prefix('/')) {
+            (true, Some(rest)) => format!("{}{}", value, rest),
+            _ => format!("{}{}", value, rest),
+        };
+
+        Ok(match resolved.strip_suffix('/') {
+            Some(dir) => IdemPath(host.clone(), IdemPathLocalPartType::Directory(dir.to_string())),
+            None => IdemPath(host.clone(), IdemPathLocalPartType::File(resolved)),
+        })
+    }
+
+    /// Evaluates a value in the current scope, running any commands it contains.
+    pub fn evaluate(&mut self, value: &IdemValueType) -> ExecResult<Value> {
+        Ok(match value {
+            IdemValueType::LitString(s) | IdemValueType::ExtendedString(s) => Value::String(self.interpolate(s)?),
+            IdemValueType::Integer(n) => Value::Integer(*n),
+            IdemValueType::Boolean(b) => Value::Bool(*b),
+            IdemValueType::PathSpec(path) => match self.resolve_path(path)? {
+                IdemPath(_, IdemPathLocalPartType::File(s)) => Value::Path(s),
+                IdemPath(_, IdemPathLocalPartType::Directory(s)) => Value::Path(format!("{}/", s)),
+            },
+            IdemValueType::Empty => Value::Empty,
+            IdemValueType::List(values) => Value::List(values.iter()
+                .map(|value| self.evaluate(value))
+                .collect::<ExecResult<_>>()?),
+            IdemValueType::Map(entries) => Value::Map(entries.iter()
+                .map(|(key, value)| Ok((key.to_string(), self.evaluate(value)?)))
+                .collect::<ExecResult<BTreeMap<_, _>>>()?),
+            IdemValueType::Variable(name, accessors) => self.lookup(name, accessors)?,
+            IdemValueType::Command(cmd) => self.evaluate_command(cmd)?,
+        })
+    }
+
+    /// Evaluates params, splitting short flags such as `-rf` into `-r` and `-f`.
+    fn evaluate_params(&mut self, params: &[Spanned<IdemParamType>]) -> ExecResult<Vec<Param>> {
+        let mut evaluated = vec![];
+
+        for param in params {
+            match &param.node {
+                // Handled by execute_statement
+                IdemParamType::When(_) => {},
+                param if is_control_param(param) => {},
+                IdemParamType::FlagKeyword(flag) => evaluated.push(Param::Flag(flag.to_string())),
+                IdemParamType::ShortFlags(flags) => evaluated.extend(flags.iter().map(|c| Param::Flag(format!("-{}", c)))),
+                IdemParamType::KeyValue(key, value) => evaluated.push(Param::KeyValue(key.to_string(), self.evaluate(value)?)),
+                IdemParamType::Positional(value) => evaluated.push(Param::Positional(self.evaluate(value)?)),
+                IdemParamType::Call(name, _) => return Err(Error::message(format!("{} must be the only param", name))),
             }
         }
+
+        Ok(evaluated)
     }
 
-    #[inline]
-    fn join_paths(a: &str, b: &str) -> String {
-        let a = a.trim_end_matches("/").replace("./", "");
-        let b = b.trim_start_matches("/").replace("./", "");
-        format!("{}/{}", a, b)
+    /// Replaces each `{{ expr }}` in `text` with the value of the expression, usually
+    /// a variable such as `{{ host }}` or `{{ c.name }}`.
+    fn interpolate(&mut self, text: &str) -> ExecResult<String> {
+        let mut result = String::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}")
+                .ok_or_else(|| Error::message(format!("Unterminated {{{{ in {:?}", text)))? + start;
+            let expr = &rest[start + 2..end];
+            let value = match parse_interpolation(CompleteStr(expr)) {
+                Ok((_, value)) => self.evaluate(&value)?,
+                Err(_) => return Err(Error::message(format!("Invalid expression: {{{{{}}}}}", expr))),
+            };
+
+            result.push_str(&rest[..start]);
+            result.push_str(&value.to_string());
+            rest = &rest[end + 2..];
+        }
+
+        result.push_str(rest);
+        Ok(result)
     }
 
-    impl Exec for TestExec {
-        fn change_directory(&mut self, dir: &str) -> ExecResult<()> {
-            Ok(())
+    /// Runs a command for its value, a failure is returned rather than raised.
+    pub fn evaluate_command(&mut self, cmd: &Spanned<IdemRawCommandType>) -> ExecResult<Value> {
+        match &cmd.node {
+            IdemRawCommandType::System(system) => self.execute_system(system),
+            IdemRawCommandType::Using(using) => self.execute_using(using),
+            IdemRawCommandType::WithPaths(obj) => self.execute_with_paths(obj),
+            _ => self.execute_statement(cmd),
+        }
+    }
+
+    /// Runs a script, then the handlers its statements notified.
+    pub fn execute_raw_script(&mut self, script: &[Spanned<IdemRawCommandType>]) -> ExecResult<()> {
+        // Handlers can be notified before the script reaches them
+        self.define_handlers(script);
+
+        let result = script.iter().try_for_each(|cmd| {
+            if self.returned.is_some() {
+                return Ok(());
+            }
+            self.execute_raw_script_command(cmd)
+        });
+        self.run_handlers(result)
+    }
+
+    /// Defines the handlers of a script, those of the files it includes too.
+    fn define_handlers(&mut self, script: &[Spanned<IdemRawCommandType>]) {
+        for cmd in script {
+            match &cmd.node {
+                IdemRawCommandType::Handler(handler) => self.define_handler(handler),
+                IdemRawCommandType::Include(include) => self.define_handlers(&include.statements),
+                _ => {}
+            }
         }
+    }
 
-        fn ensure_directory(&mut self, local_part: &str) -> ExecResult<()> {
-            let dir = join_paths(&self.cwd, local_part);
-            self.created_dirs.push(dir);
-            Ok(())
+    fn define_handler(&mut self, handler: &IdemRawCommandHandler) {
+        self.handlers.retain(|defined| defined.name != handler.name);
+        self.handlers.push(Arc::new(handler.clone()));
+    }
+
+    /// Runs the notified handlers in the order they are defined, each at most once. They
+    /// only run after statements that failed with `--force-handlers`.
+    fn run_handlers(&mut self, result: ExecResult<()>) -> ExecResult<()> {
+        if result.is_err() && !self.force_handlers {
+            return result;
         }
 
-        fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<()> {
-            let filepath = join_paths(&self.cwd, local_part);
-            self.created_files.push(filepath);
-            Ok(())
+        let mut handled = Ok(());
+        for handler in self.handlers.clone() {
+            if !self.notified.remove(&handler.name) {
+                continue;
+            }
+            writeln!(self.output(), "handler: {}", handler.name)?;
+            handled = self.run_scoped(&handler.statements, vec![]).map(|_| ());
+            self.returned = None;
+            if handled.is_err() {
+                break;
+            }
         }
 
-        fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<()> {
-            let filepath = join_paths(&self.cwd, local_part);
-            self.created_files.push(filepath);
-            Ok(())
+        result.and(handled)
+    }
+
+    fn report(&mut self, outcomes: Vec<Outcome>) -> ExecResult<()> {
+        for outcome in outcomes {
+            self.changed |= outcome.changed;
+            writeln!(self.output(), "{}", outcome)?;
         }
+        Ok(())
+    }
 
-        fn get_cwd(&mut self) -> ExecResult<String> {
-            Ok(self.cwd.to_string())
+    /// Records the handlers a statement that changed something notifies.
+    fn notify(&mut self, cmd: &IdemRawCommandType) -> ExecResult<()> {
+        for value in statement_notify(cmd) {
+            for name in self.evaluate(value)?.into_items() {
+                let name = name.to_string();
+                if !self.handlers.iter().any(|handler| handler.name == name) {
+                    return Err(Error::message(format!("Unknown handler: {}", name)));
+                }
+                self.notified.insert(name);
+            }
         }
+        Ok(())
     }
 
-    macro_rules!  parse (
-        ($code: expr) => ({
-            let expr = parse_raw_script(CompleteStr($code)).unwrap().1;
-            expr
-        });
-    );
+    pub fn execute_raw_script_command(&mut self, cmd: &Spanned<IdemRawCommandType>) -> ExecResult<()> {
+        self.execute_statement(cmd).map(|_| ())
+    }
 
-    #[test]
-    fn test_file_exists() {
-        let script = parse!(r#"
-./afile (exists)
-"#);
+    /// Runs a statement, raising an error if its result is a failure. Statements
+    /// without a result return `(())`. Errors are raised at the statement, unless
+    /// one inside it raised them.
+    fn execute_statement(&mut self, cmd: &Spanned<IdemRawCommandType>) -> ExecResult<Value> {
+        if INTERRUPTED.load(atomic::Ordering::SeqCst) {
+            return Err(Error::message("Interrupted"));
+        }
 
-        // Verify script
-        assert_eq!(script, vec![
-            IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
-                paths: vec![
-                    IdemPath(None, IdemPathLocalPartType::File("./afile".to_string())),
-                ],
-                params: vec![
-                    IdemParamType::FlagKeyword("exists".to_string()),
-                ],
-            })
-        ]);
+        let outer = self.statement.replace(describe_statement(cmd));
+        let outer_file = self.file;
+        if cmd.span.line > 0 {
+            self.file = cmd.span.file;
+        }
+        let outer_changed = std::mem::replace(&mut self.changed, false);
+        let mut result = self.attempt_statement(cmd);
+        if self.changed && result.is_ok() {
+            result = self.notify(cmd).and(result);
+        }
+        if let Err(ref error) = result {
+            if !statement_control(cmd, "ignore_errors").is_empty() && !INTERRUPTED.load(atomic::Ordering::SeqCst) {
+                writeln!(self.output(), "ignored: {}", error)?;
+                result = Ok(Value::Failure(error.failure()));
+            }
+        }
+        self.changed |= outer_changed;
+        self.statement = outer;
+        self.file = outer_file;
+        result.map_err(|e| e.with_span(cmd.span))
+    }
 
-        // Execute script
-        let mut test_exec = TestExec::new("./testing");
-        let mut handle_exec = HandleExec::new(&mut test_exec);
-        handle_exec.execute_raw_script_command(&script[0]).unwrap();
+    /// Runs a statement if its guards hold, again after `delay` seconds for each of
+    /// its `retries` while it fails.
+    fn attempt_statement(&mut self, cmd: &Spanned<IdemRawCommandType>) -> ExecResult<Value> {
+        let retries = self.control_count(cmd, "retries")?;
+        let delay = Duration::from_secs(self.control_count(cmd, "delay")?);
 
-        // Assert result
-        assert_eq!(test_exec.created_files, vec!["testing/afile"]);
+        let mut attempt = 0;
+        loop {
+            let result = match self.guards_hold(cmd) {
+                Ok(true) => self.run_statement(cmd),
+                Ok(false) => self.report_skipped(std::slice::from_ref(cmd)).map(|_| Value::Empty),
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(error) if attempt < retries && !INTERRUPTED.load(atomic::Ordering::SeqCst) => {
+                    attempt += 1;
+                    writeln!(self.output(), "retrying ({}/{}): {}", attempt, retries, error)?;
+                    thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
     }
 
-    #[test]
-    fn test_directory_exists() {
-        let script = parse!(r#"
-./adir/ (exists)
-"#);
+    fn control_count(&mut self, cmd: &IdemRawCommandType, key: &str) -> ExecResult<u64> {
+        let value = match statement_control(cmd, key).first() {
+            Some(IdemParamType::KeyValue(_, value)) => self.evaluate(value)?,
+            Some(_) => return Err(Error::message(format!("{} expects a number", key))),
+            None => return Ok(0),
+        };
+        match value {
+            Value::Integer(n) if n >= 0 => Ok(n as u64),
+            value => Err(Error::message(format!("{} expects a number, got {}", key, value.literal()))),
+        }
+    }
 
-        // Verify script
-        assert_eq!(script, vec![
-            IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
-                paths: vec![
-                    IdemPath(None, IdemPathLocalPartType::Directory("./adir".to_string())),
-                ],
-                params: vec![
-                    IdemParamType::FlagKeyword("exists".to_string()),
-                ],
-            })
-        ]);
+    /// Runs a `try` block, then its `rescue` clause if it failed, and its `always`
+    /// clause whatever happened. An interrupted script is not rescued.
+    fn execute_try(&mut self, obj: &IdemRawCommandTry) -> ExecResult<Value> {
+        let result = match (self.run_scoped(&obj.statements, vec![]), &obj.rescue) {
+            (Err(error), Some(rescue)) if !INTERRUPTED.load(atomic::Ordering::SeqCst) => {
+                writeln!(self.output(), "rescued: {}", error)?;
+                let bound = rescue.var.iter().map(|var| (var.to_string(), error_value(&error))).collect();
+                self.run_scoped(&rescue.statements, bound)
+            }
+            (result, _) => result,
+        };
 
-        // Execute script
-        let mut test_exec = TestExec::new("./testing");
-        let mut handle_exec = HandleExec::new(&mut test_exec);
-        handle_exec.execute_raw_script_command(&script[0]).unwrap();
+        // `always` runs after a `return` too, which still returns its value
+        let returned = self.returned.take();
+        let always = self.run_scoped(&obj.always, vec![]);
+        if self.returned.is_none() {
+            self.returned = returned;
+        }
 
-        // Assert result
-        assert_eq!(test_exec.created_dirs, vec!["testing/adir"]);
+        result.and_then(|value| always.map(|_| value))
     }
 
-}
+    fn guards_hold(&mut self, cmd: &IdemRawCommandType) -> ExecResult<bool> {
+        for condition in statement_guards(cmd) {
+            if !self.condition(condition)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Says which statements did not run, those of an `if` that is skipped as a whole
+    /// included.
+    fn report_skipped(&mut self, statements: &[Spanned<IdemRawCommandType>]) -> ExecResult<()> {
+        for statement in statements {
+            match &statement.node {
+                IdemRawCommandType::If(obj) => {
+                    self.report_skipped(&obj.statements)?;
+                    self.report_skipped(&obj.otherwise)?;
+                }
+                statement => writeln!(self.output(), "skipped: {}", describe_statement(statement))?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn condition(&mut self, condition: &IdemCondition) -> ExecResult<bool> {
+        Ok(match condition {
+            // A command is true when it succeeds, whatever its output
+            IdemCondition::Value(Spanned { node: IdemValueType::Command(cmd), .. }) => self.evaluate_command(cmd)?.is_success(),
+            IdemCondition::Value(value) => self.evaluate(value)?.is_true(),
+            IdemCondition::Compare(a, op, b) => {
+                let (a, b) = (self.evaluate(a)?, self.evaluate(b)?);
+                compare(&a, *op, &b)
+            }
+            IdemCondition::Exists(path) => match self.evaluate(path)? {
+                Value::Path(path) | Value::String(path) => self.driver.path_exists(&path)?,
+                value => return Err(Error::message(format!("exists applies to paths, not {}", value.literal()))),
+            },
+            IdemCondition::Not(condition) => !self.condition(condition)?,
+            IdemCondition::And(a, b) => self.condition(a)? && self.condition(b)?,
+            IdemCondition::Or(a, b) => self.condition(a)? || self.condition(b)?,
+        })
+    }
+
+    fn run_statement(&mut self, cmd: &IdemRawCommandType) -> ExecResult<Value> {
+        match cmd {
+            IdemRawCommandType::WithPaths(obj) => {
+                match self.execute_with_paths(obj)? {
+                    Value::Failure(failure) => Err(Error::failed(format!("{} failed: {}", describe_params(&obj.params), failure), failure)),
+                    value => Ok(value),
+                }
+            }
+
+            IdemRawCommandType::Each(var, extractor, collection, statements) => {
+                self.execute_each(var, extractor.as_ref(), collection, statements).map(|_| Value::Empty)
+            }
+
+            IdemRawCommandType::WithBlock(resource, as_, statements) => {
+                self.execute_with_block(resource, as_.as_deref(), statements).map(|_| Value::Empty)
+            }
+
+            IdemRawCommandType::Remotes(block) => self.execute_remotes(block).map(|_| Value::Empty),
+
+            IdemRawCommandType::Using(using) => match self.execute_using(using)? {
+                Value::Failure(failure) => Err(Error::failed(format!("using {} failed: {}", using.interpreter, failure), failure)),
+                value => Ok(value),
+            },
+
+            IdemRawCommandType::System(system) => {
+                match self.execute_system(system)? {
+                    Value::Failure(failure) => Err(Error::failed(format!("system ({}) failed: {}", system.command, failure), failure)),
+                    value => {
+                        writeln!(self.output(), "ok: system ({})", system.command)?;
+                        Ok(value)
+                    }
+                }
+            }
+
+            IdemRawCommandType::Def(def) => {
+                self.commands.insert(self.qualify(&def.name), Arc::new(def.clone()));
+                Ok(Value::Empty)
+            }
+
+            IdemRawCommandType::Return(value) => {
+                let value = self.evaluate(value)?;
+                self.returned = Some(value.clone());
+                Ok(value)
+            }
+
+            IdemRawCommandType::DefBlock(def) => {
+                self.blocks.insert(self.qualify(&def.name), Arc::new(def.clone()));
+                Ok(Value::Empty)
+            }
+
+            IdemRawCommandType::BlockCall(call) => self.call_block(call),
+
+            IdemRawCommandType::Edit(edit) => self.execute_edit(edit).map(|_| Value::Empty),
+
+            IdemRawCommandType::Call(value) => match self.evaluate(value)? {
+                Value::Block(statements) => self.run_block(&statements),
+                value => Err(Error::message(format!("Can not call {}, it is not a block", value.literal()))),
+            },
+
+            IdemRawCommandType::Handler(handler) => {
+                self.define_handler(handler);
+                Ok(Value::Empty)
+            }
+
+            IdemRawCommandType::Try(obj) => self.execute_try(obj),
+
+            IdemRawCommandType::Assert(condition, text) => match self.condition(condition)? {
+                true => Ok(Value::Empty),
+                false => Err(Error::new(ErrorKind::Assertion, format!("Assertion failed: {}", text))),
+            },
+
+            IdemRawCommandType::If(obj) => {
+                let (taken, skipped) = if self.condition(&obj.condition)? {
+                    (&obj.statements, &obj.otherwise)
+                } else {
+                    (&obj.otherwise, &obj.statements)
+                };
+                self.report_skipped(skipped)?;
+                self.run_scoped(taken, vec![])
+            }
+
+            IdemRawCommandType::Let(name, value) => {
+                let value = match self.scope.overridden(name) {
+                    Some(value) => value.clone(),
+                    None => self.evaluate(value)?,
+                };
+                self.scope.bind(name, value);
+                Ok(Value::Empty)
+            }
+
+            IdemRawCommandType::Include(include) => self.run_block(&include.statements),
+
+            IdemRawCommandType::Import(import) => {
+                let outer = self.namespace.replace(self.qualify(&import.name));
+                let result = self.run_scoped(&import.statements, vec![]);
+                self.namespace = outer;
+                result.map(|_| Value::Empty)
+            }
+        }
+    }
+
+    /// The name a command defined now is called by.
+    fn qualify(&self, name: &str) -> String {
+        match self.namespace {
+            Some(ref namespace) => format!("{}.{}", namespace, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Finds a command by the name it is called by, those of the file that defined the
+    /// command being run first.
+    fn find_command(&self, name: &str) -> Option<(String, Arc<IdemRawCommandDef>)> {
+        let qualified = self.qualify(name);
+        [qualified.as_str(), name].iter()
+            .find_map(|name| self.commands.get(*name).map(|def| (name.to_string(), def.clone())))
+    }
+
+    /// Finds a block command the way `find_command` finds a command.
+    fn find_block(&self, name: &str) -> Option<(String, Arc<IdemRawCommandDefBlock>)> {
+        let qualified = self.qualify(name);
+        [qualified.as_str(), name].iter()
+            .find_map(|name| self.blocks.get(*name).map(|def| (name.to_string(), def.clone())))
+    }
+
+    /// Applies params to paths, or calls a command defined with `def` on each path.
+    fn execute_with_paths(&mut self, obj: &IdemRawCommandWithPaths) -> ExecResult<Value> {
+        let params: Vec<&IdemParamType> = obj.params.iter()
+            .map(|param| &param.node)
+            .filter(|param| !matches!(param, IdemParamType::When(_)) && !is_control_param(param))
+            .collect();
+        let call = match params.as_slice() {
+            [IdemParamType::Call(name, args)] => Some((name, args.as_slice())),
+            [IdemParamType::FlagKeyword(name)] if self.find_command(name).is_some() => Some((name, &[][..])),
+            _ => None,
+        };
+
+        if let Some((name, args)) = call {
+            let mut values = vec![];
+            for path in &obj.paths {
+                let path = self.evaluate(&IdemValueType::PathSpec(path.clone()))?;
+                values.push(self.call(name, args, path)?);
+            }
+            return Ok(if values.len() == 1 { values.remove(0) } else { Value::List(values) });
+        }
+
+        let paths = obj.paths.iter()
+            .map(|path| self.resolve_path(path))
+            .collect::<ExecResult<Vec<_>>>()?;
+        let params = self.evaluate_params(&obj.params)?
+            .into_iter()
+            .map(|param| match param {
+                Param::KeyValue(key, value) if key == "template" => Ok(Param::KeyValue(key, self.render(&value)?)),
+                param => Ok(param),
+            })
+            .collect::<ExecResult<Vec<_>>>()?;
+        let report = apply_with_paths(self.statement_driver(&params)?, &paths, &params)?;
+        self.report(report)?;
+
+        Ok(Value::Empty)
+    }
+
+    /// Renders the template file at `template`, read where the script runs relative to
+    /// the file of the statement, like an include, with the variables in scope.
+    fn render(&self, template: &Value) -> ExecResult<Value> {
+        let name = template.to_string();
+        let path = match self.files.get(self.file) {
+            Some(file) => {
+                let dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+                dir.join(name.strip_prefix("./").unwrap_or(&name))
+            }
+            None => PathBuf::from(&name),
+        };
+        let source = fs::read_to_string(&path)
+            .map_err(|e| Error::io(&name, e).context(format!("Unable to read template {}", name)))?;
+        Ok(Value::String(render_template(&name, &source, &self.scope.visible())?))
+    }
+
+    /// Calls a command defined with `def`, with `path` bound to the path it was
+    /// called on. The result is the value of `return`, or of the last statement.
+    fn call(&mut self, name: &str, args: &[Spanned<IdemParamType>], path: Value) -> ExecResult<Value> {
+        let (qualified, def) = self.find_command(name)
+            .ok_or_else(|| Error::message(format!("Unknown command: {}", name)))?;
+        let args = self.evaluate_params(args)?;
+        let mut bound = self.bind_args(name, &def.params, args)?;
+        bound.push(("path".to_string(), path));
+
+        let namespace = qualified.rsplit_once('.').map(|(namespace, _)| namespace.to_string());
+        let outer = std::mem::replace(&mut self.namespace, namespace);
+        let result = self.run_body(&def.statements, bound);
+        self.namespace = outer;
+        result
+    }
+
+    /// Calls a block command defined with `defblock`, with its block bound to `block`.
+    fn call_block(&mut self, call: &IdemRawCommandBlockCall) -> ExecResult<Value> {
+        let (qualified, def) = self.find_block(&call.name)
+            .ok_or_else(|| Error::message(format!("Unknown block command: {}", call.name)))?;
+        let args = self.evaluate_params(&call.args)?;
+        let mut bound = self.bind_args(&call.name, &def.params, args)?;
+
+        let names = def.words.iter().filter_map(|word| match word {
+            IdemBlockWord::Param(name) => Some(name.to_string()),
+            IdemBlockWord::Tag(_) => None,
+        });
+        for (name, word) in names.zip(&call.words) {
+            bound.push((name, self.evaluate(word)?));
+        }
+        if let Some(ref block) = call.block {
+            bound.push(("block".to_string(), Value::Block(block.clone())));
+        }
+
+        let namespace = qualified.rsplit_once('.').map(|(namespace, _)| namespace.to_string());
+        let outer = std::mem::replace(&mut self.namespace, namespace);
+        let result = self.run_body(&def.statements, bound);
+        self.namespace = outer;
+        result
+    }
+
+    /// Runs the body of a command with its arguments bound in a scope of its own.
+    /// The result is the value of `return`, or of the last statement.
+    fn run_body(&mut self, statements: &[Spanned<IdemRawCommandType>], bound: Vec<(String, Value)>) -> ExecResult<Value> {
+        let value = self.run_scoped(statements, bound)?;
+        Ok(self.returned.take().unwrap_or(value))
+    }
+
+    /// Runs statements in a new scope with `bound` in it, dropping what they bind after.
+    fn run_scoped(&mut self, statements: &[Spanned<IdemRawCommandType>], bound: Vec<(String, Value)>) -> ExecResult<Value> {
+        self.scope.push();
+        for (name, value) in bound {
+            self.scope.bind(&name, value);
+        }
+
+        let result = self.run_block(statements);
+
+        self.scope.pop();
+        result
+    }
+
+    /// Runs statements in the current scope until one fails or returns.
+    fn run_block(&mut self, statements: &[Spanned<IdemRawCommandType>]) -> ExecResult<Value> {
+        let mut value = Value::Empty;
+        for statement in statements {
+            value = self.execute_statement(statement)?;
+            if self.returned.is_some() {
+                break;
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// Binds call arguments to the params of a command, falling back to defaults.
+    fn bind_args(&mut self, name: &str, params: &IdemDefParams, args: Vec<Param>) -> ExecResult<Vec<(String, Value)>> {
+        let mut bound = vec![];
+
+        match *params {
+            IdemDefParams::Positional(ref params) => {
+                let mut keywords = vec![];
+                let mut positional = vec![];
+                for arg in args {
+                    match arg {
+                        Param::KeyValue(key, value) => keywords.push((key, value)),
+                        Param::Positional(value) => positional.push(value),
+                        // A bare word passed by position is a string
+                        Param::Flag(word) => positional.push(Value::String(word)),
+                    }
+                }
+                let mut positional = positional.into_iter();
+
+                for param in params {
+                    let var = param_var(param);
+                    if param.splat {
+                        bound.push((var, Value::List(positional.by_ref().collect())));
+                        continue;
+                    }
+
+                    let value = match keywords.iter().position(|(key, _)| param.names.contains(key)) {
+                        Some(i) => keywords.remove(i).1,
+                        None => match (positional.next(), &param.default) {
+                            (Some(value), _) => value,
+                            (None, Some(default)) => self.evaluate(default)?,
+                            (None, None) => return Err(Error::message(format!("{}: missing argument {}", name, var))),
+                        },
+                    };
+                    bound.push((var, value));
+                }
+
+                if let Some((key, _)) = keywords.first() {
+                    return Err(Error::message(format!("{}: unknown argument {}", name, key)));
+                }
+                if positional.next().is_some() {
+                    return Err(Error::message(format!("{}: too many arguments", name)));
+                }
+            }
+
+            IdemDefParams::Keyword(ref params) => {
+                let splat = params.iter().find(|param| param.splat);
+                let mut rest = BTreeMap::new();
+                let mut given = HashMap::new();
+
+                for arg in args {
+                    let (key, value) = match arg {
+                        Param::KeyValue(key, value) => (key, value),
+                        Param::Flag(flag) => (flag, Value::Bool(true)),
+                        Param::Positional(value) => {
+                            return Err(Error::message(format!("{}: takes keyword arguments, got {}", name, value.literal())));
+                        }
+                    };
+
+                    match params.iter().find(|param| !param.splat && param.names.contains(&key)) {
+                        Some(param) => { given.insert(param_var(param), value); },
+                        None if splat.is_some() => { rest.insert(key.trim_start_matches('-').to_string(), value); },
+                        None => return Err(Error::message(format!("{}: unknown argument {}", name, key))),
+                    }
+                }
+
+                for param in params {
+                    let var = param_var(param);
+                    let value = match (given.remove(&var), &param.default) {
+                        _ if param.splat => Value::Map(std::mem::take(&mut rest)),
+                        (Some(value), _) => value,
+                        (None, Some(default)) => self.evaluate(default)?,
+                        (None, None) => return Err(Error::message(format!("{}: missing argument {}", name, var))),
+                    };
+                    bound.push((var, value));
+                }
+            }
+        }
+
+        Ok(bound)
+    }
+
+    /// Runs the statements once for each item of the collection, bound to `var`.
+    fn execute_each(&mut self, var: &str, extractor: Option<&IdemExtractor>, collection: &IdemValueType, statements: &[Spanned<IdemRawCommandType>]) -> ExecResult<()> {
+        let items = match extractor {
+            Some(extractor) => self.extract(extractor, collection)?,
+            None => self.evaluate(collection)?.into_items(),
+        };
+        for item in items {
+            self.run_scoped(statements, vec![(var.to_string(), item)])?;
+            if self.returned.is_some() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The items taken out of a file, or out of a string such as the output of a command.
+    fn extract(&mut self, extractor: &IdemExtractor, collection: &IdemValueType) -> ExecResult<Vec<Value>> {
+        let (text, path) = match self.evaluate(collection)? {
+            Value::Path(ref path) if !path.ends_with('/') => {
+                let text = self.driver.read_file(path)?
+                    .ok_or_else(|| Error::message(format!("Unable to extract from {}: the file does not exist", path)))?;
+                (text, Some(path.to_string()))
+            }
+            Value::String(text) => (text, None),
+            value => return Err(Error::message(format!("Unable to extract from {}, expected a file or a string", value.literal()))),
+        };
+        let source = path.as_deref().unwrap_or("the string");
+
+        let items = match extractor {
+            IdemExtractor::Regexp(regexp) => extract_lines(&text, regexp),
+            IdemExtractor::Path(pointer) => {
+                let data = match path.as_deref().and_then(Format::from_path) {
+                    Some(Format::Yaml) => parse_yaml(&text).map(|yaml| yaml_value(&yaml)),
+                    _ => parse_json(&text).map(|json| json_value(&json)),
+                };
+                data.and_then(|data| extract_path(&data, pointer))
+            }
+        };
+        items.map_err(|e| Error::message(format!("Unable to extract from {}: {}", source, e)))
+    }
+
+    /// Runs the statements with a file or directory bound to `as_`, or a directory as
+    /// the working directory. A temporary resource is removed after the statements,
+    /// whether they succeed or not.
+    fn execute_with_block(&mut self, resource: &IdemResourceType, as_: Option<&str>, statements: &[Spanned<IdemRawCommandType>]) -> ExecResult<()> {
+        let (path, temporary) = match resource {
+            IdemResourceType::Directory(dir) => (self.resolve_path(&IdemPath(None, IdemPathLocalPartType::Directory(dir.to_string())))?, false),
+            IdemResourceType::File(file) => (self.resolve_path(&IdemPath(None, IdemPathLocalPartType::File(file.to_string())))?, false),
+            IdemResourceType::Temporary(params) => (self.create_temporary(params)?, true),
+            IdemResourceType::Host(host) => {
+                return Err(Error::message(format!("with {} is not supported, use remotes to work on other hosts", host)));
+            }
+        };
+        let value = match path {
+            IdemPath(_, IdemPathLocalPartType::File(ref file)) => Value::Path(file.to_string()),
+            IdemPath(_, IdemPathLocalPartType::Directory(ref dir)) => Value::Path(format!("{}/", dir)),
+        };
+
+        let result = match (as_, &path) {
+            (Some(name), _) => self.run_scoped(statements, vec![(name.to_string(), value.clone())]),
+            (None, IdemPath(_, IdemPathLocalPartType::Directory(dir))) => self.run_in_directory(dir, statements),
+            (None, IdemPath(_, IdemPathLocalPartType::File(_))) => {
+                Err(Error::message(format!("with {} needs `as` to name the file", value.literal())))
+            }
+        };
+
+        if temporary {
+            let removed = self.driver.remove_path(&value.to_string());
+            writeln!(self.output(), "ok: {} (removed)", value)?;
+            result?;
+            removed?;
+        } else {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Runs statements in `dir`, with the drivers of `become` users too. Those first
+    /// opened in it are closed after, to be opened again where they are needed.
+    fn run_in_directory(&mut self, dir: &str, statements: &[Spanned<IdemRawCommandType>]) -> ExecResult<Value> {
+        let cwd = self.driver.get_cwd()?;
+        let mut escalated_cwds = vec![];
+        for (user, driver) in self.escalated.iter_mut() {
+            escalated_cwds.push((user.to_string(), driver.get_cwd()?));
+            driver.change_directory(dir)?;
+        }
+        self.driver.change_directory(dir)?;
+
+        let result = self.run_scoped(statements, vec![]);
+
+        self.escalated.retain(|user, _| escalated_cwds.iter().any(|(opened, _)| opened == user));
+        let mut restored = self.driver.change_directory(&cwd);
+        for (user, cwd) in &escalated_cwds {
+            restored = restored.and(self.escalated.get_mut(user).unwrap().change_directory(cwd));
+        }
+        restored.and(result)
+    }
+
+    /// Creates a temporary file, or a directory with `dir`, on the current host.
+    fn create_temporary(&mut self, params: &[Spanned<IdemParamType>]) -> ExecResult<IdemPath> {
+        let mut directory = false;
+        let mut suffix = String::new();
+        for param in self.evaluate_params(params)? {
+            match param {
+                Param::Flag(ref flag) if flag == "dir" => directory = true,
+                Param::Flag(ref flag) if flag == "file" => directory = false,
+                Param::KeyValue(ref key, ref value) if key == "suffix" => suffix = value.to_string(),
+                param => return Err(Error::message(format!("Unknown param for temporary: {:?}", param))),
+            }
+        }
+
+        let path = self.driver.create_temporary(directory, &suffix)?;
+        writeln!(self.output(), "ok: {}{} (temporary)", path, if directory { "/" } else { "" })?;
+        Ok(IdemPath(None, if directory { IdemPathLocalPartType::Directory(path) } else { IdemPathLocalPartType::File(path) }))
+    }
+
+    /// Runs the body of a `remotes` block on each host, each with its own driver.
+    fn execute_remotes(&mut self, block: &IdemRawCommandRemotes) -> ExecResult<()> {
+        let connector = self.remote.connector.clone()
+            .ok_or_else(|| Error::message("remotes requires a remote connection, none is configured"))?;
+        let hosts = resolve_hosts(&block.hosts, &self.remote.inventory)?;
+        let params = self.evaluate_params(&block.params)?;
+        let opts = RolloutOptions::from_params(self.remote.forks, &params)?;
+        let remote = self.remote.clone();
+        let commands = self.commands.clone();
+        let namespace = self.namespace.clone();
+        let blocks = self.blocks.clone();
+        let scope = self.scope.clone();
+        let handlers = self.handlers.clone();
+        let force_handlers = self.force_handlers;
+        let files = self.files.clone();
+        let file = self.file;
+
+        let results = run_rollout(&hosts, &opts, self.output(), |host, buf| {
+            let mut driver = connector(host)?;
+            if let Some(ref target) = block.become_user {
+                driver = driver.escalate(&remote.escalation.escalation(host, target)?)?;
+            }
+
+            let mut handle_exec = HandleExec::new(&mut driver)
+                .with_output(buf)
+                .with_remote_config(remote.clone())
+                .with_host(host)
+                .with_files(files.clone());
+            handle_exec.file = file;
+            handle_exec.commands = commands.clone();
+            handle_exec.namespace = namespace.clone();
+            handle_exec.blocks = blocks.clone();
+            handle_exec.scope = scope.clone();
+            handle_exec.handlers = handlers.clone();
+            handle_exec.force_handlers = force_handlers;
+            if let Some(ref var) = block.var {
+                handle_exec = handle_exec.with_var(var, Value::Host(host.to_string()));
+            }
+
+            // Handlers notified on a host run there once its statements are done
+            let result = block.statements.iter().try_for_each(|statement| handle_exec.execute_raw_script_command(statement));
+            handle_exec.run_handlers(result)
+        })?;
+
+        summarize(&results)
+    }
+
+    /// Runs an external command on the target, returning its result as a value. Output
+    /// is written through unless the command captures it.
+    pub fn execute_system(&mut self, system: &IdemRawCommandSystem) -> ExecResult<Value> {
+        let mut words = split_words(&self.interpolate(&system.command)?)?.into_iter();
+        let mut process = Process::new(&words.next()
+            .ok_or_else(|| Error::message("system requires a command"))?);
+        process.args = words.collect();
+
+        let params = self.evaluate_params(&system.params)?;
+        for param in &params {
+            match param {
+                Param::KeyValue(ref key, ref value) if key == "cwd" => process.cwd = Some(value.to_string()),
+                Param::KeyValue(ref key, ref value) if key == "stdin" => process.stdin = Some(value.to_string()),
+                Param::KeyValue(ref key, ref value) if key == "env" => {
+                    let env = value.to_string();
+                    let mut parts = env.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(name), Some(value)) if !name.is_empty() => process.env.push((name.to_string(), value.to_string())),
+                        _ => return Err(Error::message(format!("env expects NAME=value, got {:?}", env))),
+                    }
+                },
+                Param::KeyValue(ref key, Value::Integer(seconds)) if key == "timeout" && *seconds > 0 => {
+                    process.timeout = Some(Duration::from_secs(*seconds as u64));
+                },
+                Param::KeyValue(ref key, _) if key == "become" => {},
+                _ => return Err(Error::message(format!("Unknown param for system: {:?}", param))),
+            }
+        }
+
+        let result = self.statement_driver(&params)?.execute(&process)?;
+        let value = Value::from_output(&result, system.capture);
+
+        let output = self.output();
+        if !system.capture || !value.is_success() {
+            output.write_all(result.stdout.as_bytes())?;
+        }
+        output.write_all(result.stderr.as_bytes())?;
+
+        Ok(value)
+    }
+
+    /// Merges the template or applies the line edit to the file, writing it only when it changes.
+    fn execute_edit(&mut self, edit: &IdemRawCommandEdit) -> ExecResult<()> {
+        let path = match self.resolve_path(&edit.path)? {
+            IdemPath(_, IdemPathLocalPartType::File(path)) => path,
+            IdemPath(_, IdemPathLocalPartType::Directory(dir)) => {
+                return Err(Error::message(format!("{}/ is a directory and can not be edited", dir)));
+            }
+        };
+        let params = self.evaluate_params(&edit.params)?;
+        let mut matching = Matching::Trimmed;
+        let mut format = None;
+        let mut document = 0;
+        for param in &params {
+            match param {
+                Param::Flag(ref flag) if flag == "edit" => {},
+                Param::KeyValue(ref key, _) if key == "edit" => {},
+                Param::Flag(ref flag) if flag == "ignore_whitespace" => matching = Matching::IgnoreWhitespace,
+                Param::KeyValue(ref key, ref value) if key == "format" => {
+                    format = Some(Format::from_name(&value.to_string())
+                        .ok_or_else(|| Error::message(format!("Unknown format for edit: {}, expected ini, toml, json or yaml", value)))?);
+                },
+                Param::KeyValue(ref key, ref value) if key == "document" => {
+                    document = value.to_string().parse()
+                        .map_err(|_| Error::message(format!("Invalid document for edit: {}, expected a number", value)))?;
+                },
+                Param::KeyValue(ref key, _) if key == "become" => {},
+                _ => return Err(Error::message(format!("Unknown param for edit: {:?}", param))),
+            }
+        }
+
+        let body = match edit.body {
+            IdemEditBody::Blocks(ref template) => IdemEditBody::Blocks(self.interpolate(template)?),
+            IdemEditBody::Lines(ref lines) => IdemEditBody::Lines(self.interpolate_edit(lines)?),
+            IdemEditBody::Json(ref edits) => {
+                let mut interpolated = vec![];
+                for edit in edits {
+                    interpolated.push(match edit {
+                        IdemJsonEdit::Merge(pointer, value) => IdemJsonEdit::Merge(self.interpolate(pointer)?, self.interpolate(value)?),
+                        IdemJsonEdit::Set(pointer, value) => IdemJsonEdit::Set(self.interpolate(pointer)?, self.interpolate(value)?),
+                        IdemJsonEdit::Remove(pointer) => IdemJsonEdit::Remove(self.interpolate(pointer)?),
+                    });
+                }
+                IdemEditBody::Json(interpolated)
+            }
+        };
+
+        let driver = self.statement_driver(&params)?;
+        let current = driver.read_file(&path)?.unwrap_or_default();
+        let edited = match body {
+            IdemEditBody::Blocks(template) => match format.or_else(|| Format::from_path(&path)) {
+                Some(Format::Ini) => merge_keys(&current, &template, Syntax::Ini),
+                Some(Format::Toml) => merge_keys(&current, &template, Syntax::Toml),
+                _ if format.is_some() => Err(Error::message("format = json and format = yaml only apply to edits at paths")),
+                _ => merge_blocks(&current, &template),
+            },
+            IdemEditBody::Lines(_) if format.is_some() => Err(Error::message("format does not apply to line edits")),
+            IdemEditBody::Lines(lines) => apply_edit(&current, &lines, matching),
+            IdemEditBody::Json(edits) => match format.or_else(|| Format::from_path(&path)) {
+                Some(Format::Yaml) => apply_yaml_edits(&current, &edits, document),
+                Some(Format::Ini) | Some(Format::Toml) if format.is_some() => Err(Error::message("format = ini and format = toml only apply to a template of sections and keys")),
+                _ => apply_json_edits(&current, &edits),
+            },
+        };
+        let edited = edited.map_err(|e| Error::message(format!("Unable to edit {}: {}", path, e)))?;
+
+        let changed = edited != current && driver.ensure_file_contents(&path, FileContents::StaticString(edited))?;
+        self.report(vec![Outcome::new(changed, format!("{} (edit)", path))])
+    }
+
+    fn interpolate_edit(&mut self, edit: &IdemEdit) -> ExecResult<IdemEdit> {
+        let mut commands = vec![];
+        for command in &edit.commands {
+            commands.push(match command {
+                IdemEditCommandType::InsertStart(line) => IdemEditCommandType::InsertStart(self.interpolate(line)?),
+                IdemEditCommandType::InsertEnd(line) => IdemEditCommandType::InsertEnd(self.interpolate(line)?),
+                IdemEditCommandType::InsertAfter(anchor, line) => IdemEditCommandType::InsertAfter(self.interpolate(anchor)?, self.interpolate(line)?),
+                IdemEditCommandType::InsertBefore(anchor, line) => IdemEditCommandType::InsertBefore(self.interpolate(anchor)?, self.interpolate(line)?),
+                IdemEditCommandType::Remove(line) => IdemEditCommandType::Remove(self.interpolate(line)?),
+                IdemEditCommandType::Context(line) => IdemEditCommandType::Context(self.interpolate(line)?),
+                IdemEditCommandType::Replace(selector, replace) => IdemEditCommandType::Replace(
+                    match selector {
+                        Some(IdemLineSelector::Line(line)) => Some(IdemLineSelector::Line(self.interpolate(line)?)),
+                        selector => selector.clone(),
+                    },
+                    IdemReplace { replacement: self.interpolate(&replace.replacement)?, ..replace.clone() },
+                ),
+                IdemEditCommandType::LineInFile(line_in_file) => IdemEditCommandType::LineInFile(IdemLineInFile {
+                    line: self.interpolate(&line_in_file.line)?,
+                    ..line_in_file.clone()
+                }),
+            });
+        }
+        Ok(IdemEdit { commands })
+    }
+
+    /// Runs the body of a `using` block through the interpreter on the target, unless
+    /// its `creates` path exists or its `unless` command succeeds. Its output is the
+    /// value, or the failure when it exits with a status.
+    fn execute_using(&mut self, using: &IdemRawCommandUsing) -> ExecResult<Value> {
+        let mut creates = None;
+        let mut unless = None;
+        let params = self.evaluate_params(&using.params)?;
+        for param in &params {
+            match param {
+                Param::KeyValue(ref key, ref value) if key == "creates" => creates = Some(value.to_string()),
+                Param::KeyValue(ref key, ref value) if key == "unless" => unless = Some(value.to_string()),
+                Param::KeyValue(ref key, _) if key == "become" => {},
+                _ => return Err(Error::message(format!("Unknown param for using: {:?}", param))),
+            }
+        }
+        let script = self.interpolate(&using.script)?;
+
+        let driver = self.statement_driver(&params)?;
+        let skipped = match (creates, unless) {
+            (Some(ref path), _) if driver.path_exists(path)? => Some(format!("{} exists", path)),
+            // The guard is a shell command, whatever the interpreter of the block
+            (_, Some(ref cmd)) if driver.run_script("sh", cmd)?.status == 0 => Some(format!("{} succeeded", cmd.trim())),
+            _ => None,
+        };
+
+        if let Some(reason) = skipped {
+            writeln!(self.output(), "skipped: using {} ({})", using.interpreter, reason)?;
+            return Ok(Value::Empty);
+        }
+
+        let result = driver.run_script(&using.interpreter, &script)?;
+        let output = self.output();
+        output.write_all(result.stdout.as_bytes())?;
+        output.write_all(result.stderr.as_bytes())?;
+
+        let value = Value::from_output(&result, true);
+        if value.is_success() {
+            writeln!(output, "ok: using {}", using.interpreter)?;
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use super::super::parser::*;
+    use super::super::escalate::Escalation;
+    use super::super::loader::Loader;
+
+    #[derive(Debug, Clone)]
+    pub struct TestExec {
+        /// The directory the driver started in, paths within it are absolute.
+        root: String,
+        cwd: String,
+        pub created_dirs: Vec<String>,
+        pub created_files: Vec<String>,
+        /// The last contents written to each file.
+        pub contents: HashMap<String, String>,
+        /// Drivers handed out by `escalate`, rooted at `<user>@<cwd>`.
+        pub escalated: Vec<Arc<Mutex<TestExec>>>,
+        /// Scripts run, with the exit status they all return.
+        pub scripts: Vec<(String, String)>,
+        /// Processes executed, which return the same exit status as scripts.
+        pub processes: Vec<Process>,
+        pub script_status: i32,
+        /// Paths given to `remove_path`.
+        pub removed: Vec<String>,
+    }
+
+    impl TestExec {
+        pub fn new(cwd: &str) -> Self {
+            TestExec {
+                root: cwd.to_string(),
+                cwd: cwd.to_string(),
+                created_dirs: vec![],
+                created_files: vec![],
+                contents: HashMap::new(),
+                escalated: vec![],
+                scripts: vec![],
+                processes: vec![],
+                script_status: 0,
+                removed: vec![],
+            }
+        }
+    }
+
+    #[inline]
+    fn join_paths(a: &str, b: &str) -> String {
+        if b.starts_with('/') {
+            return b.to_string();
+        }
+        let a = a.trim_end_matches("/").replace("./", "");
+        let b = b.trim_start_matches("/").replace("./", "");
+        format!("{}/{}", a, b)
+    }
+
+    impl Exec for TestExec {
+        fn change_directory(&mut self, dir: &str) -> ExecResult<()> {
+            let absolute = dir.starts_with('/') || dir.starts_with(&self.root);
+            self.cwd = if absolute { dir.trim_end_matches('/').to_string() } else { join_paths(&self.cwd, dir) };
+            Ok(())
+        }
+
+        fn ensure_directory(&mut self, local_part: &str) -> ExecResult<bool> {
+            let dir = join_paths(&self.cwd, local_part);
+            let changed = !self.created_dirs.contains(&dir);
+            self.created_dirs.push(dir);
+            Ok(changed)
+        }
+
+        fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+            let filepath = join_paths(&self.cwd, local_part);
+            let changed = !self.created_files.contains(&filepath) && !self.contents.contains_key(&filepath);
+            self.created_files.push(filepath);
+            Ok(changed)
+        }
+
+        fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<bool> {
+            let filepath = join_paths(&self.cwd, local_part);
+            let FileContents::StaticString(contents) = contents;
+            let changed = self.contents.get(&filepath) != Some(&contents);
+            self.contents.insert(filepath.clone(), contents);
+            self.created_files.push(filepath);
+            Ok(changed)
+        }
+
+        fn get_cwd(&mut self) -> ExecResult<String> {
+            Ok(self.cwd.to_string())
+        }
+
+        fn path_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+            let path = join_paths(&self.cwd, local_part.trim_end_matches('/'));
+            Ok(self.created_files.contains(&path) || self.created_dirs.contains(&path))
+        }
+
+        fn read_file(&mut self, local_part: &str) -> ExecResult<Option<String>> {
+            Ok(self.contents.get(&join_paths(&self.cwd, local_part)).cloned())
+        }
+
+        fn create_temporary(&mut self, directory: bool, suffix: &str) -> ExecResult<String> {
+            let path = format!("/tmp/idemsh-test{}", suffix);
+            if directory {
+                self.created_dirs.push(path.clone());
+            } else {
+                self.created_files.push(path.clone());
+            }
+            Ok(path)
+        }
+
+        fn remove_path(&mut self, local_part: &str) -> ExecResult<()> {
+            self.removed.push(local_part.to_string());
+            Ok(())
+        }
+
+        fn execute(&mut self, process: &Process) -> ExecResult<CommandOutput> {
+            self.processes.push(process.clone());
+            Ok(CommandOutput {
+                status: self.script_status,
+                stdout: format!("ran {}\n", process.program),
+                ..CommandOutput::default()
+            })
+        }
+
+        fn run_script(&mut self, interpreter: &str, script: &str) -> ExecResult<CommandOutput> {
+            self.scripts.push((interpreter.to_string(), script.to_string()));
+            Ok(CommandOutput {
+                status: self.script_status,
+                stdout: format!("ran {}\n", interpreter),
+                ..CommandOutput::default()
+            })
+        }
+
+        fn escalate(&mut self, escalation: &Escalation) -> ExecResult<Box<dyn Exec + Send>> {
+            let escalated = Arc::new(Mutex::new(TestExec::new(&format!("{}@{}", escalation.user, self.cwd))));
+            self.escalated.push(escalated.clone());
+            Ok(Box::new(escalated))
+        }
+    }
+
+    macro_rules!  parse (
+        ($code: expr) => ({
+            let expr = parse_raw_script(CompleteStr($code)).unwrap().1;
+            expr
+        });
+    );
+
+    #[test]
+    fn test_file_exists() {
+        let script = parse!(r#"
+./afile (exists)
+"#);
+
+        // Verify script
+        assert_eq!(script, vec![
+            IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                paths: vec![
+                    IdemPath(None, IdemPathLocalPartType::File("./afile".to_string())).into(),
+                ],
+                params: vec![
+                    IdemParamType::FlagKeyword("exists".to_string()).into(),
+                ],
+            }).into()
+        ]);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut handle_exec = HandleExec::new(&mut test_exec);
+        handle_exec.execute_raw_script_command(&script[0]).unwrap();
+
+        // Assert result
+        assert_eq!(test_exec.created_files, vec!["testing/afile"]);
+    }
+
+    #[test]
+    fn test_directory_exists() {
+        let script = parse!(r#"
+./adir/ (exists)
+"#);
+
+        // Verify script
+        assert_eq!(script, vec![
+            IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                paths: vec![
+                    IdemPath(None, IdemPathLocalPartType::Directory("./adir".to_string())).into(),
+                ],
+                params: vec![
+                    IdemParamType::FlagKeyword("exists".to_string()).into(),
+                ],
+            }).into()
+        ]);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut handle_exec = HandleExec::new(&mut test_exec);
+        handle_exec.execute_raw_script_command(&script[0]).unwrap();
+
+        // Assert result
+        assert_eq!(test_exec.created_dirs, vec!["testing/adir"]);
+    }
+
+    impl Exec for Arc<Mutex<TestExec>> {
+        fn change_directory(&mut self, dir: &str) -> ExecResult<()> {
+            self.lock().unwrap().change_directory(dir)
+        }
+
+        fn ensure_directory(&mut self, local_part: &str) -> ExecResult<bool> {
+            self.lock().unwrap().ensure_directory(local_part)
+        }
+
+        fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+            self.lock().unwrap().ensure_file_exists(local_part)
+        }
+
+        fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<bool> {
+            self.lock().unwrap().ensure_file_contents(local_part, contents)
+        }
+
+        fn get_cwd(&mut self) -> ExecResult<String> {
+            self.lock().unwrap().get_cwd()
+        }
+
+        fn path_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+            self.lock().unwrap().path_exists(local_part)
+        }
+
+        fn read_file(&mut self, local_part: &str) -> ExecResult<Option<String>> {
+            self.lock().unwrap().read_file(local_part)
+        }
+
+        fn create_temporary(&mut self, directory: bool, suffix: &str) -> ExecResult<String> {
+            self.lock().unwrap().create_temporary(directory, suffix)
+        }
+
+        fn remove_path(&mut self, local_part: &str) -> ExecResult<()> {
+            self.lock().unwrap().remove_path(local_part)
+        }
+
+        fn execute(&mut self, process: &Process) -> ExecResult<CommandOutput> {
+            self.lock().unwrap().execute(process)
+        }
+
+        fn run_script(&mut self, interpreter: &str, script: &str) -> ExecResult<CommandOutput> {
+            self.lock().unwrap().run_script(interpreter, script)
+        }
+
+        fn escalate(&mut self, escalation: &Escalation) -> ExecResult<Box<dyn Exec + Send>> {
+            self.lock().unwrap().escalate(escalation)
+        }
+    }
+
+    #[test]
+    fn test_remotes() {
+        let script = parse!(r#"
+remotes host in web-* (serial="50%", max_fail=0)
+    ./afile (exists)
+end
+"#);
+
+        // Verify script
+        assert_eq!(script, vec![
+            IdemRawCommandType::Remotes(IdemRawCommandRemotes {
+                var: Some("host".to_string()),
+                hosts: "web-*".to_string(),
+                become_user: None,
+                params: vec![
+                    IdemParamType::KeyValue("serial".to_string(), IdemValueType::LitString("50%".to_string()).into()).into(),
+                    IdemParamType::KeyValue("max_fail".to_string(), IdemValueType::Integer(0).into()).into(),
+                ],
+                statements: vec![
+                    IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                        paths: vec![
+                            IdemPath(None, IdemPathLocalPartType::File("./afile".to_string())).into(),
+                        ],
+                        params: vec![
+                            IdemParamType::FlagKeyword("exists".to_string()).into(),
+                        ],
+                    }).into(),
+                ],
+            }).into()
+        ]);
+
+        // Every host gets a TestExec rooted at the host name
+        let hosts: Arc<Mutex<Vec<Arc<Mutex<TestExec>>>>> = Arc::new(Mutex::new(vec![]));
+        let connected = hosts.clone();
+        let remote = RemoteConfig {
+            connector: Some(Arc::new(move |host: &str| {
+                let exec = Arc::new(Mutex::new(TestExec::new(host)));
+                connected.lock().unwrap().push(exec.clone());
+                Ok(Box::new(exec) as Box<dyn Exec + Send>)
+            })),
+            inventory: vec!["web-1".to_string(), "db-1".to_string(), "web-2".to_string()],
+            ..RemoteConfig::default()
+        };
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_remote_config(remote);
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result
+        let mut created: Vec<String> = hosts.lock().unwrap().iter()
+            .flat_map(|h| h.lock().unwrap().created_files.clone())
+            .collect();
+        created.sort();
+        assert_eq!(created, vec!["web-1/afile", "web-2/afile"]);
+        assert!(test_exec.created_files.is_empty());
+        assert_eq!(String::from_utf8(output).unwrap(), "== web-1 ==\nchanged: ./afile (exists)\n== web-2 ==\nchanged: ./afile (exists)\n");
+    }
+
+    #[test]
+    fn test_become() {
+        let script = parse!(r#"
+./etc/motd (content="hello", become=root)
+./etc/issue (content="hello", become=root)
+./home/app/ (exists, become="app:www")
+./tmp/ (exists)
+"#);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = io::sink();
+        let mut handle_exec = HandleExec::new(&mut test_exec).with_output(&mut output);
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, one escalated driver per user
+        assert_eq!(test_exec.escalated.len(), 2);
+        assert_eq!(test_exec.escalated[0].lock().unwrap().created_files, vec!["root@testing/etc/motd", "root@testing/etc/issue"]);
+        assert_eq!(test_exec.escalated[1].lock().unwrap().created_dirs, vec!["app@testing/home/app"]);
+        assert_eq!(test_exec.created_dirs, vec!["testing/tmp"]);
+
+        // `with` a directory changes the directory of escalated drivers too, and back
+        let script = parse!(r#"
+./etc/motd (content="hello", become=root)
+with ./sub/
+    ./y (content="y", become=root)
+    ./z (content="z", become=app)
+end
+./x (content="x", become=root)
+./w (content="w", become=app)
+"#);
+        let mut test_exec = TestExec::new("./testing");
+        HandleExec::new(&mut test_exec).with_output(&mut io::sink()).execute_raw_script(&script).unwrap();
+
+        let files = |i: usize| {
+            let mut files: Vec<String> = test_exec.escalated[i].lock().unwrap().contents.keys().cloned().collect();
+            files.sort();
+            files
+        };
+        assert_eq!(test_exec.escalated.len(), 3);
+        assert_eq!(files(0), vec!["root@testing/etc/motd", "root@testing/sub/y", "root@testing/x"]);
+        assert_eq!(files(1), vec!["app@testing/sub/z"]);
+        assert_eq!(files(2), vec!["app@testing/w"]);
+    }
+
+    #[test]
+    fn test_remotes_as_user() {
+        let script = parse!(r#"
+remotes db-1 as postgres:postgres
+    ./data/ (exists)
+end
+"#);
+
+        let hosts: Arc<Mutex<Vec<Arc<Mutex<TestExec>>>>> = Arc::new(Mutex::new(vec![]));
+        let connected = hosts.clone();
+        let remote = RemoteConfig {
+            connector: Some(Arc::new(move |host: &str| {
+                let exec = Arc::new(Mutex::new(TestExec::new(host)));
+                connected.lock().unwrap().push(exec.clone());
+                Ok(Box::new(exec) as Box<dyn Exec + Send>)
+            })),
+            ..RemoteConfig::default()
+        };
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = io::sink();
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_remote_config(remote);
+        handle_exec.execute_raw_script(&script).unwrap();
+
+        // Assert result
+        let hosts = hosts.lock().unwrap();
+        let host = hosts[0].lock().unwrap();
+        assert!(host.created_dirs.is_empty());
+        assert_eq!(host.escalated[0].lock().unwrap().created_dirs, vec!["postgres@db-1/data"]);
+    }
+
+    #[test]
+    fn test_using() {
+        let script = parse!(r#"
+./build/ (exists)
+using bash (creates="./build/")
+    make
+end
+using python3 (unless="test -f ./done")
+    for i in range(2):
+        print("{{ name }}")
+end
+"#);
+
+        // Verify script
+        assert_eq!(script[2], IdemRawCommandType::Using(IdemRawCommandUsing {
+            interpreter: "python3".to_string(),
+            params: vec![
+                IdemParamType::KeyValue("unless".to_string(), IdemValueType::LitString("test -f ./done".to_string()).into()).into(),
+            ],
+            script: "for i in range(2):\n    print(\"{{ name }}\")\n".to_string(),
+        }).into());
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.script_status = 1;
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("name", "idemsh");
+        handle_exec.execute_raw_script(&script[..2]).unwrap();
+        assert!(handle_exec.execute_raw_script_command(&script[2]).is_err());
+        drop(handle_exec);
+
+        // Assert result, the unless command ran with sh and failed, so the script ran and
+        // failed too
+        assert_eq!(test_exec.scripts, vec![
+            ("sh".to_string(), "test -f ./done".to_string()),
+            ("python3".to_string(), "for i in range(2):\n    print(\"idemsh\")\n".to_string()),
+        ]);
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./build/ (exists)\nskipped: using bash (./build/ exists)\nran python3\n");
+
+        // Lines with only `end` in the body are kept, and the output is the value
+        let script = parse!(r#"
+let out = ((
+using ruby
+    [1, 2].each do |i|
+      puts i
+    end
+end
+))
+./out (content = $out)
+"#);
+        let mut test_exec = TestExec::new("./testing");
+        HandleExec::new(&mut test_exec).with_output(&mut io::sink()).execute_raw_script(&script).unwrap();
+        assert_eq!(test_exec.scripts, vec![
+            ("ruby".to_string(), "[1, 2].each do |i|\n  puts i\nend\n".to_string()),
+        ]);
+        assert_eq!(test_exec.contents["testing/out"], "ran ruby\n");
+    }
+
+    #[test]
+    fn test_remotes_using() {
+        let script = parse!(r#"
+remotes host in web-1,web-2 using sh
+    echo "Remote {{ host }}"
+end
+"#);
+
+        let hosts: Arc<Mutex<Vec<Arc<Mutex<TestExec>>>>> = Arc::new(Mutex::new(vec![]));
+        let connected = hosts.clone();
+        let remote = RemoteConfig {
+            connector: Some(Arc::new(move |host: &str| {
+                let exec = Arc::new(Mutex::new(TestExec::new(host)));
+                connected.lock().unwrap().push(exec.clone());
+                Ok(Box::new(exec) as Box<dyn Exec + Send>)
+            })),
+            forks: 1,
+            ..RemoteConfig::default()
+        };
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_remote_config(remote);
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result
+        let scripts: Vec<String> = hosts.lock().unwrap().iter()
+            .flat_map(|h| h.lock().unwrap().scripts.clone())
+            .map(|(_, script)| script)
+            .collect();
+        assert_eq!(scripts, vec!["echo \"Remote web-1\"\n", "echo \"Remote web-2\"\n"]);
+        assert_eq!(String::from_utf8(output).unwrap(), "== web-1 ==\nran sh\nok: using sh\n== web-2 ==\nran sh\nok: using sh\n");
+    }
+
+    #[test]
+    fn test_system() {
+        let script = parse!(r#"
+system (make -C "{{ dir }}" all) (cwd="./src", timeout=60, env="CC=clang")
+$((test -f ./done)) (become=root)
+"#);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("dir", "my build");
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, captured output is not written
+        assert_eq!(test_exec.processes, vec![Process {
+            args: vec!["-C".to_string(), "my build".to_string(), "all".to_string()],
+            env: vec![("CC".to_string(), "clang".to_string())],
+            cwd: Some("./src".to_string()),
+            timeout: Some(Duration::from_secs(60)),
+            ..Process::new("make")
+        }]);
+        assert_eq!(test_exec.escalated[0].lock().unwrap().processes, vec![Process {
+            args: vec!["-f".to_string(), "./done".to_string()],
+            ..Process::new("test")
+        }]);
+        assert_eq!(String::from_utf8(output).unwrap(), "ok: system (make -C \"{{ dir }}\" all)\nran test\nok: system (test -f ./done)\n");
+
+        // A non-zero exit status is a failure value
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.script_status = 2;
+        let mut output = io::sink();
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("dir", "my build");
+        let system = match script[0].node {
+            IdemRawCommandType::System(ref system) => system,
+            _ => unreachable!(),
+        };
+        assert_eq!(handle_exec.execute_system(system).unwrap(), Value::Failure(Failure::ResultCode(2)));
+        assert!(handle_exec.execute_raw_script_command(&script[0]).is_err());
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words(r#"echo "a b" 'c "d"' e\ f"#).unwrap(), vec!["echo", "a b", "c \"d\"", "e f"]);
+        assert_eq!(split_words(r#"printf '' """#).unwrap(), vec!["printf", "", ""]);
+        assert!(split_words("echo 'a").is_err());
+    }
+
+    #[test]
+    fn test_expressions() {
+        let script = parse!(r#"
+each site in [{ name = "blog", port = 8080 }, { name = "shop", port = (( 8081 )) }]
+    ./sites/ (exists)
+    ./site.conf (content="{{ site.name }}:{{ site.port }}")
+end
+./version (content=(( system (git describe) )))
+./empty (content=(()))
+def wc
+    system (wc -l)
+end
+./lines (content=(( ./file (wc) )))
+"#);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec).with_output(&mut output);
+        handle_exec.execute_raw_script(&script).unwrap();
+
+        // The loop variable is gone after the loop
+        let undefined = parse!(r#"./x (content="{{ site }}")"#);
+        assert!(handle_exec.execute_raw_script(&undefined).is_err());
+
+        // A command on a path needs a definition
+        let undefined = parse!("./x (content=(( ./file (missing) )))");
+        assert!(handle_exec.execute_raw_script(&undefined).is_err());
+        drop(handle_exec);
+
+        // Assert result
+        assert_eq!(test_exec.processes, vec![Process {
+            args: vec!["describe".to_string()],
+            ..Process::new("git")
+        }, Process {
+            args: vec!["-l".to_string()],
+            ..Process::new("wc")
+        }]);
+        assert_eq!(test_exec.contents["testing/site.conf"], "shop:8081");
+        assert_eq!(test_exec.contents["testing/version"], "ran git\n");
+        assert_eq!(test_exec.contents["testing/empty"], "");
+        assert_eq!(test_exec.contents["testing/lines"], "ran wc\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+changed: ./sites/ (exists)
+changed: ./site.conf (content)
+ok: ./sites/ (exists)
+changed: ./site.conf (content)
+changed: ./version (content)
+changed: ./empty (content)
+ok: system (wc -l)
+changed: ./lines (content)
+");
+    }
+
+    #[test]
+    fn test_each_extract() {
+        let script = parse!(r#"
+each @/parent/child[*] as c in ./test.json
+    system (echo "child {{ c.name }}")
+end
+each @~/^name=(.*)/ as c in ./test.txt
+    system (echo "name {{ c[1] }}")
+end
+each @/hosts/*/port as port in ./hosts.yml
+    system (echo {{ port }})
+end
+"#);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/test.json".to_string(), r#"{ "parent": { "child": [{ "name": "a" }, { "name": "b" }] } }"#.to_string());
+        test_exec.contents.insert("testing/test.txt".to_string(), "name=web\n# name=old\n".to_string());
+        test_exec.contents.insert("testing/hosts.yml".to_string(), "hosts:\n  web:\n    port: 80 # http\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec).with_output(&mut output);
+        handle_exec.execute_raw_script(&script).unwrap();
+
+        // A missing file fails the loop
+        let missing = parse!("each @/a as a in ./missing.json\nend\n");
+        let err = handle_exec.execute_raw_script(&missing).unwrap_err();
+        assert_eq!(err.to_string(), "Unable to extract from ./missing.json: the file does not exist");
+        drop(handle_exec);
+
+        // Assert result
+        let args: Vec<String> = test_exec.processes.iter().map(|p| p.args.join(" ")).collect();
+        assert_eq!(args, vec!["child a", "child b", "name web", "80"]);
+    }
+
+    #[test]
+    fn test_with_temporary() {
+        let script = parse!(r#"
+with ~~build/
+    ./test1 (content = "built")
+end
+with (( temporary(suffix = ".conf") )) as conf
+    $conf (content = "conf")
+end
+"#);
+
+        // Execute script, from an absolute directory like local drivers have
+        let mut test_exec = TestExec::new("/testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec).with_output(&mut output);
+        handle_exec.execute_raw_script(&script).unwrap();
+
+        // A failing block still removes its temporary directory
+        let failing = parse!("with ~~failing/\n    system (false)\nend\n");
+        handle_exec.driver.script_status = 1;
+        assert!(handle_exec.execute_raw_script(&failing).is_err());
+        drop(handle_exec);
+
+        // Assert result, the working directory is restored after the block
+        assert_eq!(test_exec.contents["/tmp/idemsh-test-build/test1"], "built");
+        assert_eq!(test_exec.contents["/tmp/idemsh-test.conf"], "conf");
+        assert_eq!(test_exec.get_cwd().unwrap(), "/testing");
+        assert_eq!(test_exec.removed, vec!["/tmp/idemsh-test-build/", "/tmp/idemsh-test.conf", "/tmp/idemsh-test-failing/"]);
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+ok: /tmp/idemsh-test-build/ (temporary)
+changed: ./test1 (content)
+ok: /tmp/idemsh-test-build/ (removed)
+ok: /tmp/idemsh-test.conf (temporary)
+changed: /tmp/idemsh-test.conf (content)
+ok: /tmp/idemsh-test.conf (removed)
+ok: /tmp/idemsh-test-failing/ (temporary)
+ran false
+ok: /tmp/idemsh-test-failing/ (removed)
+");
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.script_status = 1;
+        let mut output = io::sink();
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("ports", Value::List(vec![Value::Integer(80), Value::Integer(443)]))
+            .with_var("dir", Value::Path("./etc/".to_string()));
+
+        let value = |s| parse_value(CompleteStr(s)).unwrap().1;
+        assert_eq!(handle_exec.evaluate(&value("$ports[1]")).unwrap(), Value::Integer(443));
+        assert_eq!(handle_exec.evaluate(&value(r#""{{ ports }}""#)).unwrap(), Value::from("[80, 443]"));
+        assert_eq!(handle_exec.evaluate(&value("(( $((false)) ))")).unwrap(), Value::Failure(Failure::ResultCode(1)));
+        assert_eq!(handle_exec.evaluate(&value("./dir/")).unwrap(), Value::Path("./dir/".to_string()));
+        assert_eq!(handle_exec.evaluate(&value("$dir/motd")).unwrap(), Value::Path("./etc/motd".to_string()));
+        assert!(handle_exec.evaluate(&value("$ports.name")).is_err());
+    }
+
+    #[test]
+    fn test_if() {
+        let script = parse!(r#"
+./etc/ (exists)
+if $os == "debian" and ./etc/ exists
+    ./debian (content = "yes")
+else if $os == arch
+    ./arch (content = "yes")
+else
+    ./other (content = "yes")
+end
+unless $count > 2
+    ./few (content = "{{ count }}")
+end
+./motd (content = "hi", when = ./missing exists)
+./issue (content = "hi", unless = $os != "debian")
+./status (content = "ok", when = (( $((true)) )) and not $count)
+"#);
+
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("os", "debian")
+            .with_var("count", Value::Integer(10));
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result
+        assert_eq!(test_exec.contents["testing/debian"], "yes");
+        assert_eq!(test_exec.contents["testing/issue"], "hi");
+        for path in &["testing/arch", "testing/other", "testing/few", "testing/motd", "testing/status"] {
+            assert!(!test_exec.contents.contains_key(*path), "{}", path);
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+changed: ./etc/ (exists)
+skipped: ./arch
+skipped: ./other
+changed: ./debian (content)
+skipped: ./few
+skipped: ./motd
+changed: ./issue (content)
+ran true
+skipped: ./status
+");
+    }
+
+    #[test]
+    fn test_handlers() {
+        let script = parse!(r#"
+./etc/ (exists)
+./etc/nginx.conf (content = "v1", notify = reload)
+./etc/site.conf (content = "v1", notify = [reload, restart])
+./etc/other.conf (content = "v1", notify = restart, when = ./missing exists)
+handler restart
+    system (restart)
+end
+handler reload
+    system (reload)
+end
+"#);
+
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        HandleExec::new(&mut test_exec).with_output(&mut output).execute_raw_script(&script).unwrap();
+
+        // Both handlers run once, in the order they are defined
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+changed: ./etc/ (exists)
+changed: ./etc/nginx.conf (content)
+changed: ./etc/site.conf (content)
+skipped: ./etc/other.conf
+handler: restart
+ok: system (restart)
+handler: reload
+ok: system (reload)
+");
+
+        // Nothing changes the second time
+        let mut output = vec![];
+        HandleExec::new(&mut test_exec).with_output(&mut output).execute_raw_script(&script).unwrap();
+        assert!(!String::from_utf8(output).unwrap().contains("handler"));
+
+        // Handlers don't run after a failure unless forced
+        let failing = parse!(r#"
+./etc/nginx.conf (content = "v2", notify = reload)
+./etc/version (content = $undefined)
+handler reload
+    system (reload)
+end
+"#);
+        for force_handlers in &[false, true] {
+            let mut test_exec = TestExec::new("./testing");
+            let mut output = vec![];
+            let result = HandleExec::new(&mut test_exec)
+                .with_output(&mut output)
+                .with_force_handlers(*force_handlers)
+                .execute_raw_script(&failing);
+            assert!(result.is_err());
+            assert_eq!(String::from_utf8(output).unwrap().contains("system (reload)"), *force_handlers);
+        }
+
+        let unknown = parse!(r#"./etc/motd (content = "hi", notify = missing)"#);
+        let mut test_exec = TestExec::new("./testing");
+        let error = HandleExec::new(&mut test_exec).with_output(&mut vec![]).execute_raw_script(&unknown).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown handler: missing");
+    }
+
+    #[test]
+    fn test_try() {
+        let script = parse!(r#"
+try
+    system (deploy)
+    ./etc/deployed (content = "yes")
+rescue err
+    ./etc/error (content = "{{ err.code }}: {{ err.message }}")
+always
+    ./etc/always (content = "done")
+end
+system (cleanup) (ignore_errors)
+"#);
+
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.script_status = 2;
+        let mut output = vec![];
+        HandleExec::new(&mut test_exec).with_output(&mut output).execute_raw_script(&script).unwrap();
+
+        assert!(!test_exec.contents.contains_key("testing/etc/deployed"));
+        assert_eq!(test_exec.contents["testing/etc/error"], "2: system (deploy) failed: ResultCode(2)");
+        assert_eq!(test_exec.contents["testing/etc/always"], "done");
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+ran deploy
+rescued: system (deploy) failed: ResultCode(2)
+changed: ./etc/error (content)
+changed: ./etc/always (content)
+ran cleanup
+ignored: system (cleanup) failed: ResultCode(2)
+");
+
+        // Without `rescue` the error is raised once `always` has run
+        let script = parse!(r#"
+try
+    system (deploy) (retries = 2, delay = 0)
+always
+    ./etc/always (content = "done")
+end
+"#);
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.script_status = 1;
+        let mut output = vec![];
+        let error = HandleExec::new(&mut test_exec).with_output(&mut output).execute_raw_script(&script).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::CommandFailed(Failure::ResultCode(1)));
+        assert_eq!(test_exec.processes.len(), 3);
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+ran deploy
+retrying (1/2): system (deploy) failed: ResultCode(1)
+ran deploy
+retrying (2/2): system (deploy) failed: ResultCode(1)
+ran deploy
+changed: ./etc/always (content)
+");
+
+        let script = parse!(r#"
+try
+    assert $count > 2
+rescue err
+    ./etc/error (content = "{{ err.kind }}: {{ err.message }}")
+end
+"#);
+        let mut test_exec = TestExec::new("./testing");
+        HandleExec::new(&mut test_exec)
+            .with_output(&mut io::sink())
+            .with_var("count", Value::Integer(1))
+            .execute_raw_script(&script)
+            .unwrap();
+        assert_eq!(test_exec.contents["testing/etc/error"], "assertion failed: Assertion failed: $count > 2");
+    }
+
+    #[test]
+    fn test_error_span() {
+        // Errors are raised at the innermost statement that failed
+        let source = "./etc/motd (exists)\nif true\n    ./etc/issue (content = $missing)\nend\n";
+        let script = parse_file(0, source).unwrap().1;
+        let mut test_exec = TestExec::new("./testing");
+        let error = HandleExec::new(&mut test_exec).with_output(&mut io::sink()).execute_raw_script(&script).unwrap_err();
+        assert_eq!(error.render(&[("site.idem".to_string(), source.to_string())]), "\
+error: Undefined variable $missing in ./etc/issue
+ --> site.idem:3:5
+  |
+3 |     ./etc/issue (content = $missing)
+  |     ^
+");
+    }
+
+    #[test]
+    fn test_let() {
+        let script = parse!(r#"
+let version = "1.0"
+let release = "app-{{ version }}"
+let home = $ENV.HOME
+each host in [web, db]
+    let conf = "{{ home }}/{{ host }}.conf"
+    $conf (content = "{{ release }} on {{ host }}")
+end
+with ./opt/
+    let version = "2.0"
+    ./VERSION (content = "{{ version }}")
+end
+./RELEASE (content = "{{ release }} {{ version }}")
+"#);
+
+        let mut env = BTreeMap::new();
+        env.insert("HOME".to_string(), Value::from("/home/deploy"));
+        let mut test_exec = TestExec::new("/testing");
+        let mut output = io::sink();
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("ENV", Value::Map(env))
+            .with_override("version", "1.2");
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // `let version` is overridden, and bindings in blocks end with them
+        assert_eq!(test_exec.contents["/home/deploy/web.conf"], "app-1.2 on web");
+        assert_eq!(test_exec.contents["/home/deploy/db.conf"], "app-1.2 on db");
+        assert_eq!(test_exec.contents["/testing/opt/VERSION"], "1.2");
+        assert_eq!(test_exec.contents["/testing/RELEASE"], "app-1.2 1.2");
+
+        // A binding does not outlive its block
+        let script = parse!(r#"
+each host in [web]
+    let conf = "{{ host }}.conf"
+end
+./motd (content = "{{ conf }}")
+"#);
+        let mut test_exec = TestExec::new("/testing");
+        let err = HandleExec::new(&mut test_exec).execute_raw_script(&script).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined variable $conf in ./motd");
+    }
+
+    #[test]
+    fn test_def() {
+        let script = parse!(r#"
+def motd(greeting, name = "world", ...rest)
+    $path (content="{{ greeting }}, {{ name }}{{ rest }}")
+end
+def site({ name, (tls|-t) = false, ...options })
+    return { name = $name, tls = $tls, options = $options }
+    ./unreachable (exists)
+end
+def count
+    system (wc -l)
+end
+./etc/motd (motd "Hello")
+./etc/issue (motd greeting="Hi", name="there")
+./etc/banner (motd Welcome, "back", "!")
+./list (count)
+"#);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec).with_output(&mut output);
+        handle_exec.execute_raw_script(&script).unwrap();
+
+        // Keyword arguments, flags and the splat
+        let value = |s| parse_value(CompleteStr(s)).unwrap().1;
+        let site = handle_exec.evaluate(&value(r#"(( ./www (site name="blog" -t port=8080) ))"#)).unwrap();
+        assert_eq!(site.to_string(), r#"{ name = "blog", options = { port = 8080 }, tls = true }"#);
+        let site = handle_exec.evaluate(&value(r#"(( ./www (site name="blog") ))"#)).unwrap();
+        assert_eq!(site.to_string(), r#"{ name = "blog", options = {  }, tls = false }"#);
+
+        // The last statement is the result, and a path without a call is not a command
+        assert_eq!(handle_exec.evaluate(&value("(( ./list (count) ))")).unwrap(), Value::from("ran wc\n"));
+        assert!(handle_exec.evaluate(&value("$path")).is_err());
+
+        // Bad calls
+        assert!(handle_exec.evaluate(&value(r#"(( ./www (site "blog") ))"#)).is_err());
+        assert!(handle_exec.evaluate(&value(r#"(( ./www (site tls=true) ))"#)).is_err());
+        assert!(handle_exec.evaluate(&value(r#"(( ./www (missing x=1) ))"#)).is_err());
+        drop(handle_exec);
+
+        // Assert result
+        assert_eq!(test_exec.contents["testing/etc/motd"], "Hello, world[]");
+        assert_eq!(test_exec.contents["testing/etc/issue"], "Hi, there[]");
+        assert_eq!(test_exec.contents["testing/etc/banner"], r#"Welcome, back["!"]"#);
+        assert!(!test_exec.created_files.contains(&"testing/unreachable".to_string()));
+    }
+
+    #[test]
+    fn test_include_import() {
+        let mut script = parse!(r#"
+let greeting = "Hello"
+include ./common.idem
+import ./lib/web.idem as web
+./etc/motd (motd)
+./srv/app (web.deploy "1.0")
+./srv/www (web.deploy, when = $release)
+"#);
+        let included = parse!(r#"
+let release = false
+def motd()
+    $path (content = "{{ greeting }}")
+end
+"#);
+        let imported = parse!(r#"
+def motd()
+    $path (content = "web")
+end
+def deploy(version)
+    $path/version (content = $version)
+    $path/motd (motd)
+end
+"#);
+        match script[1].node {
+            IdemRawCommandType::Include(ref mut include) => include.statements = included,
+            _ => unreachable!(),
+        }
+        match script[2].node {
+            IdemRawCommandType::Import(ref mut import) => import.statements = imported,
+            _ => unreachable!(),
+        }
+
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        HandleExec::new(&mut test_exec).with_output(&mut output).execute_raw_script(&script).unwrap();
+
+        // The included file binds in the scope of the script, the commands of the
+        // imported one call each other without its name
+        assert_eq!(test_exec.contents["testing/etc/motd"], "Hello");
+        assert_eq!(test_exec.contents["testing/srv/app/version"], "1.0");
+        assert_eq!(test_exec.contents["testing/srv/app/motd"], "web");
+        assert!(!test_exec.contents.contains_key("testing/srv/www/version"));
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+changed: ./etc/motd (content)
+changed: ./srv/app/version (content)
+changed: ./srv/app/motd (content)
+skipped: ./srv/www
+");
+    }
+
+    #[test]
+    fn test_import_defblock() {
+        let root = std::path::Path::new("./testing/import-defblock");
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join("site.idem"), r#"
+defblock stage [env] do
+    $path (content = "site {{ env }}")
+end
+import ./web.idem as web
+let path = "./site"
+stage prod
+end
+web.stage prod
+end
+"#).unwrap();
+        fs::write(root.join("web.idem"), r#"
+defblock banner do
+    ./web (content = "web")
+end
+defblock stage [env] do
+    banner
+    end
+end
+"#).unwrap();
+
+        let mut files = vec![];
+        let script = Loader::new(&mut files, vec![]).load("./testing/import-defblock/site.idem").unwrap();
+        let mut test_exec = TestExec::new("./testing");
+        HandleExec::new(&mut test_exec).execute_raw_script(&script).unwrap();
+
+        // The imported block does not replace the one of the script, and calls the
+        // blocks of its own file without their name
+        assert_eq!(test_exec.contents["testing/site"], "site prod");
+        assert_eq!(test_exec.contents["testing/web"], "web");
+    }
+
+    #[test]
+    fn test_defblock() {
+        let script = parse!(r#"
+defblock deploy [app "to" env] ({ (force|-f) = false }) do
+    ./releases/ (exists)
+    call $block
+    ./current (content="{{ app }} on {{ env }}, force={{ force }}")
+end
+defblock banner [text] (width = 10)
+    return "{{ text }}/{{ width }}"
+end
+deploy "web" to staging (-f)
+    ./releases/web/ (exists)
+end
+"#);
+
+        // Execute script
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec).with_output(&mut output);
+        handle_exec.execute_raw_script(&script).unwrap();
+
+        // A block command without `do` takes no block
+        let value = |s| parse_value(CompleteStr(s)).unwrap().1;
+        assert_eq!(handle_exec.evaluate(&value("(( banner hello (width=4) ))")).unwrap(), Value::from("hello/4"));
+        assert!(handle_exec.evaluate(&value("(( call $undefined ))")).is_err());
+        drop(handle_exec);
+
+        // Assert result, the block runs between the body's statements
+        assert_eq!(test_exec.created_dirs, vec!["testing/releases", "testing/releases/web"]);
+        assert_eq!(test_exec.contents["testing/current"], "web on staging, force=true");
+    }
+
+    #[test]
+    fn test_edit() {
+        let script = parse!(r#"
+with ./etc/app.ini (edit)
+    [blocka]
+    a1 = "{{ value }}"
+
+    [blockb]
+end
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/etc/app.ini".to_string(), "[main]\nx = 1\n\n[blockb]\nb1 = 2\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("value", "one");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the second run changes nothing
+        assert_eq!(test_exec.contents["testing/etc/app.ini"], "[main]\nx = 1\n\n[blocka]\na1 = \"one\"\n\n[blockb]\nb1 = 2\n");
+        assert_eq!(test_exec.created_files.len(), 1);
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./etc/app.ini (edit)\nok: ./etc/app.ini (edit)\n");
+    }
+
+    #[test]
+    fn test_edit_template_with_markers() {
+        let script = parse!(r#"
+with ./etc/iptables/rules.v4 (edit)
+    *filter
+    -A INPUT -p tcp --dport 22 -j ACCEPT
+    -A INPUT -p tcp --dport 443 -j ACCEPT
+end
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/etc/iptables/rules.v4".to_string(), "*filter\n-A INPUT -p tcp --dport 22 -j ACCEPT\nCOMMIT\n".to_string());
+        let mut handle_exec = HandleExec::new(&mut test_exec);
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the rules are merged into the block rather than removed
+        assert_eq!(
+            test_exec.contents["testing/etc/iptables/rules.v4"],
+            "*filter\n-A INPUT -p tcp --dport 443 -j ACCEPT\n-A INPUT -p tcp --dport 22 -j ACCEPT\nCOMMIT\n",
+        );
+    }
+
+    #[test]
+    fn test_block() {
+        let script = parse!(r##"
+./.bashrc (block = """
+    alias k=kubectl
+    export TEAM={{ team }}
+    """, marker = "# {mark} IDEMSH team-x")
+./.bashrc (marker = "# {mark} IDEMSH old", absent)
+"##);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/.bashrc".to_string(), "export EDITOR=vi\n# BEGIN IDEMSH old\nalias old=true\n# END IDEMSH old\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("team", "x");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the second run changes nothing
+        assert_eq!(test_exec.contents["testing/.bashrc"], "export EDITOR=vi\n# BEGIN IDEMSH team-x\nalias k=kubectl\nexport TEAM=x\n# END IDEMSH team-x\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+changed: ./.bashrc (block)
+changed: ./.bashrc (block)
+ok: ./.bashrc (block)
+ok: ./.bashrc (block)
+");
+    }
+
+    #[test]
+    fn test_template() {
+        let script = parse!(r##"
+./site.conf (template = ./testing/templates/site.conf.tpl)
+"##);
+        fs::create_dir_all("testing/templates").unwrap();
+        fs::write("testing/templates/site.conf.tpl", "\
+{% for host in hosts %}
+server {{ host | upper }};
+{% endfor %}
+port {{ port | default(80) }};
+").unwrap();
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("hosts", Value::List(vec![Value::from("web1"), Value::from("web2")]));
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result
+        assert_eq!(test_exec.contents["testing/site.conf"], "server WEB1;\nserver WEB2;\nport 80;\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./site.conf (template)\nok: ./site.conf (template)\n");
+
+        // Errors point at the line of the template
+        fs::write("testing/templates/broken.tpl", "a\n{{ nope }}\n").unwrap();
+        let script = parse!("./site.conf (template = ./testing/templates/broken.tpl)");
+        let mut test_exec = TestExec::new("./testing");
+        let err = HandleExec::new(&mut test_exec).execute_raw_script(&script).unwrap_err();
+        assert!(err.to_string().contains("testing/templates/broken.tpl line 2: nope is not defined"), "{}", err);
+
+        // Templates are relative to the file of the statement, like includes
+        fs::write("testing/templates/site.idem", "include ./roles/app.idem\n").unwrap();
+        fs::create_dir_all("testing/templates/roles").unwrap();
+        fs::write("testing/templates/roles/app.idem", "./app.conf (template = ./app.conf.tpl)\n").unwrap();
+        fs::write("testing/templates/roles/app.conf.tpl", "port {{ port }};\n").unwrap();
+        let mut files = vec![];
+        let script = Loader::new(&mut files, vec![]).load("./testing/templates/site.idem").unwrap();
+        let mut test_exec = TestExec::new("./testing");
+        HandleExec::new(&mut test_exec)
+            .with_files(files.into_iter().map(|(name, _)| name).collect())
+            .with_var("port", Value::Integer(8080))
+            .execute_raw_script(&script)
+            .unwrap();
+        assert_eq!(test_exec.contents["testing/app.conf"], "port 8080;\n");
+    }
+
+    #[test]
+    fn test_edit_lines() {
+        let script = parse!(r#"
+with ./etc/app.ini (edit = lines)
+    [main]
+    - debug = true
+    + debug = {{ debug }}
+    /^x/ s/=.*/= {{ x }}/
+end
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/etc/app.ini".to_string(), "[main]\ndebug = true\nx = 1\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("debug", "false")
+            .with_var("x", "2");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the second run changes nothing
+        assert_eq!(test_exec.contents["testing/etc/app.ini"], "[main]\ndebug = false\nx = 2\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./etc/app.ini (edit)\nok: ./etc/app.ini (edit)\n");
+    }
+
+    #[test]
+    fn test_edit_param() {
+        let script = parse!(r#"
+./etc/hosts (edit = {
+    $+ {{ ip }} web (/ web$/, insertafter = /^127\./)
+    $- (/^# remove/)
+})
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/etc/hosts".to_string(), "127.0.0.1 localhost\n# remove me\n::1 localhost\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("ip", "10.0.0.2");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the second run changes nothing
+        assert_eq!(test_exec.contents["testing/etc/hosts"], "127.0.0.1 localhost\n10.0.0.2 web\n::1 localhost\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./etc/hosts (edit)\nok: ./etc/hosts (edit)\n");
+    }
+
+    #[test]
+    fn test_edit_json() {
+        let script = parse!(r#"
+with ./etc/app.json (edit)
+    @/server
+    { "port": {{ port }}, "tls": { "enabled": true } }
+    @/debug (removed)
+end
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/etc/app.json".to_string(), "{\n  \"server\": {\n    \"port\": 80\n  },\n  \"debug\": true\n}\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("port", "8080");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the second run changes nothing
+        assert_eq!(test_exec.contents["testing/etc/app.json"], "{\n  \"server\": {\n    \"port\": 8080,\n    \"tls\": {\n      \"enabled\": true\n    }\n  }\n}\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./etc/app.json (edit)\nok: ./etc/app.json (edit)\n");
+    }
+
+    #[test]
+    fn test_edit_ini() {
+        let script = parse!(r#"
+with ./etc/app.conf (edit, format = ini)
+    [server]
+    port = {{ port }}
+end
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/etc/app.conf".to_string(), "[server]\n; the port\nport = 80\nhost = localhost\n".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("port", "8080");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, the key is overwritten and the second run changes nothing
+        assert_eq!(test_exec.contents["testing/etc/app.conf"], "[server]\n; the port\nport = 8080\nhost = localhost\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./etc/app.conf (edit)\nok: ./etc/app.conf (edit)\n");
+    }
+
+    #[test]
+    fn test_edit_yaml() {
+        let script = parse!(r#"
+with ./k8s/app.yml (edit, document = 1)
+    @/spec/replicas = {{ replicas }}
+    @/metadata/labels
+    tier: web
+end
+"#);
+
+        // Execute script twice
+        let mut test_exec = TestExec::new("./testing");
+        test_exec.contents.insert("testing/k8s/app.yml".to_string(), "kind: Namespace
+---
+kind: Deployment
+metadata:
+  name: app # the app
+  labels: {}
+spec:
+  replicas: 1
+".to_string());
+        let mut output = vec![];
+        let mut handle_exec = HandleExec::new(&mut test_exec)
+            .with_output(&mut output)
+            .with_var("replicas", "3");
+        handle_exec.execute_raw_script(&script).unwrap();
+        handle_exec.execute_raw_script(&script).unwrap();
+        drop(handle_exec);
+
+        // Assert result, only the second document changes and the second run changes nothing
+        assert_eq!(test_exec.contents["testing/k8s/app.yml"], "kind: Namespace\n---\nkind: Deployment\nmetadata:\n  name: app # the app\n  labels: {tier: web}\nspec:\n  replicas: 3\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "changed: ./k8s/app.yml (edit)\nok: ./k8s/app.yml (edit)\n");
+    }
+}
+
@@ -0,0 +1,231 @@
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nom::Context;
+
+use super::ast::{IdemRawCommandType, Span, Spanned};
+use super::errors::{Error, ErrorKind, Result as ExecResult};
+use super::parser::{BlockKeywords, FileParser};
+
+/// Reads a script and the files it includes and imports, keeping the name and source
+/// of each in `files` so errors can show where they were raised.
+pub struct Loader<'f> {
+    files: &'f mut Vec<(String, String)>,
+    /// Where files named without `./`, `../` or `/` are looked for, after the directory
+    /// of the file naming them.
+    search_path: Vec<PathBuf>,
+    /// The files being read, each included by the one before it, as indices in `files`
+    /// and with their canonical paths.
+    chain: Vec<(usize, PathBuf)>,
+}
+
+impl<'f> Loader<'f> {
+    pub fn new(files: &'f mut Vec<(String, String)>, search_path: Vec<PathBuf>) -> Self {
+        Loader { files, search_path, chain: vec![] }
+    }
+
+    /// Reads and parses the script at `name`, with the files it includes and imports.
+    pub fn load(&mut self, name: &str) -> ExecResult<Vec<Spanned<IdemRawCommandType>>> {
+        self.load_file(name).map(|(script, _)| script)
+    }
+
+    /// Reads a script and the files it includes and imports, and the block keywords
+    /// it defines.
+    fn load_file(&mut self, name: &str) -> ExecResult<(Vec<Spanned<IdemRawCommandType>>, BlockKeywords)> {
+        let canonical = fs::canonicalize(name).map_err(|e| Error::io(name, e))?;
+        if let Some(position) = self.chain.iter().position(|(_, path)| *path == canonical) {
+            let mut names: Vec<&str> = self.chain[position..].iter()
+                .map(|&(file, _)| self.files[file].0.as_str())
+                .collect();
+            names.push(name);
+            return Err(Error::new(ErrorKind::Parse, format!("Include cycle: {}", names.join(" -> "))));
+        }
+
+        let source = fs::read_to_string(name).map_err(|e| Error::io(name, e))?;
+        self.files.push((name.to_string(), source));
+        let file = self.files.len() - 1;
+
+        self.chain.push((file, canonical));
+        let result = self.parse(file);
+        self.chain.pop();
+        result
+    }
+
+    /// Parses a file a statement at a time, reading the files a statement includes and
+    /// imports before the next, which can then use the block keywords they define.
+    fn parse(&mut self, file: usize) -> ExecResult<(Vec<Spanned<IdemRawCommandType>>, BlockKeywords)> {
+        let source = self.files[file].1.clone();
+        let mut parser = FileParser::new(file, &source);
+        let mut script = vec![];
+        let rest = loop {
+            match parser.next_statement() {
+                Ok(Some(mut statement)) => {
+                    let keywords = self.resolve(std::slice::from_mut(&mut statement))?;
+                    parser.add_keywords(&keywords);
+                    script.push(statement);
+                }
+                Ok(None) => return Ok((script, parser.keywords().clone())),
+                Err(nom::Err::Error(Context::Code(rest, _))) | Err(nom::Err::Failure(Context::Code(rest, _))) => break rest,
+                Err(e) => return Err(Error::new(ErrorKind::Parse, format!("Unable to parse script: {:?}", e))),
+            }
+        };
+
+        let rest = rest.trim_start();
+        let start = source.len() - rest.len();
+        let near = rest.lines().next().unwrap_or_default();
+        Err(Error::new(ErrorKind::Parse, format!("Unable to parse script near: {}", near))
+            .with_span(Span::new(file, &source, start, start + near.len())))
+    }
+
+    /// Reads the files included and imported by statements, at any depth, returning the
+    /// block keywords they define.
+    fn resolve(&mut self, statements: &mut [Spanned<IdemRawCommandType>]) -> ExecResult<BlockKeywords> {
+        let mut keywords = BlockKeywords::default();
+        for statement in statements {
+            let span = statement.span;
+            // The keywords of an imported file are called by the name it is imported as
+            let loaded = match statement.node {
+                IdemRawCommandType::Include(ref mut include) => Some((&include.path, &mut include.statements, None)),
+                IdemRawCommandType::Import(ref mut import) => Some((&import.path, &mut import.statements, Some(&import.name))),
+                _ => None,
+            };
+
+            match loaded {
+                Some((path, statements, namespace)) => {
+                    let (script, loaded) = self.find(span.file, path)
+                        .and_then(|name| self.load_file(&name))
+                        .map_err(|e| e.with_span(span))?;
+                    *statements = script;
+                    match namespace {
+                        Some(namespace) => keywords.extend(&loaded.qualified(namespace)),
+                        None => keywords.extend(&loaded),
+                    }
+                }
+                None => {
+                    for block in statement.node.blocks_mut() {
+                        keywords.extend(&self.resolve(block)?);
+                    }
+                }
+            }
+        }
+        Ok(keywords)
+    }
+
+    /// The file `path` names in the file `from`. Paths starting with `./`, `../` or `/`
+    /// are only relative to the directory of `from`, others are also looked for in
+    /// the search path.
+    fn find(&self, from: usize, path: &str) -> ExecResult<String> {
+        let dir = Path::new(&self.files[from].0).parent().unwrap_or_else(|| Path::new(""));
+        let explicit = path.starts_with("./") || path.starts_with("../");
+        if explicit || path.starts_with('/') {
+            let path = path.strip_prefix("./").unwrap_or(path);
+            return Ok(dir.join(path).to_string_lossy().to_string());
+        }
+
+        std::iter::once(dir).chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+            .map(|found| found.to_string_lossy().to_string())
+            .ok_or_else(|| Error::new(
+                ErrorKind::Io(Some(path.to_string())),
+                format!("Unable to find {} next to {} or in the search path", path, self.files[from].0),
+            ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, files: &[(&str, &str)]) {
+        let _ = fs::remove_dir_all(root);
+        for (name, source) in files {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let root = Path::new("./testing/loader");
+        write(root, &[
+            ("site.idem", "include ./roles/common.idem\nimport web.idem as web\n"),
+            ("roles/common.idem", "if $x\n    include ../motd.idem\nend\n"),
+            ("motd.idem", "./etc/motd (exists)\n"),
+            ("lib/web.idem", "def deploy()\nend\n"),
+        ]);
+
+        let mut files = vec![];
+        let script = Loader::new(&mut files, vec![root.join("lib")]).load("./testing/loader/site.idem").unwrap();
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec![
+            "./testing/loader/site.idem",
+            "./testing/loader/roles/common.idem",
+            "./testing/loader/roles/../motd.idem",
+            "./testing/loader/lib/web.idem",
+        ]);
+
+        let include = match script[0].node {
+            IdemRawCommandType::Include(ref include) => include,
+            _ => unreachable!(),
+        };
+        match include.statements[0].node {
+            IdemRawCommandType::If(ref obj) => assert_eq!(obj.statements[0].span.file, 1),
+            _ => unreachable!(),
+        }
+        match script[1].node {
+            IdemRawCommandType::Import(ref import) => assert_eq!(import.statements[0].span.file, 3),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_load_block_keywords() {
+        let root = Path::new("./testing/loader-keywords");
+        write(root, &[
+            ("site.idem", "include ./roles.idem\nstage prod\n    ./x (exists)\nend\nimport ./lib.idem as lib\n"),
+            ("roles.idem", "include ./lib.idem\n"),
+            ("lib.idem", "defblock stage [env] do\n    call $block\nend\n"),
+        ]);
+
+        // Keywords of included files are recognised after the include, at any depth
+        let mut files = vec![];
+        let script = Loader::new(&mut files, vec![]).load("./testing/loader-keywords/site.idem").unwrap();
+        assert!(matches!(script[1].node, IdemRawCommandType::BlockCall(ref call) if call.name == "stage"));
+
+        // Those of imported files are called by the name they are imported as
+        write(root, &[
+            ("site.idem", "import ./lib.idem as lib\nlib.stage prod\n    ./x (exists)\nend\nstage prod\nend\n"),
+            ("lib.idem", "defblock stage [env] do\n    call $block\nend\n"),
+        ]);
+        let error = Loader::new(&mut files, vec![]).load("./testing/loader-keywords/site.idem").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::Parse);
+        assert_eq!(error.to_string(), "Unable to parse script near: stage prod");
+    }
+
+    #[test]
+    fn test_load_cycle() {
+        let root = Path::new("./testing/loader-cycle");
+        write(root, &[
+            ("site.idem", "./etc/ (exists)\ninclude ./a.idem\n"),
+            ("a.idem", "include ./b.idem\n"),
+            ("b.idem", "include ./a.idem\n"),
+        ]);
+
+        let mut files = vec![];
+        let error = Loader::new(&mut files, vec![]).load("./testing/loader-cycle/site.idem").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::Parse);
+        assert_eq!(error.render(&files), "\
+parse error: Include cycle: ./testing/loader-cycle/a.idem -> ./testing/loader-cycle/b.idem -> ./testing/loader-cycle/a.idem
+ --> ./testing/loader-cycle/b.idem:1:1
+  |
+1 | include ./a.idem
+  | ^
+");
+
+        let error = Loader::new(&mut files, vec![]).load("./testing/loader-cycle/missing.idem").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::Io(Some("./testing/loader-cycle/missing.idem".to_string())));
+    }
+}
@@ -1,13 +1,22 @@
 
 use std::path::{Path, PathBuf};
-use std::io::Result as IOResult;
-use std::env::{current_dir};
+use std::io::{self, Read, Result as IOResult, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::env::{current_dir, current_exe, temp_dir};
 use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use super::ast::*;
-use super::parser::*;
+use super::agent::AgentExec;
 use super::traits::*;
-use super::errors::Result as ExecResult;
+use super::escalate::{shell_quote, Escalation};
+use super::errors::{Error, Result as ExecResult};
+
+/// Numbers the temporary files and directories created by this process.
+static TEMPORARY_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct LocalExec {
@@ -23,10 +32,10 @@ impl Default for LocalExec {
 }
 
 impl LocalExec {
-    fn with_new_relative_working_dir<P: AsRef<Path>>(dir: P) -> Self {
+    pub fn with_new_relative_working_dir<P: AsRef<Path>>(dir: P) -> Self {
         let cwd =  current_dir().expect("Failed to get cwd, this should never happen.")
                 .join(dir);
-        if !cwd.exists() { fs::create_dir_all(&cwd); }
+        if !cwd.exists() { let _ = fs::create_dir_all(&cwd); }
 
         LocalExec {
             cwd: cwd.to_path_buf()
@@ -36,61 +45,171 @@ impl LocalExec {
 
 impl Exec for LocalExec {
     fn change_directory(&mut self, dir: &str) -> ExecResult<()> {
+        self.cwd = self.cwd.join(dir);
         Ok(())
     }
 
-    fn ensure_directory(&mut self, local_part: &str) -> ExecResult<()> {
+    fn ensure_directory(&mut self, local_part: &str) -> ExecResult<bool> {
         let dir = self.cwd.join(local_part);
-        eprintln!("Checking for path {:?}", dir);
-        if !dir.exists() {
-            eprintln!("Creating path {:?}", dir);
-            fs::create_dir(dir)?;
-        };
+        if dir.exists() {
+            return Ok(false);
+        }
 
-        Ok(())
+        fs::create_dir(dir).map_err(|e| Error::io(local_part, e))?;
+        Ok(true)
     }
 
-    fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<()> {
-        Ok(())
+    fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+        let path = self.cwd.join(local_part);
+        if path.exists() {
+            return Ok(false);
+        }
+
+        create_ignore_existing(path).map_err(|e| Error::io(local_part, e))?;
+        Ok(true)
     }
 
-    fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<()> {
-        Ok(())
+    fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<bool> {
+        let path = self.cwd.join(local_part);
+        let FileContents::StaticString(contents) = contents;
+        if fs::read_to_string(&path).ok().as_ref() == Some(&contents) {
+            return Ok(false);
+        }
+
+        fs::write(path, contents).map_err(|e| Error::io(local_part, e))?;
+        Ok(true)
     }
 
     fn get_cwd(&mut self) -> ExecResult<String> {
         Ok(self.cwd.to_str().unwrap().to_string())
     }
-}
 
-#[inline(always)]
-fn find_single_path<'a>(obj: &'a IdemRawCommandWithPaths) -> Option<&'a IdemPath> {
-    if obj.paths.len() != 1 { return None; }
-    obj.paths.iter().next()
-}
+    fn path_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+        Ok(self.cwd.join(local_part).exists())
+    }
+
+    fn read_file(&mut self, local_part: &str) -> ExecResult<Option<String>> {
+        match fs::read_to_string(self.cwd.join(local_part)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::io(local_part, e)),
+        }
+    }
 
-#[inline(always)]
-fn find_single_flag_keyword(params: &[IdemParamType]) -> Option<&str> {
-    if params.len() != 1 { return None; }
+    fn create_temporary(&mut self, directory: bool, suffix: &str) -> ExecResult<String> {
+        loop {
+            let n = TEMPORARY_COUNT.fetch_add(1, Ordering::SeqCst);
+            let path = temp_dir().join(format!("idemsh-{}-{}{}", process::id(), n, suffix));
+            let created = if directory {
+                fs::create_dir(&path)
+            } else {
+                fs::OpenOptions::new().write(true).create_new(true).open(&path).map(|_| ())
+            };
 
-    match params[0] {
-        IdemParamType::FlagKeyword(ref s) => {
-            Some(s)
+            match created {
+                Ok(()) => return Ok(path.to_string_lossy().to_string()),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
         }
+    }
+
+    fn remove_path(&mut self, local_part: &str) -> ExecResult<()> {
+        let path = self.cwd.join(local_part);
+        let removed = match fs::symlink_metadata(&path) {
+            Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(&path),
+            Ok(_) => fs::remove_file(&path),
+            Err(e) => Err(e),
+        };
+
+        match removed {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            removed => removed.map_err(|e| Error::io(local_part, e)),
+        }
+    }
+
+    fn execute(&mut self, process: &Process) -> ExecResult<CommandOutput> {
+        let cwd = match process.cwd {
+            Some(ref cwd) => self.cwd.join(cwd),
+            None => self.cwd.clone(),
+        };
+
+        let mut child = Command::new(&process.program)
+            .args(&process.args)
+            .envs(process.env.iter().map(|(k, v)| (k, v)))
+            .current_dir(cwd)
+            .stdin(if process.stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Feed and drain the pipes on their own threads so a chatty process can't block
+        let stdin = child.stdin.take().map(|mut pipe| {
+            let input = process.stdin.clone().unwrap_or_default();
+            thread::spawn(move || { let _ = pipe.write_all(input.as_bytes()); })
+        });
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+
+        let status = match process.timeout {
+            Some(timeout) => wait_timeout(&mut child, timeout)?,
+            None => Some(child.wait()?),
+        };
+
+        if let Some(stdin) = stdin { let _ = stdin.join(); }
+
+        Ok(CommandOutput {
+            status: status.map(exit_code).unwrap_or(-1),
+            timed_out: status.is_none(),
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
 
-        _ => None
+    fn escalate(&mut self, escalation: &Escalation) -> ExecResult<Box<dyn Exec + Send>> {
+        let agent = format!("{} agent --root {}",
+            shell_quote(&current_exe()?.to_string_lossy()),
+            shell_quote(&self.cwd.to_string_lossy()));
+        let agent = AgentExec::spawn_shell(&escalation.wrap(&agent)?, escalation.password.as_deref())?;
+        Ok(Box::new(agent))
     }
 }
 
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe { let _ = pipe.read_to_end(&mut buf); }
+        String::from_utf8_lossy(&buf).to_string()
+    })
+}
+
+/// Waits for the child to exit, killing it once the timeout has passed.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> IOResult<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The exit code, or 128 + the signal number for a process killed by a signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
 fn create_ignore_existing<T: AsRef<Path>>(path: T) -> IOResult<()> {
-    fs::OpenOptions::new().create(true).write(true).open(path).map(|_| ())
+    fs::OpenOptions::new().create(true).append(true).open(path).map(|_| ())
 }
 
 #[cfg(test)]
 mod tests {
-    use nom::types::CompleteStr;
-
-    #[macro_use]
     use super::*;
 
     macro_rules! local_exec {
@@ -116,4 +235,58 @@ mod tests {
         // Assert result
         assert!(Path::new("./testing/adir").is_dir(), "./testing/adir does not exist or is not a directory.");
     }
+
+    #[test]
+    fn test_temporary() {
+        let mut local_exec = local_exec!();
+
+        let dir = local_exec.create_temporary(true, "-build").unwrap();
+        let file = local_exec.create_temporary(false, ".conf").unwrap();
+        assert!(dir.ends_with("-build") && Path::new(&dir).is_dir(), "{}", dir);
+        assert!(file.ends_with(".conf") && Path::new(&file).is_file(), "{}", file);
+        local_exec.ensure_file_contents(&format!("{}/afile", dir), FileContents::StaticString("a".to_string())).unwrap();
+
+        // Assert result, removing twice is not an error
+        for path in &[&dir, &file, &dir] {
+            local_exec.remove_path(path).unwrap();
+            assert!(!Path::new(path).exists(), "{} was not removed", path);
+        }
+    }
+
+    #[test]
+    fn test_run_script() {
+        let mut local_exec = local_exec!();
+
+        let output = local_exec.run_script("sh", "echo out; echo err >&2; exit 3").unwrap();
+
+        // Assert result
+        assert_eq!(output, CommandOutput {
+            status: 3,
+            timed_out: false,
+            stdout: "out\n".to_string(),
+            stderr: "err\n".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_execute() {
+        let mut local_exec = local_exec!();
+        local_exec.ensure_directory("./execdir").unwrap();
+
+        let output = local_exec.execute(&Process {
+            args: vec!["-c".to_string(), "pwd; echo $GREETING".to_string()],
+            env: vec![("GREETING".to_string(), "hello".to_string())],
+            cwd: Some("./execdir".to_string()),
+            ..Process::new("sh")
+        }).unwrap();
+        assert_eq!(output.status, 0);
+        assert!(output.stdout.ends_with("/testing/execdir\nhello\n"), "{}", output.stdout);
+
+        let output = local_exec.execute(&Process {
+            args: vec!["5".to_string()],
+            timeout: Some(Duration::from_millis(50)),
+            ..Process::new("sleep")
+        }).unwrap();
+        assert!(output.timed_out);
+    }
 }
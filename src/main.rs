@@ -1,4 +1,3 @@
-
 #[macro_use]
 pub extern crate nom;
 
@@ -8,7 +7,187 @@ mod traits;
 mod errors;
 mod local_exec;
 mod handle_exec;
+mod remote;
+mod agent;
+mod escalate;
+mod value;
+mod edit;
+mod extract;
+mod template;
+mod scope;
+mod loader;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+
+
+use agent::{AgentExec, DEFAULT_AGENT_COMMAND};
+use errors::{Error, Result as ExecResult};
+use escalate::{EscalationConfig, EscalationMethod};
+use handle_exec::HandleExec;
+use loader::Loader;
+use local_exec::LocalExec;
+use remote::RemoteConfig;
+use traits::Exec;
+use value::Value;
+
+const USAGE: &str = "\
+Usage:
+    idemsh run <script> [--forks N] [--hosts HOST,...] [--agent-command CMD]
+                        [--become-method sudo|su|CMD] [--ask-become-pass]
+                        [-e NAME=VALUE ...] [--force-handlers]
+                        [--include-path DIR ...]
+    idemsh agent [--root DIR]";
+
+struct RunOptions {
+    script: String,
+    forks: usize,
+    hosts: Vec<String>,
+    agent_command: String,
+    become_method: EscalationMethod,
+    ask_become_pass: bool,
+    /// Variables given with `-e`, in place of what the script binds them to.
+    overrides: Vec<(String, String)>,
+    force_handlers: bool,
+    /// Where included and imported files are looked for, then in `$IDEMSH_PATH`.
+    include_path: Vec<PathBuf>,
+}
+
+fn parse_run_options(args: &[String]) -> ExecResult<RunOptions> {
+    let mut script = None;
+    let mut opts = RunOptions {
+        script: String::new(),
+        forks: remote::DEFAULT_FORKS,
+        hosts: vec![],
+        agent_command: env::var("IDEMSH_AGENT_COMMAND").unwrap_or_else(|_| DEFAULT_AGENT_COMMAND.to_string()),
+        become_method: EscalationMethod::Sudo,
+        ask_become_pass: false,
+        overrides: vec![],
+        force_handlers: false,
+        include_path: vec![],
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned()
+            .ok_or_else(|| Error::message(format!("{} expects a value", arg)));
+
+        match arg.as_str() {
+            "--forks" | "-f" => {
+                opts.forks = value()?.parse()
+                    .map_err(|_| Error::message("--forks expects a number"))?;
+            }
+            "--hosts" => opts.hosts.extend(value()?.split(',').map(|h| h.to_string())),
+            "--agent-command" => opts.agent_command = value()?,
+            "--become-method" => opts.become_method = EscalationMethod::parse(&value()?),
+            "--ask-become-pass" | "-K" => opts.ask_become_pass = true,
+            "--force-handlers" => opts.force_handlers = true,
+            "--include-path" | "-I" => opts.include_path.push(PathBuf::from(value()?)),
+            "--extra-var" | "-e" => {
+                let var = value()?;
+                match var.split_once('=') {
+                    Some((name, value)) if !name.is_empty() => opts.overrides.push((name.to_string(), value.to_string())),
+                    _ => return Err(Error::message(format!("{} expects NAME=value, got {:?}", arg, var))),
+                }
+            }
+            _ if script.is_none() && !arg.starts_with('-') => script = Some(arg.to_string()),
+            _ => return Err(Error::message(format!("Unexpected argument: {}", arg))),
+        }
+    }
+
+    opts.script = script.ok_or_else(|| Error::message("No script given"))?;
+    if let Some(path) = env::var_os("IDEMSH_PATH") {
+        opts.include_path.extend(env::split_paths(&path));
+    }
+    Ok(opts)
+}
+
+/// Reads a password from the terminal, or from stdin when there is none.
+fn prompt_password(host: &str) -> ExecResult<String> {
+    eprint!("BECOME password for {}: ", host);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    match fs::File::open("/dev/tty") {
+        Ok(tty) => BufReader::new(tty).read_line(&mut line)?,
+        Err(_) => io::stdin().read_line(&mut line)?,
+    };
+    eprintln!();
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Runs a script, adding the name and source of each file it reads to `files` so
+/// errors can show where they were raised.
+fn run(args: &[String], files: &mut Vec<(String, String)>) -> ExecResult<()> {
+    let opts = parse_run_options(args)?;
+    let script = Loader::new(files, opts.include_path).load(&opts.script)?;
+    let names = files.iter().map(|(name, _)| name.to_string()).collect();
+
+    let agent_command = opts.agent_command;
+    let remote = RemoteConfig {
+        connector: Some(Arc::new(move |host: &str| {
+            Ok(Box::new(AgentExec::spawn(&agent_command, host)?) as Box<dyn Exec + Send>)
+        })),
+        inventory: opts.hosts,
+        forks: opts.forks,
+        escalation: EscalationConfig::new(
+            opts.become_method,
+            if opts.ask_become_pass { Some(Arc::new(prompt_password)) } else { None },
+        ),
+    };
+
+    // The first Ctrl-C stops the script before its next statement, so `with` blocks
+    // still remove their temporary files, and a second one exits at once
+    ctrlc::set_handler(|| {
+        if handle_exec::interrupt() {
+            process::exit(130);
+        }
+    }).map_err(|e| Error::message(format!("Unable to handle Ctrl-C: {}", e)))?;
+
+    let mut local_exec = LocalExec::default();
+    let env = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, Value::String(value.into_string().ok()?))))
+        .collect();
+    let mut handle_exec = HandleExec::new(&mut local_exec)
+        .with_remote_config(remote)
+        .with_var("ENV", Value::Map(env))
+        .with_files(names)
+        .with_force_handlers(opts.force_handlers);
+    for (name, value) in opts.overrides {
+        handle_exec = handle_exec.with_override(&name, value);
+    }
+    handle_exec.execute_raw_script(&script)
+}
+
+fn agent(args: &[String]) -> ExecResult<()> {
+    let mut local_exec = match args {
+        [] => LocalExec::default(),
+        [flag, root] if flag == "--root" => LocalExec::with_new_relative_working_dir(root),
+        _ => return Err(Error::message(USAGE)),
+    };
+
+    let stdin = io::stdin();
+    agent::serve(&mut BufReader::new(stdin.lock()), &mut io::stdout(), &mut local_exec)?;
+    Ok(())
+}
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut files = vec![];
+    let result = match args.first().map(|s| s.as_str()) {
+        Some("run") => run(&args[1..], &mut files),
+        Some("agent") => agent(&args[1..]),
+        _ => Err(Error::message(USAGE)),
+    };
+
+    if let Err(e) = result {
+        eprint!("idemsh: {}", e.render(&files));
+        process::exit(1);
+    }
 }
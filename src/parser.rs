@@ -1,10 +1,81 @@
 
+use std::cell::RefCell;
+
 use nom::types::CompleteStr;
-use nom::anychar;
+use nom::{digit, multispace, space, ErrorKind, IResult};
 
-#[macro_use]
 use super::ast::*;
 
+/// The words and block of a `defblock`, which its invocations must match.
+#[derive(Debug, Clone)]
+struct BlockSignature {
+    words: Vec<IdemBlockWord>,
+    takes_block: bool,
+}
+
+/// The keywords introduced by `defblock`, the last one of a name winning.
+#[derive(Debug, Clone, Default)]
+pub struct BlockKeywords(Vec<(String, BlockSignature)>);
+
+impl BlockKeywords {
+    /// Adds the keywords of `other`, such as those of an included file.
+    pub fn extend(&mut self, other: &BlockKeywords) {
+        self.0.extend(other.0.iter().cloned());
+    }
+
+    /// The keywords as called from a file importing them as `namespace`.
+    pub fn qualified(&self, namespace: &str) -> BlockKeywords {
+        BlockKeywords(self.0.iter().map(|(name, signature)| (format!("{}.{}", namespace, name), signature.clone())).collect())
+    }
+
+    fn get(&self, name: &str) -> Option<&BlockSignature> {
+        self.0.iter().rev().find(|(n, _)| n == name).map(|(_, signature)| signature)
+    }
+}
+
+/// The file being parsed by a `FileParser`, with the offset each of its lines starts at.
+struct Source {
+    file: usize,
+    start: usize,
+    len: usize,
+    lines: Vec<usize>,
+    text: String,
+}
+
+thread_local! {
+    // Keywords introduced by `defblock`, recognised from then on in the file being parsed.
+    static BLOCK_KEYWORDS: RefCell<BlockKeywords> = RefCell::new(BlockKeywords::default());
+    static SOURCE: RefCell<Option<Source>> = const { RefCell::new(None) };
+}
+
+/// Where `text`, a slice of the file being parsed, is in it. Text parsed from anything
+/// else, such as an interpolation, has an empty span.
+fn span_of(text: &str) -> Span {
+    SOURCE.with(|source| match *source.borrow() {
+        Some(ref source) => {
+            let start = (text.as_ptr() as usize).wrapping_sub(source.start);
+            if start > source.len || start + text.len() > source.len {
+                return Span::default();
+            }
+            let line = source.lines.partition_point(|&offset| offset <= start);
+            let line_start = source.lines[line - 1];
+            let column = source.text[line_start..start].chars().count() + 1;
+            Span { file: source.file, start, end: start + text.len(), line, column }
+        }
+        None => Span::default(),
+    })
+}
+
+/// Runs `parser`, recording where the node it returns is, without the whitespace
+/// around it.
+fn spanned<'a, T, F>(input: CompleteStr<'a>, parser: F) -> IResult<CompleteStr<'a>, Spanned<T>>
+    where F: Fn(CompleteStr<'a>) -> IResult<CompleteStr<'a>, T>
+{
+    let (rest, node) = parser(input)?;
+    let text = input.0[..input.len() - rest.len()].trim();
+    Ok((rest, Spanned::new(node, span_of(text))))
+}
+
 named!(parse_identifier<CompleteStr, CompleteStr>,
     recognize!(
         do_parse!(
@@ -15,13 +86,35 @@ named!(parse_identifier<CompleteStr, CompleteStr>,
     )
 );
 
+named!(parse_path_char<CompleteStr, char>,
+    one_of!("_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.\\/")
+);
+
+named!(parse_reserved_word<CompleteStr, CompleteStr>,
+    terminated!(
+        alt_complete!(tag!("end") | tag!("else") | tag!("rescue") | tag!("always")),
+        not!(peek!(parse_path_char))
+    )
+);
+
+// A keyword, which must not be followed by more of a word or path.
+named_args!(parse_keyword<'a>(keyword: &'a str)<CompleteStr<'a>, CompleteStr<'a>>,
+    terminated!(
+        tag!(keyword),
+        not!(peek!(parse_path_char))
+    )
+);
+
 named!(parse_path<CompleteStr, IdemPath>,
     do_parse!(
-        s: many1!(one_of!("_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.\\/")) >>
+        not!(parse_reserved_word) >>
+        s: alt_complete!(
+            // A path relative to the value of a variable, `$dir/file`
+            recognize!(tuple!(tag!("$"), parse_identifier, many0!(parse_path_char))) |
+            recognize!(many1!(parse_path_char))
+        ) >>
         ({
-            let s: String = s.into_iter().collect();
-            eprintln!("Got path: '{}'", s);
-
+            let s = s.to_string();
             if s.ends_with("/") {
                 IdemPath(None, IdemPathLocalPartType::Directory(s.trim_end_matches('/').to_string()))
             } else {
@@ -31,8 +124,39 @@ named!(parse_path<CompleteStr, IdemPath>,
     )
 );
 
+// `~~name/` is a temporary directory and `~~name` a temporary file, whose names end with `-name`.
+named!(parse_resource_tilde<CompleteStr, IdemResourceType>,
+    do_parse!(
+        tag!("~~") >>
+        name: recognize!(many1!(one_of!("_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.-"))) >>
+        dir: opt!(tag!("/")) >>
+        ({
+            let mut params = vec![IdemParamType::KeyValue("suffix".to_string(), IdemValueType::LitString(format!("-{}", name.0)).into()).into()];
+            if dir.is_some() {
+                params.insert(0, IdemParamType::FlagKeyword("dir".to_string()).into());
+            }
+            IdemResourceType::Temporary(params)
+        })
+    )
+);
+
+// `(( temporary(dir suffix = "build") ))`, the params may be separated by commas.
+named!(parse_resource_temporary<CompleteStr, IdemResourceType>,
+    do_parse!(
+        ws!(tag!("((")) >>
+        call!(parse_keyword, "temporary") >>
+        ws!(tag!("(")) >>
+        params: many0!(terminated!(parse_param, opt!(ws!(tag!(","))))) >>
+        ws!(tag!(")")) >>
+        tag!("))") >>
+        (IdemResourceType::Temporary(params))
+    )
+);
+
 named!(parse_resource<CompleteStr, IdemResourceType>,
     alt_complete!(
+        parse_resource_tilde |
+        parse_resource_temporary |
         map!(parse_path, |IdemPath(_, s)| {
             match s {
                 IdemPathLocalPartType::File(s) => IdemResourceType::File(s),
@@ -51,77 +175,1090 @@ named!(parse_value_litstring<CompleteStr, IdemValueType>,
     )
 );
 
-named!(parse_value_path_spec<CompleteStr, IdemValueType>,
-    do_parse!(
-        path: ws!(parse_path) >>
-        (IdemValueType::PathSpec(path))
-    )
+// `"""..."""`, a string that can contain quotes and span lines, which are dedented.
+named!(parse_value_extended_string<CompleteStr, IdemValueType>,
+    do_parse!(
+        opt!(call!(nom::multispace)) >>
+        tag!("\"\"\"") >>
+        value: take_until!("\"\"\"") >>
+        tag!("\"\"\"") >>
+        (IdemValueType::ExtendedString(match value.0.trim_end_matches([' ', '\t']) {
+            text if text.contains('\n') => dedent(text),
+            text => text.to_string(),
+        }))
+    )
+);
+
+named!(parse_value_path_spec<CompleteStr, IdemValueType>,
+    do_parse!(
+        path: ws!(call!(spanned, parse_path)) >>
+        (IdemValueType::PathSpec(path))
+    )
+);
+
+// The number must end before any whitespace after it, so `2` ending a line is not
+// taken as the start of a path on the next.
+named!(parse_value_integer<CompleteStr, IdemValueType>,
+    do_parse!(
+        opt!(multispace) >>
+        peek!(pair!(opt!(tag!("-")), digit)) >>
+        // A number out of range fails the parse rather than being taken as a path
+        n: return_error!(ErrorKind::Custom(1),
+            map_res!(recognize!(pair!(opt!(tag!("-")), digit)), |n: CompleteStr| n.0.parse::<i64>())
+        ) >>
+        not!(peek!(parse_path_char)) >>
+        opt!(multispace) >>
+        (IdemValueType::Integer(n))
+    )
+);
+
+named!(parse_value_boolean<CompleteStr, IdemValueType>,
+    alt_complete!(
+        value!(IdemValueType::Boolean(true), ws!(call!(parse_keyword, "true"))) |
+        value!(IdemValueType::Boolean(false), ws!(call!(parse_keyword, "false")))
+    )
+);
+
+named!(parse_accessor<CompleteStr, IdemAccessor>,
+    alt_complete!(
+        map!(preceded!(tag!("."), parse_identifier), |key| IdemAccessor::Key(key.to_string())) |
+        map!(
+            delimited!(
+                tag!("["),
+                map_res!(recognize!(pair!(opt!(tag!("-")), digit)), |n: CompleteStr| n.0.parse()),
+                tag!("]")
+            ),
+            IdemAccessor::Index
+        )
+    )
+);
+
+// A variable with its accessors, `name.key[0]`, as written inside `{{ }}`.
+named!(parse_variable<CompleteStr, IdemValueType>,
+    do_parse!(
+        name: parse_identifier >>
+        accessors: many0!(parse_accessor) >>
+        (IdemValueType::Variable(name.to_string(), accessors))
+    )
+);
+
+named!(parse_value_variable<CompleteStr, IdemValueType>,
+    terminated!(
+        preceded!(ws!(tag!("$")), parse_variable),
+        not!(peek!(one_of!("/\\")))
+    )
+);
+
+named!(parse_value_empty<CompleteStr, IdemValueType>,
+    value!(IdemValueType::Empty, ws!(tuple!(tag!("(("), tag!("))"))))
+);
+
+// A command is tried first so that `(( ./file (wc) ))` is not taken as a path.
+named!(parse_value_command<CompleteStr, IdemValueType>,
+    delimited!(
+        ws!(tag!("((")),
+        alt_complete!(
+            map!(ws!(parse_raw_command), |cmd| IdemValueType::Command(Box::new(cmd))) |
+            ws!(parse_value_node)
+        ),
+        tag!("))")
+    )
+);
+
+named!(parse_value_list<CompleteStr, IdemValueType>,
+    do_parse!(
+        ws!(tag!("[")) >>
+        values: separated_list!(ws!(tag!(",")), ws!(parse_value)) >>
+        opt!(ws!(tag!(","))) >>
+        tag!("]") >>
+        (IdemValueType::List(values))
+    )
+);
+
+named!(parse_map_key<CompleteStr, String>,
+    alt_complete!(
+        map!(parse_identifier, |key| key.to_string()) |
+        map!(parse_value_litstring, |key| match key {
+            IdemValueType::LitString(key) => key,
+            _ => unreachable!(),
+        })
+    )
+);
+
+named!(parse_value_map<CompleteStr, IdemValueType>,
+    do_parse!(
+        ws!(tag!("{")) >>
+        entries: separated_list!(
+            ws!(tag!(",")),
+            do_parse!(
+                key: ws!(parse_map_key) >>
+                ws!(tag!("=")) >>
+                value: ws!(parse_value) >>
+                ((key, value))
+            )
+        ) >>
+        opt!(ws!(tag!(","))) >>
+        tag!("}") >>
+        (IdemValueType::Map(entries))
+    )
+);
+
+named!(parse_value_node<CompleteStr, IdemValueType>,
+    alt_complete!(
+        parse_value_extended_string |
+        parse_value_litstring |
+        parse_value_integer |
+        parse_value_boolean |
+        parse_value_empty |
+        parse_value_command |
+        parse_value_list |
+        parse_value_map |
+        parse_value_variable |
+        parse_value_path_spec
+    )
+);
+
+pub fn parse_value(input: CompleteStr) -> IResult<CompleteStr, Spanned<IdemValueType>> {
+    spanned(input, parse_value_node)
+}
+
+// The contents of a `{{ }}` interpolation, a variable or any other value.
+named!(pub parse_interpolation<CompleteStr, Spanned<IdemValueType>>,
+    ws!(alt_complete!(
+        terminated!(call!(spanned, parse_variable), eof!()) |
+        terminated!(parse_value, eof!())
+    ))
+);
+
+named!(parse_param_key_value<CompleteStr, IdemParamType>,
+    do_parse!(
+        key: ws!(parse_identifier) >>
+        ws!(tag!("=")) >>
+        value: ws!(parse_value) >>
+        (IdemParamType::KeyValue(key.to_string(), value))
+    )
+);
+
+named!(parse_param_flag_keyword<CompleteStr, IdemParamType>,
+    map!(ws!(parse_identifier), |s| IdemParamType::FlagKeyword(s.to_string()))
+);
+
+named!(parse_compare_op<CompleteStr, IdemCompareOp>,
+    alt_complete!(
+        value!(IdemCompareOp::Eq, tag!("==")) |
+        value!(IdemCompareOp::Ne, tag!("!=")) |
+        value!(IdemCompareOp::Le, tag!("<=")) |
+        value!(IdemCompareOp::Ge, tag!(">=")) |
+        value!(IdemCompareOp::Lt, tag!("<")) |
+        value!(IdemCompareOp::Gt, tag!(">"))
+    )
+);
+
+/// `not <condition>`, `<path> exists`, `<value> <op> <value>` or a value.
+fn parse_condition_unary(input: CompleteStr) -> IResult<CompleteStr, IdemCondition> {
+    if let Ok((rest, _)) = ws!(input, call!(parse_keyword, "not")) {
+        let (rest, condition) = parse_condition_unary(rest)?;
+        return Ok((rest, IdemCondition::Not(Box::new(condition))));
+    }
+
+    let (rest, left) = ws!(input, parse_value)?;
+    if let Ok((rest, _)) = preceded!(rest, opt!(space), call!(parse_keyword, "exists")) {
+        return Ok((rest, IdemCondition::Exists(left)));
+    }
+    if let Ok((rest, op)) = preceded!(rest, opt!(space), parse_compare_op) {
+        let (rest, right) = ws!(rest, parse_value)?;
+        return Ok((rest, IdemCondition::Compare(left, op, right)));
+    }
+    Ok((rest, IdemCondition::Value(left)))
+}
+
+/// Conditions joined by `keyword`, which binds to the left.
+fn parse_condition_chain<'a>(
+    input: CompleteStr<'a>,
+    keyword: &'a str,
+    operand: fn(CompleteStr<'a>) -> IResult<CompleteStr<'a>, IdemCondition>,
+    join: fn(Box<IdemCondition>, Box<IdemCondition>) -> IdemCondition,
+) -> IResult<CompleteStr<'a>, IdemCondition> {
+    let (mut rest, mut condition) = operand(input)?;
+    while let Ok((r, right)) = preceded!(rest, preceded!(opt!(space), call!(parse_keyword, keyword)), operand) {
+        condition = join(Box::new(condition), Box::new(right));
+        rest = r;
+    }
+    Ok((rest, condition))
+}
+
+fn parse_condition_and(input: CompleteStr) -> IResult<CompleteStr, IdemCondition> {
+    parse_condition_chain(input, "and", parse_condition_unary, IdemCondition::And)
+}
+
+// `and` binds tighter than `or`, and both must be on the line of the first operand.
+pub fn parse_condition(input: CompleteStr) -> IResult<CompleteStr, IdemCondition> {
+    parse_condition_chain(input, "or", parse_condition_and, IdemCondition::Or)
+}
+
+// `when = <condition>` runs the statement only if the condition is true, `unless = ` only
+// if it is false. `unless = "<command>"` stays the shell command of a `using` block.
+named!(parse_param_when<CompleteStr, IdemParamType>,
+    do_parse!(
+        negate: ws!(alt_complete!(
+            value!(false, call!(parse_keyword, "when")) |
+            value!(true, call!(parse_keyword, "unless"))
+        )) >>
+        ws!(tag!("=")) >>
+        condition: parse_condition >>
+        (match (negate, condition) {
+            (true, IdemCondition::Value(Spanned { node: IdemValueType::LitString(command), span })) => {
+                IdemParamType::KeyValue("unless".to_string(), Spanned::new(IdemValueType::LitString(command), span))
+            }
+            (true, condition) => IdemParamType::When(IdemCondition::Not(Box::new(condition))),
+            (false, condition) => IdemParamType::When(condition),
+        })
+    )
+);
+
+named!(parse_param_node<CompleteStr, IdemParamType>,
+    alt_complete!(
+        ws!(parse_param_when)
+        | ws!(parse_param_key_value)
+        | ws!(parse_param_flag_keyword)
+    )
+);
+
+fn parse_param(input: CompleteStr) -> IResult<CompleteStr, Spanned<IdemParamType>> {
+    spanned(input, parse_param_node)
+}
+
+// A bare word, as opposed to a path such as `file.txt`.
+named!(parse_flag_word<CompleteStr, IdemParamType>,
+    map!(
+        terminated!(parse_identifier, not!(peek!(parse_path_char))),
+        |s| IdemParamType::FlagKeyword(s.to_string())
+    )
+);
+
+named!(parse_short_flags<CompleteStr, IdemParamType>,
+    map!(
+        preceded!(tag!("-"), many1!(one_of!("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ"))),
+        IdemParamType::ShortFlags
+    )
+);
+
+// Guards follow the arguments of a call rather than being one of them.
+named!(parse_arg_node<CompleteStr, IdemParamType>,
+    preceded!(
+        not!(parse_param_when),
+        alt_complete!(
+            ws!(parse_param_key_value)
+            | ws!(parse_short_flags)
+            | ws!(parse_flag_word)
+            | map!(ws!(parse_value), IdemParamType::Positional)
+        )
+    )
+);
+
+fn parse_arg(input: CompleteStr) -> IResult<CompleteStr, Spanned<IdemParamType>> {
+    spanned(input, parse_arg_node)
+}
+
+// `command arg1="x" arg2="y"` or `command pos1, pos2`, the name and the first
+// argument are always separated by whitespace.
+// `web.deploy`, a command defined by a file imported as `web`.
+named!(parse_qualified_name<CompleteStr, CompleteStr>,
+    recognize!(pair!(parse_identifier, many1!(preceded!(tag!("."), parse_identifier))))
+);
+
+named!(parse_param_call<CompleteStr, IdemParamType>,
+    alt_complete!(
+        do_parse!(
+            name: alt_complete!(parse_qualified_name | parse_identifier) >>
+            space >>
+            args: many1!(terminated!(parse_arg, opt!(ws!(tag!(","))))) >>
+            (IdemParamType::Call(name.to_string(), args))
+        ) |
+        // An imported command is a call even without arguments
+        do_parse!(
+            name: terminated!(parse_qualified_name, not!(peek!(parse_path_char))) >>
+            opt!(ws!(tag!(","))) >>
+            (IdemParamType::Call(name.to_string(), vec![]))
+        )
+    )
+);
+
+named!(parse_call_args<CompleteStr, Vec<Spanned<IdemParamType>>>,
+    delimited!(
+        ws!(tag!("(")),
+        many0!(terminated!(alt_complete!(ws!(call!(spanned, parse_param_when)) | parse_arg), opt!(ws!(tag!(","))))),
+        ws!(tag!(")"))
+    )
+);
+
+named!(parse_params<CompleteStr, Vec<Spanned<IdemParamType>>>,
+    do_parse!(
+        ws!(tag!("(")) >>
+        params: alt_complete!(
+            map!(
+                pair!(
+                    ws!(call!(spanned, parse_param_call)),
+                    many0!(terminated!(ws!(call!(spanned, parse_param_when)), opt!(ws!(tag!(",")))))
+                ),
+                |(call, guards)| [vec![call], guards].concat()
+            ) |
+            separated_list!(ws!(tag!(",")), ws!(parse_param))
+        ) >>
+        ws!(tag!(")")) >>
+        (params)
+    )
+);
+
+named!(parse_raw_command_with_paths<CompleteStr, IdemRawCommandWithPaths>,
+    do_parse!(
+        paths: many1!(ws!(call!(spanned, parse_path))) >>
+        params: parse_params >>
+        ({
+            IdemRawCommandWithPaths {
+                paths,
+                params,
+            }
+        })
+    )
+);
+
+named!(parse_raw_statements<CompleteStr, Vec<Spanned<IdemRawCommandType>>>,
+    many0!(ws!(parse_raw_command))
+);
+
+// The statements of a block up to and including the closing `end`.
+named!(parse_raw_block<CompleteStr, Vec<Spanned<IdemRawCommandType>>>,
+    terminated!(parse_raw_statements, ws!(call!(parse_keyword, "end")))
+);
+
+named!(parse_host_pattern<CompleteStr, CompleteStr>,
+    recognize!(
+        many1!(one_of!("_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.-*?,@:"))
+    )
+);
+
+/// Removes the indentation shared by all non-blank lines, and any leading blank lines.
+fn dedent(text: &str) -> String {
+    let indent = text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    text.lines()
+        .skip_while(|l| l.trim().is_empty())
+        .map(|l| if l.len() >= indent { &l[indent..] } else { l.trim_start() })
+        .map(|l| format!("{}\n", l))
+        .collect()
+}
+
+/// Takes everything up to the `end` closing the block, without parsing it. The closing
+/// `end` is indented less than the first line of the block, so lines with only `end`
+/// in the body are kept, and a body that is not indented ends at its first `end`.
+fn parse_verbatim_block(input: CompleteStr) -> IResult<CompleteStr, String> {
+    let mut offset = 0;
+    let mut indent = None;
+    for line in input.0.split_inclusive('\n') {
+        let depth = line.len() - line.trim_start().len();
+        if line.trim() == "end" && indent.is_none_or(|indent| indent == 0 || depth < indent) {
+            let rest = CompleteStr(&input.0[offset + line.len()..]);
+            return Ok((rest, dedent(&input.0[..offset])));
+        }
+        if indent.is_none() && !line.trim().is_empty() {
+            indent = Some(depth);
+        }
+        offset += line.len();
+    }
+
+    Err(nom::Err::Error(error_position!(input, ErrorKind::Custom(0))))
+}
+
+// Whitespace is only skipped within the header line, the block's indentation is kept.
+named!(parse_using_header<CompleteStr, (String, Vec<Spanned<IdemParamType>>)>,
+    do_parse!(
+        ws!(call!(parse_keyword, "using")) >>
+        interpreter: parse_path >>
+        params: opt!(
+            preceded!(
+                opt!(space),
+                delimited!(
+                    tag!("("),
+                    separated_list!(ws!(tag!(",")), ws!(parse_param)),
+                    tag!(")")
+                )
+            )
+        ) >>
+        ({
+            let IdemPath(_, interpreter) = interpreter;
+            let interpreter = match interpreter {
+                IdemPathLocalPartType::File(s) | IdemPathLocalPartType::Directory(s) => s,
+            };
+            (interpreter, params.unwrap_or_default())
+        })
+    )
+);
+
+/// Splits `input` at the first `delimiter` not escaped with `\`, which is unescaped
+/// in the text before it unless `keep_escape` is set.
+fn split_delimited(input: &str, delimiter: char, keep_escape: bool) -> Option<(String, &str)> {
+    let mut text = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, next)) if next == delimiter && !keep_escape => text.push(next),
+                Some((_, next)) => {
+                    text.push(c);
+                    text.push(next);
+                }
+                None => text.push(c),
+            },
+            c if c == delimiter => return Some((text, &input[i + c.len_utf8()..])),
+            c => text.push(c),
+        }
+    }
+    None
+}
+
+/// Like `split_delimited`, but a delimiter that is special in a regexp stays escaped.
+fn split_regexp(input: &str, delimiter: char) -> Option<(String, &str)> {
+    split_delimited(input, delimiter, regex::escape(&delimiter.to_string()).len() > 1)
+}
+
+/// `s/regexp/replacement/flags` with any punctuation as the delimiter, after an
+/// optional `@#line#` or `/^regexp/` selector. The flags are `g` and `i`.
+fn parse_substitution(line: &str) -> Option<IdemEditCommandType> {
+    let line = line.trim();
+    let (selector, command) = match line.chars().next()? {
+        '@' => {
+            let delimiter = line[1..].chars().next()?;
+            let (text, rest) = split_delimited(&line[1 + delimiter.len_utf8()..], delimiter, false)?;
+            (Some(IdemLineSelector::Line(text.trim().to_string())), rest.trim_start())
+        }
+        '/' => {
+            let (regexp, rest) = split_regexp(&line[1..], '/')?;
+            (Some(IdemLineSelector::Regexp(regexp)), rest.trim_start())
+        }
+        _ => (None, line),
+    };
+
+    let delimiter = command.strip_prefix('s')?.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' {
+        return None;
+    }
+    let (regexp, rest) = split_regexp(&command[1 + delimiter.len_utf8()..], delimiter)?;
+    let (replacement, flags) = split_delimited(rest, delimiter, false)?;
+    if !flags.chars().all(|flag| flag == 'g' || flag == 'i') {
+        return None;
+    }
+
+    let regexp = if flags.contains('i') { format!("(?i){}", regexp) } else { regexp };
+    Some(IdemEditCommandType::Replace(selector, IdemReplace { regexp, replacement, global: flags.contains('g') }))
+}
+
+/// The `(/regexp/, insertafter = /regexp/)` options ending a `$+` or `$-` line.
+fn parse_line_options(input: &str) -> Option<(Option<String>, IdemLineInsert)> {
+    let mut rest = input.strip_prefix('(')?.trim_start();
+    let mut regexp = None;
+    let mut insert = IdemLineInsert::End;
+    loop {
+        if let Some(after) = rest.strip_prefix('/') {
+            let (found, after) = split_regexp(after, '/')?;
+            regexp = Some(found);
+            rest = after;
+        } else {
+            let (key, value) = rest.split_once('=')?;
+            let value = value.trim_start();
+            let (position, after) = match (key.trim(), value.strip_prefix('/')) {
+                ("insertafter", Some(value)) => split_regexp(value, '/').map(|(found, after)| (IdemLineInsert::After(found), after))?,
+                ("insertbefore", Some(value)) => split_regexp(value, '/').map(|(found, after)| (IdemLineInsert::Before(found), after))?,
+                ("insertafter", None) => (IdemLineInsert::End, value.strip_prefix("EOF")?),
+                ("insertbefore", None) => (IdemLineInsert::Start, value.strip_prefix("BOF")?),
+                _ => return None,
+            };
+            insert = position;
+            rest = after;
+        }
+
+        rest = rest.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after.trim_start(),
+            None if rest == ")" => return Some((regexp, insert)),
+            None => return None,
+        }
+    }
+}
+
+/// `$+ line (<options>)` or `$- line (<options>)`, the options are the first
+/// parenthesized group that parses up to the end of the line.
+fn parse_line_in_file(line: &str) -> Option<IdemEditCommandType> {
+    let line = line.trim_start();
+    let present = match line.get(..2)? {
+        "$+" => true,
+        "$-" => false,
+        _ => return None,
+    };
+    let text = line[2..].strip_prefix(' ').unwrap_or(&line[2..]).trim_end();
+
+    let (text, regexp, insert) = text.match_indices('(')
+        .filter(|&(i, _)| i == 0 || text[..i].ends_with(' '))
+        .find_map(|(i, _)| parse_line_options(&text[i..]).map(|(regexp, insert)| (text[..i].trim_end(), regexp, insert)))
+        .unwrap_or((text, None, IdemLineInsert::End));
+    if text.trim().is_empty() && (present || regexp.is_none()) {
+        return None;
+    }
+
+    Some(IdemEditCommandType::LineInFile(IdemLineInFile { line: text.to_string(), present, regexp, insert }))
+}
+
+/// A line that is not context, other than `+` and `-` lines.
+fn parse_edit_line(line: &str) -> Option<IdemEditCommandType> {
+    parse_line_in_file(line).or_else(|| parse_substitution(line))
+}
+
+/// `@/pointer` lines followed by a value to merge, `@/pointer = value` or
+/// `@/pointer (removed)`.
+fn parse_json_edits(body: &str) -> Vec<IdemJsonEdit> {
+    let mut edits = vec![];
+    let mut current: Option<(String, bool, String)> = None;
+    let finish = |current: Option<(String, bool, String)>, edits: &mut Vec<IdemJsonEdit>| {
+        if let Some((pointer, set, value)) = current {
+            edits.push(if set { IdemJsonEdit::Set(pointer, value) } else { IdemJsonEdit::Merge(pointer, value) });
+        }
+    };
+
+    for line in body.lines() {
+        let header = line.trim_start();
+        if !header.starts_with("@/") {
+            if let Some((_, _, ref mut value)) = current {
+                value.push_str(line);
+                value.push('\n');
+            }
+            continue;
+        }
+
+        finish(current.take(), &mut edits);
+        let (pointer, rest) = header[1..].split_at(header[1..].find(char::is_whitespace).unwrap_or(header.len() - 1));
+        let rest = rest.trim();
+        match rest.strip_prefix('=') {
+            _ if rest == "(removed)" => edits.push(IdemJsonEdit::Remove(pointer.to_string())),
+            Some(value) => current = Some((pointer.to_string(), true, format!("{}\n", value.trim()))),
+            None => current = Some((pointer.to_string(), false, format!("{}\n", rest))),
+        }
+    }
+    finish(current, &mut edits);
+
+    edits
+}
+
+/// A body starting with a JSON pointer edits a JSON document. A body with
+/// substitutions or `$+` and `$-` lines, or any body with `lines`, is a line edit,
+/// otherwise it is a template of blocks, which may well have lines starting with
+/// `+` or `-`. In a line edit `+` lines are added after the line before them, or
+/// before the first context line when they come first.
+fn parse_edit_body(body: &str, lines: bool) -> IdemEditBody {
+    if !lines {
+        let first = body.lines().find(|line| !line.trim().is_empty()).unwrap_or("");
+        if first.trim_start().starts_with("@/") && parse_substitution(first).is_none() {
+            return IdemEditBody::Json(parse_json_edits(body));
+        }
+        if !body.lines().any(|line| parse_edit_line(line).is_some()) {
+            return IdemEditBody::Blocks(body.to_string());
+        }
+    }
+
+    // The text after a marker, without the space separating it
+    let text = |line: &str| {
+        let line = &line.trim_start()[1..];
+        line.strip_prefix(' ').unwrap_or(line).to_string()
+    };
+
+    let mut commands = vec![];
+    let mut previous: Option<String> = None;
+    let mut leading = vec![];
+    for line in body.lines().filter(|line| !line.trim().is_empty()) {
+        match line.trim_start().chars().next() {
+            Some('+') => match previous {
+                Some(ref anchor) => {
+                    commands.push(IdemEditCommandType::InsertAfter(anchor.to_string(), text(line)));
+                    previous = Some(text(line));
+                }
+                None => leading.push(text(line)),
+            },
+            Some('-') => commands.push(IdemEditCommandType::Remove(text(line))),
+            _ => match parse_edit_line(line) {
+                Some(command) => commands.push(command),
+                None => {
+                    let context = line.trim().to_string();
+                    commands.extend(leading.drain(..).map(|l| IdemEditCommandType::InsertBefore(context.to_string(), l)));
+                    commands.push(IdemEditCommandType::Context(context.to_string()));
+                    previous = Some(context);
+                }
+            },
+        }
+    }
+    commands.extend(leading.into_iter().map(IdemEditCommandType::InsertEnd));
+
+    IdemEditBody::Lines(IdemEdit { commands })
+}
+
+/// `edit = lines`, which reads the body as a diff.
+fn is_line_edit(param: &IdemParamType) -> bool {
+    match *param {
+        IdemParamType::KeyValue(ref key, ref value) => key == "edit" && match value.node {
+            IdemValueType::PathSpec(ref path) => path.node == IdemPath(None, IdemPathLocalPartType::File("lines".to_string())),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Like the `using` header, the params end the line so the template keeps its indentation.
+named!(parse_raw_command_edit<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(call!(parse_keyword, "with")) >>
+        path: call!(spanned, parse_path) >>
+        opt!(space) >>
+        params: delimited!(
+            tag!("("),
+            separated_list!(ws!(tag!(",")), ws!(parse_param)),
+            tag!(")")
+        ) >>
+        lines: value!(params.iter().any(|param| is_line_edit(param))) >>
+        cond_reduce!(lines || params.contains(&IdemParamType::FlagKeyword("edit".to_string()).into()), take!(0)) >>
+        body: map!(parse_verbatim_block, |body| parse_edit_body(&body, lines)) >>
+        (IdemRawCommandType::Edit(IdemRawCommandEdit { path, params, body }))
+    )
+);
+
+/// Takes the text between a `{` and its matching `}`.
+fn parse_balanced_braces(input: CompleteStr) -> IResult<CompleteStr, String> {
+    if !input.0.starts_with('{') {
+        return Err(nom::Err::Error(error_position!(input, ErrorKind::Custom(0))));
+    }
+
+    let mut depth = 0;
+    for (i, c) in input.0.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok((CompleteStr(&input.0[i + 1..]), dedent(input.0[1..i].trim_matches(|c| c == ' ' || c == '\t'))));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Err(nom::Err::Error(error_position!(input, ErrorKind::Custom(0))))
+}
+
+// `<file> (edit = { ... })`, the same edit as a `with <file> (edit)` block.
+named!(parse_raw_command_edit_param<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        path: ws!(call!(spanned, parse_path)) >>
+        ws!(tag!("(")) >>
+        ws!(tag!("edit")) >>
+        ws!(tag!("=")) >>
+        body: parse_balanced_braces >>
+        params: many0!(preceded!(ws!(tag!(",")), ws!(parse_param))) >>
+        ws!(tag!(")")) >>
+        ({
+            let mut params = params;
+            params.insert(0, IdemParamType::FlagKeyword("edit".to_string()).into());
+            IdemRawCommandType::Edit(IdemRawCommandEdit { path, params, body: parse_edit_body(&body, false) })
+        })
+    )
+);
+
+named!(parse_raw_command_using<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        header: parse_using_header >>
+        script: parse_verbatim_block >>
+        (IdemRawCommandType::Using(IdemRawCommandUsing {
+            interpreter: header.0,
+            params: header.1,
+            script,
+        }))
+    )
+);
+
+/// Takes the text between a `(` and its matching `)`, skipping parens in quotes.
+fn parse_balanced_parens(input: CompleteStr) -> IResult<CompleteStr, String> {
+    if !input.0.starts_with('(') {
+        return Err(nom::Err::Error(error_position!(input, ErrorKind::Custom(0))));
+    }
+
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in input.0.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (_, '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok((CompleteStr(&input.0[i + 1..]), input.0[1..i].trim().to_string()));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Err(nom::Err::Error(error_position!(input, ErrorKind::Custom(0))))
+}
+
+named!(parse_raw_command_system<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        command: alt_complete!(
+            map!(
+                preceded!(terminated!(call!(parse_keyword, "system"), opt!(space)), parse_balanced_parens),
+                |command| (command, true)
+            ) |
+            map!(
+                delimited!(tag!("$("), parse_balanced_parens, tag!(")")),
+                |command| (command, false)
+            )
+        ) >>
+        params: opt!(parse_params) >>
+        (IdemRawCommandType::System(IdemRawCommandSystem {
+            command: command.0,
+            capture: command.1,
+            params: params.unwrap_or_default(),
+        }))
+    )
+);
+
+named!(parse_def_param_name<CompleteStr, String>,
+    map!(
+        alt_complete!(recognize!(preceded!(tag!("-"), one_of!("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ"))) | parse_identifier),
+        |name| name.to_string()
+    )
+);
+
+named!(parse_def_param<CompleteStr, IdemDefParam>,
+    alt_complete!(
+        do_parse!(
+            tag!("...") >>
+            name: parse_identifier >>
+            (IdemDefParam { names: vec![name.to_string()], default: None, splat: true })
+        ) |
+        do_parse!(
+            names: alt_complete!(
+                delimited!(
+                    tag!("("),
+                    separated_nonempty_list!(ws!(tag!("|")), ws!(parse_def_param_name)),
+                    tag!(")")
+                ) |
+                map!(parse_identifier, |name| vec![name.to_string()])
+            ) >>
+            default: opt!(preceded!(ws!(tag!("=")), parse_value)) >>
+            (IdemDefParam { names, default, splat: false })
+        )
+    )
+);
+
+named!(parse_def_param_list<CompleteStr, Vec<IdemDefParam>>,
+    terminated!(
+        separated_list!(ws!(tag!(",")), ws!(parse_def_param)),
+        opt!(ws!(tag!(",")))
+    )
+);
+
+named!(parse_def_params<CompleteStr, IdemDefParams>,
+    delimited!(
+        ws!(tag!("(")),
+        alt_complete!(
+            map!(delimited!(ws!(tag!("{")), parse_def_param_list, ws!(tag!("}"))), IdemDefParams::Keyword) |
+            map!(parse_def_param_list, IdemDefParams::Positional)
+        ),
+        ws!(tag!(")"))
+    )
+);
+
+named!(parse_raw_command_def<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(call!(parse_keyword, "def")) >>
+        name: parse_identifier >>
+        params: opt!(parse_def_params) >>
+        statements: parse_raw_block >>
+        (IdemRawCommandType::Def(IdemRawCommandDef {
+            name: name.to_string(),
+            params: params.unwrap_or(IdemDefParams::Positional(vec![])),
+            statements,
+        }))
+    )
+);
+
+named!(parse_raw_command_handler<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(call!(parse_keyword, "handler")) >>
+        name: parse_identifier >>
+        statements: parse_raw_block >>
+        (IdemRawCommandType::Handler(IdemRawCommandHandler { name: name.to_string(), statements }))
+    )
+);
+
+// The value must be on the same line, a bare `return` returns `(())`.
+named!(parse_raw_command_return<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(call!(parse_keyword, "return")) >>
+        value: opt!(preceded!(
+            pair!(opt!(space), not!(peek!(one_of!("\r\n")))),
+            parse_value
+        )) >>
+        (IdemRawCommandType::Return(value.unwrap_or_else(|| IdemValueType::Empty.into())))
+    )
+);
+
+named!(parse_block_word<CompleteStr, IdemBlockWord>,
+    alt_complete!(
+        map!(parse_value_litstring, |tag| match tag {
+            IdemValueType::LitString(tag) => IdemBlockWord::Tag(tag),
+            _ => unreachable!(),
+        }) |
+        map!(ws!(parse_identifier), |name| IdemBlockWord::Param(name.to_string()))
+    )
+);
+
+named!(parse_raw_command_defblock<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(call!(parse_keyword, "defblock")) >>
+        name: parse_identifier >>
+        words: opt!(delimited!(ws!(tag!("[")), many0!(parse_block_word), ws!(tag!("]")))) >>
+        params: opt!(parse_def_params) >>
+        takes_block: map!(opt!(ws!(call!(parse_keyword, "do"))), |d| d.is_some()) >>
+        statements: parse_raw_block >>
+        ({
+            let words = words.unwrap_or_default();
+            let signature = BlockSignature { words: words.clone(), takes_block };
+            BLOCK_KEYWORDS.with(|keywords| keywords.borrow_mut().0.push((name.to_string(), signature)));
+
+            IdemRawCommandType::DefBlock(IdemRawCommandDefBlock {
+                name: name.to_string(),
+                words,
+                params: params.unwrap_or(IdemDefParams::Positional(vec![])),
+                takes_block,
+                statements,
+            })
+        })
+    )
+);
+
+// A word given for a param of a block command, bare words are strings.
+named!(parse_block_call_word<CompleteStr, Spanned<IdemValueType>>,
+    alt_complete!(
+        map!(ws!(call!(spanned, parse_flag_word)), |word| match word {
+            Spanned { node: IdemParamType::FlagKeyword(word), span } => Spanned::new(IdemValueType::LitString(word), span),
+            _ => unreachable!(),
+        }) |
+        ws!(parse_value)
+    )
+);
+
+// `deploy`, or `web.deploy` for a block command imported as `web`.
+named!(parse_block_name<CompleteStr, CompleteStr>,
+    terminated!(alt_complete!(parse_qualified_name | parse_identifier), not!(peek!(parse_path_char)))
 );
 
-named!(parse_value<CompleteStr, IdemValueType>,
-    alt_complete!(
-        parse_value_litstring |
-        parse_value_path_spec
+/// An invocation of a block command defined earlier with `defblock`, or imported.
+fn parse_raw_command_block_call(input: CompleteStr) -> IResult<CompleteStr, IdemRawCommandType> {
+    let (mut rest, name) = ws!(input, parse_block_name)?;
+    let name = name.to_string();
+    let signature = BLOCK_KEYWORDS.with(|keywords| keywords.borrow().get(&name).cloned())
+        .ok_or(nom::Err::Error(error_position!(input, ErrorKind::Custom(0))))?;
+
+    let mut words = vec![];
+    for word in &signature.words {
+        match word {
+            IdemBlockWord::Tag(tag) => {
+                let trimmed = rest.0.trim_start();
+                match trimmed.strip_prefix(tag.as_str()) {
+                    Some(r) if parse_path_char(CompleteStr(r)).is_err() => rest = CompleteStr(r),
+                    _ => return Err(nom::Err::Error(error_position!(rest, ErrorKind::Custom(0)))),
+                }
+            }
+            IdemBlockWord::Param(_) => {
+                let (r, value) = parse_block_call_word(rest)?;
+                words.push(value);
+                rest = r;
+            }
+        }
+    }
+
+    let (rest, args) = opt!(rest, parse_call_args)?;
+    let (rest, block) = if signature.takes_block {
+        let (rest, block) = parse_raw_block(rest)?;
+        (rest, Some(block))
+    } else {
+        (rest, None)
+    };
+
+    Ok((rest, IdemRawCommandType::BlockCall(IdemRawCommandBlockCall {
+        name,
+        words,
+        args: args.unwrap_or_default(),
+        block,
+    })))
+}
+
+named!(parse_raw_command_call<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(call!(parse_keyword, "call")) >>
+        value: parse_value >>
+        (IdemRawCommandType::Call(value))
     )
 );
 
-named!(parse_param_key_value<CompleteStr, IdemParamType>,
+named!(parse_raw_command_let<CompleteStr, IdemRawCommandType>,
     do_parse!(
-        key: ws!(parse_identifier) >>
+        ws!(call!(parse_keyword, "let")) >>
+        name: parse_identifier >>
         ws!(tag!("=")) >>
-        value: ws!(parse_value) >>
-        (IdemParamType::KeyValue(key.to_string(), value))
+        value: parse_value >>
+        (IdemRawCommandType::Let(name.to_string(), value))
     )
 );
 
-named!(parse_param_flag_keyword<CompleteStr, IdemParamType>,
-        alt_complete!(
-            map!(ws!(recognize!(tag!("copied"))), |s| IdemParamType::FlagKeyword(s.to_string()))
-            | map!(ws!(recognize!(tag!("exists"))), |s| IdemParamType::FlagKeyword(s.to_string()))
-        )
+// `include <file>`, the file is read by the loader.
+named!(parse_raw_command_include<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        call!(parse_keyword, "include") >>
+        space >>
+        path: is_not!(" \t\r\n") >>
+        (IdemRawCommandType::Include(IdemRawCommandInclude { path: path.to_string(), statements: vec![] }))
+    )
 );
 
-named!(parse_param<CompleteStr, IdemParamType>,
-    alt_complete!(
-        ws!(parse_param_key_value)
-        | ws!(parse_param_flag_keyword)
+// `import <file> as <name>`
+named!(parse_raw_command_import<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        call!(parse_keyword, "import") >>
+        space >>
+        path: is_not!(" \t\r\n") >>
+        ws!(call!(parse_keyword, "as")) >>
+        name: parse_identifier >>
+        (IdemRawCommandType::Import(IdemRawCommandImport {
+            path: path.to_string(),
+            name: name.to_string(),
+            statements: vec![],
+        }))
     )
 );
 
-named!(parse_raw_command_with_paths<CompleteStr, IdemRawCommandWithPaths>,
-    do_parse!(
-        paths: many1!(ws!(parse_path)) >>
-        ws!(tag!("(")) >>
-        params: separated_list!(ws!(tag!(",")), ws!(parse_param)) >>
-        ws!(tag!(")")) >>
-        ({
-            IdemRawCommandWithPaths {
-                paths: paths,
-                params: params,
+/// `assert <condition>`, keeping the condition as written for the error it raises.
+fn parse_raw_command_assert(input: CompleteStr) -> IResult<CompleteStr, IdemRawCommandType> {
+    let (rest, _) = ws!(input, call!(parse_keyword, "assert"))?;
+    let (after, condition) = parse_condition(rest)?;
+    let text = rest.0[..rest.len() - after.len()].trim();
+    Ok((after, IdemRawCommandType::Assert(condition, text.to_string())))
+}
+
+/// `if <condition> ... else ... end`, or `unless`. `else if` on one line continues the
+/// same statement, so the chain has a single `end`.
+fn parse_raw_command_if(input: CompleteStr) -> IResult<CompleteStr, IdemRawCommandType> {
+    let (rest, negate) = ws!(input, alt_complete!(
+        value!(false, call!(parse_keyword, "if")) |
+        value!(true, call!(parse_keyword, "unless"))
+    ))?;
+    let (rest, condition) = parse_condition(rest)?;
+    let condition = if negate { IdemCondition::Not(Box::new(condition)) } else { condition };
+    let (rest, statements) = parse_raw_statements(rest)?;
+
+    let (rest, otherwise) = match preceded!(rest, opt!(multispace), call!(parse_keyword, "else")) {
+        Ok((rest, _)) => match preceded!(rest, opt!(space), peek!(alt_complete!(call!(parse_keyword, "if") | call!(parse_keyword, "unless")))) {
+            Ok(_) => {
+                let (rest, nested) = spanned(rest, parse_raw_command_if)?;
+                (rest, vec![nested])
             }
-        })
+            Err(_) => parse_raw_block(rest)?,
+        },
+        Err(_) => {
+            let (rest, _) = ws!(rest, call!(parse_keyword, "end"))?;
+            (rest, vec![])
+        }
+    };
+
+    Ok((rest, IdemRawCommandType::If(IdemRawCommandIf { condition, statements, otherwise })))
+}
+
+/// `try ... end` with an optional `rescue [<var>]` clause and then an optional
+/// `always` clause.
+fn parse_raw_command_try(input: CompleteStr) -> IResult<CompleteStr, IdemRawCommandType> {
+    let (rest, _) = ws!(input, call!(parse_keyword, "try"))?;
+    let (rest, statements) = parse_raw_statements(rest)?;
+
+    let (rest, rescue) = match preceded!(rest, opt!(multispace), call!(parse_keyword, "rescue")) {
+        Ok((rest, _)) => {
+            let (rest, var) = opt!(rest, preceded!(opt!(space), parse_identifier))?;
+            let (rest, statements) = parse_raw_statements(rest)?;
+            (rest, Some(IdemRescue {
+                var: var.map(|var| var.to_string()),
+                statements,
+            }))
+        }
+        Err(_) => (rest, None),
+    };
+
+    let (rest, always) = match ws!(rest, call!(parse_keyword, "always")) {
+        Ok((rest, _)) => parse_raw_block(rest)?,
+        Err(_) => {
+            let (rest, _) = ws!(rest, call!(parse_keyword, "end"))?;
+            (rest, vec![])
+        }
+    };
+
+    Ok((rest, IdemRawCommandType::Try(IdemRawCommandTry {
+        statements,
+        rescue,
+        always,
+    })))
+}
+
+named!(parse_user_group<CompleteStr, CompleteStr>,
+    recognize!(
+        many1!(one_of!("_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.-:"))
     )
 );
 
-named!(parse_raw_statements<CompleteStr, Vec<IdemRawCommandType>>,
-    many0!(ws!(parse_raw_command))
-);
+/// `@/parent/child[*]` up to the next whitespace, or `@~/regexp/` with any
+/// punctuation as the delimiter.
+fn parse_extractor(input: CompleteStr) -> IResult<CompleteStr, IdemExtractor> {
+    let error = || nom::Err::Error(error_position!(input, ErrorKind::Custom(0)));
+    if let Some(rest) = input.0.strip_prefix("@~") {
+        let delimiter = rest.chars().next().filter(|c| c.is_ascii_punctuation() && *c != '\\').ok_or_else(error)?;
+        let (regexp, rest) = split_regexp(&rest[delimiter.len_utf8()..], delimiter).ok_or_else(error)?;
+        return Ok((CompleteStr(rest), IdemExtractor::Regexp(regexp)));
+    }
+    if input.0.starts_with("@/") {
+        let end = input.0.find(char::is_whitespace).unwrap_or(input.0.len());
+        return Ok((CompleteStr(&input.0[end..]), IdemExtractor::Path(input.0[1..end].to_string())));
+    }
+    Err(error())
+}
 
 named!(parse_raw_command_each<CompleteStr, IdemRawCommandType>,
     do_parse!(
         ws!(tag!("each")) >>
+        extractor: opt!(
+            do_parse!(
+                extractor: ws!(parse_extractor) >>
+                call!(parse_keyword, "as") >>
+                (extractor)
+            )
+        ) >>
         key: ws!(parse_identifier) >>
         ws!(tag!("in")) >>
         coll: ws!(parse_value) >>
-        statements: ws!(map_res!(
-            take_until!("end"),
-            parse_raw_statements
-        )) >>
-        ws!(tag!("end")) >>
-        ({
-            let statements = statements.1.into_iter().map(|s| Box::new(s)).collect();
-            IdemRawCommandType::Each(key.0.to_string(), coll, statements)
-        })
+        statements: parse_raw_block >>
+        (IdemRawCommandType::Each(key.0.to_string(), extractor, coll, statements))
     )
 );
 
@@ -138,37 +1275,161 @@ named!(parse_raw_command_with_block<CompleteStr, IdemRawCommandType>,
             )
         ) >>
 
-        statements: ws!(map_res!(
-            take_until!("end"),
-            parse_raw_statements
-        )) >>
-        ws!(tag!("end")) >>
-        ({
-            let statements = statements.1.into_iter().map(|s| Box::new(s)).collect();
-            IdemRawCommandType::WithBlock(
-                resource,
-                as_.map(|s| s.to_string()),
-                statements,
+        statements: parse_raw_block >>
+        (IdemRawCommandType::WithBlock(resource, as_, statements))
+    )
+);
+
+named!(parse_raw_command_remotes<CompleteStr, IdemRawCommandType>,
+    do_parse!(
+        ws!(tag!("remotes")) >>
+        var: opt!(
+            do_parse!(
+                var: ws!(parse_identifier) >>
+                call!(parse_keyword, "in") >>
+                (var.to_string())
             )
-        })
+        ) >>
+        hosts: ws!(parse_host_pattern) >>
+        become_user: opt!(
+            do_parse!(
+                call!(parse_keyword, "as") >>
+                user: ws!(parse_user_group) >>
+                (user.to_string())
+            )
+        ) >>
+        params: opt!(parse_params) >>
+        statements: alt_complete!(
+            map!(call!(spanned, parse_raw_command_using), |using| vec![using]) |
+            parse_raw_block
+        ) >>
+        (IdemRawCommandType::Remotes(IdemRawCommandRemotes {
+            var,
+            hosts: hosts.to_string(),
+            become_user,
+            params: params.unwrap_or_default(),
+            statements,
+        }))
     )
 );
 
-named!(parse_raw_command<CompleteStr, IdemRawCommandType>,
+named!(parse_raw_command_node<CompleteStr, IdemRawCommandType>,
     alt_complete!(
         parse_raw_command_each |
+        parse_raw_command_if |
+        parse_raw_command_edit |
         parse_raw_command_with_block |
-        map!(parse_raw_command_with_paths, |p| IdemRawCommandType::WithPaths(p))
+        parse_raw_command_remotes |
+        parse_raw_command_using |
+        parse_raw_command_system |
+        parse_raw_command_def |
+        parse_raw_command_handler |
+        parse_raw_command_try |
+        parse_raw_command_return |
+        parse_raw_command_defblock |
+        parse_raw_command_call |
+        parse_raw_command_let |
+        parse_raw_command_assert |
+        parse_raw_command_include |
+        parse_raw_command_import |
+        parse_raw_command_block_call |
+        parse_raw_command_edit_param |
+        map!(parse_raw_command_with_paths, IdemRawCommandType::WithPaths)
     )
 );
 
-named!(pub parse_raw_script<CompleteStr, Vec<IdemRawCommandType>>,
+/// A statement. Keywords defined by a `defblock` in a statement that then fails to parse
+/// are forgotten, so they are only recognised after a `defblock` that is kept.
+fn parse_raw_command(input: CompleteStr) -> IResult<CompleteStr, Spanned<IdemRawCommandType>> {
+    let known = BLOCK_KEYWORDS.with(|keywords| keywords.borrow().0.len());
+    let result = spanned(input, parse_raw_command_node);
+    if result.is_err() {
+        BLOCK_KEYWORDS.with(|keywords| keywords.borrow_mut().0.truncate(known));
+    }
+    result
+}
+
+named!(pub parse_raw_script<CompleteStr, Vec<Spanned<IdemRawCommandType>>>,
     call!(parse_raw_statements)
 );
 
+/// Parses a file a statement at a time, so that the files a statement includes can be
+/// read before the next statement, which may use the block keywords they define. The
+/// nodes have spans in `file`.
+pub struct FileParser<'s> {
+    rest: CompleteStr<'s>,
+    source: Option<Source>,
+    keywords: BlockKeywords,
+}
+
+impl<'s> FileParser<'s> {
+    pub fn new(file: usize, source: &'s str) -> Self {
+        let lines = std::iter::once(0).chain(source.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        FileParser {
+            rest: CompleteStr(source),
+            source: Some(Source {
+                file,
+                start: source.as_ptr() as usize,
+                len: source.len(),
+                lines,
+                text: source.to_string(),
+            }),
+            keywords: BlockKeywords::default(),
+        }
+    }
+
+    /// Recognises `keywords` in the statements after this one.
+    pub fn add_keywords(&mut self, keywords: &BlockKeywords) {
+        self.keywords.extend(keywords);
+    }
+
+    /// The keywords the file defines, and those of the files it includes.
+    pub fn keywords(&self) -> &BlockKeywords {
+        &self.keywords
+    }
+
+    /// The next statement, or `None` at the end of the file. A statement that can not be
+    /// parsed is an error at the start of the text left.
+    pub fn next_statement(&mut self) -> Result<Option<Spanned<IdemRawCommandType>>, nom::Err<CompleteStr<'s>>> {
+        if self.rest.trim().is_empty() {
+            return Ok(None);
+        }
+
+        // Other files may be parsed between statements, each with its own source and keywords
+        SOURCE.with(|source| std::mem::swap(&mut *source.borrow_mut(), &mut self.source));
+        BLOCK_KEYWORDS.with(|keywords| std::mem::swap(&mut *keywords.borrow_mut(), &mut self.keywords));
+        let result = ws!(self.rest, parse_raw_command);
+        SOURCE.with(|source| std::mem::swap(&mut *source.borrow_mut(), &mut self.source));
+        BLOCK_KEYWORDS.with(|keywords| std::mem::swap(&mut *keywords.borrow_mut(), &mut self.keywords));
+
+        match result {
+            Ok((rest, statement)) => {
+                self.rest = rest;
+                Ok(Some(statement))
+            }
+            Err(nom::Err::Error(_)) => Err(nom::Err::Error(error_position!(self.rest, ErrorKind::Custom(0)))),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Parses a script on its own, the nodes have spans in `file`.
+#[cfg(test)]
+pub fn parse_file(file: usize, source: &str) -> IResult<CompleteStr<'_>, Vec<Spanned<IdemRawCommandType>>> {
+    let mut parser = FileParser::new(file, source);
+    let mut script = vec![];
+    loop {
+        match parser.next_statement() {
+            Ok(Some(statement)) => script.push(statement),
+            Ok(None) => return Ok((CompleteStr(""), script)),
+            Err(nom::Err::Error(_)) => return Ok((parser.rest, script)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    #[macro_use]
     use super::*;
 
     macro_rules!  test_parser (
@@ -184,7 +1445,7 @@ mod tests {
     #[test]
     fn test_parse_path1() {
         test_parser!(
-            CompleteStr(&r"path/"),
+            CompleteStr(r"path/"),
             parse_path,
             IdemPath(None, IdemPathLocalPartType::Directory("path".to_string()))
         );
@@ -193,7 +1454,7 @@ mod tests {
     #[test]
     fn test_parse_path2() {
         test_parser!(
-            CompleteStr(&r"path"),
+            CompleteStr(r"path"),
             parse_path,
             IdemPath(None, IdemPathLocalPartType::File("path".to_string()))
         );
@@ -202,7 +1463,7 @@ mod tests {
     #[test]
     fn test_parse_path3() {
         test_parser!(
-            CompleteStr(&r"path/to/path/"),
+            CompleteStr(r"path/to/path/"),
             parse_path,
             IdemPath(None, IdemPathLocalPartType::Directory("path/to/path".to_string()))
         );
@@ -211,7 +1472,7 @@ mod tests {
     #[test]
     fn test_parse_path4() {
         test_parser!(
-            CompleteStr(&r"path/to/path"),
+            CompleteStr(r"path/to/path"),
             parse_path,
             IdemPath(None, IdemPathLocalPartType::File("path/to/path".to_string()))
         );
@@ -220,19 +1481,33 @@ mod tests {
     #[test]
     fn test_parse_value_litstring() {
         test_parser!(
-            CompleteStr(&r#""value""#),
+            CompleteStr(r#""value""#),
             parse_value_litstring,
             IdemValueType::LitString("value".to_string())
         );
     }
 
+    #[test]
+    fn test_parse_value_extended_string() {
+        test_parser!(
+            CompleteStr("\"\"\"\n        alias k=\"kubectl\"\n          alias g=git\n        \"\"\""),
+            parse_value_extended_string,
+            IdemValueType::ExtendedString("alias k=\"kubectl\"\n  alias g=git\n".to_string())
+        );
+        test_parser!(
+            CompleteStr(r#""""say "hi"""""#),
+            parse_value_extended_string,
+            IdemValueType::ExtendedString("say \"hi".to_string())
+        );
+    }
+
     #[test]
     fn test_parse_value_path_spec1() {
         test_parser!(
-            CompleteStr(&r#"./path"#),
+            CompleteStr(r#"./path"#),
             parse_value_path_spec,
             IdemValueType::PathSpec(
-                IdemPath(None, IdemPathLocalPartType::File("./path".to_string()))
+                IdemPath(None, IdemPathLocalPartType::File("./path".to_string())).into()
             )
         );
     }
@@ -240,10 +1515,10 @@ mod tests {
     #[test]
     fn test_parse_value_path_spec2() {
         test_parser!(
-            CompleteStr(&r#"./path/"#),
+            CompleteStr(r#"./path/"#),
             parse_value_path_spec,
             IdemValueType::PathSpec(
-                IdemPath(None, IdemPathLocalPartType::Directory("./path".to_string()))
+                IdemPath(None, IdemPathLocalPartType::Directory("./path".to_string())).into()
             )
         );
     }
@@ -251,16 +1526,16 @@ mod tests {
     #[test]
     fn test_parse_param_key_value() {
         test_parser!(
-            CompleteStr(&r#"key="value""#),
+            CompleteStr(r#"key="value""#),
             parse_param_key_value,
-            IdemParamType::KeyValue("key".to_string(), IdemValueType::LitString("value".to_string()))
+            IdemParamType::KeyValue("key".to_string(), IdemValueType::LitString("value".to_string()).into())
         );
     }
 
     #[test]
     fn test_parse_param_flag_keyword() {
         test_parser!(
-            CompleteStr(&"copied"),
+            CompleteStr("copied"),
             parse_param_flag_keyword,
             IdemParamType::FlagKeyword("copied".to_string())
         );
@@ -269,15 +1544,15 @@ mod tests {
     #[test]
     fn test_parse_raw_command_with_paths1() {
         test_parser!(
-            CompleteStr(&r#"./path1 ./path2 (key="value")"#),
+            CompleteStr(r#"./path1 ./path2 (key="value")"#),
             parse_raw_command_with_paths,
             IdemRawCommandWithPaths {
                 paths: vec![
-                    IdemPath(None, IdemPathLocalPartType::File("./path1".to_string())),
-                    IdemPath(None, IdemPathLocalPartType::File("./path2".to_string())),
+                    IdemPath(None, IdemPathLocalPartType::File("./path1".to_string())).into(),
+                    IdemPath(None, IdemPathLocalPartType::File("./path2".to_string())).into(),
                 ],
                 params: vec![
-                    IdemParamType::KeyValue("key".to_string(), IdemValueType::LitString("value".to_string()))
+                    IdemParamType::KeyValue("key".to_string(), IdemValueType::LitString("value".to_string()).into()).into()
                 ]
             }
         );
@@ -286,15 +1561,15 @@ mod tests {
     #[test]
     fn test_parse_raw_command_with_paths2() {
         test_parser!(
-            CompleteStr(&r#"./path1/ ./path2/ (copied)"#),
+            CompleteStr(r#"./path1/ ./path2/ (copied)"#),
             parse_raw_command_with_paths,
             IdemRawCommandWithPaths {
                 paths: vec![
-                    IdemPath(None, IdemPathLocalPartType::Directory("./path1".to_string())),
-                    IdemPath(None, IdemPathLocalPartType::Directory("./path2".to_string())),
+                    IdemPath(None, IdemPathLocalPartType::Directory("./path1".to_string())).into(),
+                    IdemPath(None, IdemPathLocalPartType::Directory("./path2".to_string())).into(),
                 ],
                 params: vec![
-                    IdemParamType::FlagKeyword("copied".to_string()),
+                    IdemParamType::FlagKeyword("copied".to_string()).into(),
                 ]
             }
         );
@@ -303,7 +1578,7 @@ mod tests {
     #[test]
     fn test_parse_raw_command_each() {
         test_parser!(
-            CompleteStr(&r#"
+            CompleteStr(r#"
 each i in ./dir/
     ./a (mode="755")
     ./b (mode="600")
@@ -312,35 +1587,80 @@ end
             parse_raw_command_each,
             IdemRawCommandType::Each(
                 "i".to_string(),
+                None,
                 IdemValueType::PathSpec(
-                    IdemPath(None, IdemPathLocalPartType::Directory("./dir".to_string()))
-                ),
+                    IdemPath(None, IdemPathLocalPartType::Directory("./dir".to_string())).into()
+                ).into(),
                 vec![
-                    Box::new(IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                    IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
                         paths: vec![
-                            IdemPath(None, IdemPathLocalPartType::File("./a".to_string())),
+                            IdemPath(None, IdemPathLocalPartType::File("./a".to_string())).into(),
                         ],
                         params: vec![
-                            IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("755".to_string()))
+                            IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("755".to_string()).into()).into()
                         ]
-                    })),
-                    Box::new(IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                    }).into(),
+                    IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
                         paths: vec![
-                            IdemPath(None, IdemPathLocalPartType::File("./b".to_string())),
+                            IdemPath(None, IdemPathLocalPartType::File("./b".to_string())).into(),
                         ],
                         params: vec![
-                            IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("600".to_string()))
+                            IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("600".to_string()).into()).into()
                         ]
-                    })),
+                    }).into(),
                 ]
             )
         );
     }
 
+    #[test]
+    fn test_parse_raw_command_each_extractor() {
+        test_parser!(
+            CompleteStr("each @/parent/child[*] as c in ./test.json\nend\n"),
+            parse_raw_command_each,
+            IdemRawCommandType::Each(
+                "c".to_string(),
+                Some(IdemExtractor::Path("/parent/child[*]".to_string())),
+                IdemValueType::PathSpec(IdemPath(None, IdemPathLocalPartType::File("./test.json".to_string())).into()).into(),
+                vec![]
+            )
+        );
+        test_parser!(
+            CompleteStr("each @~|^name=(.*)\\|| as c in ./test.txt\nend\n"),
+            parse_raw_command_each,
+            IdemRawCommandType::Each(
+                "c".to_string(),
+                Some(IdemExtractor::Regexp("^name=(.*)\\|".to_string())),
+                IdemValueType::PathSpec(IdemPath(None, IdemPathLocalPartType::File("./test.txt".to_string())).into()).into(),
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_resource_temporary() {
+        test_parser!(
+            CompleteStr("~~build/"),
+            parse_resource,
+            IdemResourceType::Temporary(vec![
+                IdemParamType::FlagKeyword("dir".to_string()).into(),
+                IdemParamType::KeyValue("suffix".to_string(), IdemValueType::LitString("-build".to_string()).into()).into(),
+            ])
+        );
+        test_parser!(
+            CompleteStr(r#"(( temporary(dir suffix="build") ))"#),
+            parse_resource,
+            IdemResourceType::Temporary(vec![
+                IdemParamType::FlagKeyword("dir".to_string()).into(),
+                IdemParamType::KeyValue("suffix".to_string(), IdemValueType::LitString("build".to_string()).into()).into(),
+            ])
+        );
+    }
+
     #[test]
     fn test_parse_raw_command_with_block() {
         test_parser!(
-            CompleteStr(&r#"
+            CompleteStr(r#"
 with ./dir/
     ./child/ (exists)
 end
@@ -350,14 +1670,14 @@ end
                 IdemResourceType::Directory("./dir".to_string()),
                 None,
                 vec![
-                    Box::new(IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                    IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
                         paths: vec![
-                            IdemPath(None, IdemPathLocalPartType::Directory("./child".to_string())),
+                            IdemPath(None, IdemPathLocalPartType::Directory("./child".to_string())).into(),
                         ],
                         params: vec![
-                            IdemParamType::FlagKeyword("exists".to_string()),
+                            IdemParamType::FlagKeyword("exists".to_string()).into(),
                         ]
-                    }))
+                    }).into()
                 ]
             )
         );
@@ -366,7 +1686,7 @@ end
     #[test]
     fn test_parse_raw_script1() {
         test_parser!(
-            CompleteStr(&r#"
+            CompleteStr(r#"
 each i in ./dir/
     ./a (mode="755")
     ./b (mode="600")
@@ -377,39 +1697,533 @@ end
             vec![
                 IdemRawCommandType::Each(
                     "i".to_string(),
+                    None,
                     IdemValueType::PathSpec(
-                        IdemPath(None, IdemPathLocalPartType::Directory("./dir".to_string()))
-                    ),
+                        IdemPath(None, IdemPathLocalPartType::Directory("./dir".to_string())).into()
+                    ).into(),
                     vec![
-                        Box::new(IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                        IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
                             paths: vec![
-                                IdemPath(None, IdemPathLocalPartType::File("./a".to_string())),
+                                IdemPath(None, IdemPathLocalPartType::File("./a".to_string())).into(),
                             ],
                             params: vec![
-                                IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("755".to_string()))
+                                IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("755".to_string()).into()).into()
                             ]
-                        })),
-                        Box::new(IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                        }).into(),
+                        IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
                             paths: vec![
-                                IdemPath(None, IdemPathLocalPartType::File("./b".to_string())),
+                                IdemPath(None, IdemPathLocalPartType::File("./b".to_string())).into(),
                             ],
                             params: vec![
-                                IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("600".to_string()))
+                                IdemParamType::KeyValue("mode".to_string(), IdemValueType::LitString("600".to_string()).into()).into()
                             ]
-                        })),
+                        }).into(),
                     ]
-                ),
+                ).into(),
                 IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
                     paths: vec![
-                        IdemPath(None, IdemPathLocalPartType::Directory("./x".to_string())),
-                        IdemPath(None, IdemPathLocalPartType::Directory("./y".to_string())),
+                        IdemPath(None, IdemPathLocalPartType::Directory("./x".to_string())).into(),
+                        IdemPath(None, IdemPathLocalPartType::Directory("./y".to_string())).into(),
                     ],
                     params: vec![
-                        IdemParamType::FlagKeyword("copied".to_string()),
+                        IdemParamType::FlagKeyword("copied".to_string()).into(),
                     ]
-                }),
+                }).into(),
             ]
         );
     }
 
+    #[test]
+    fn test_parse_raw_command_remotes() {
+        test_parser!(
+            CompleteStr(r#"
+remotes prod-*,db1 (forks=10)
+    with ./srv/
+        ./app/ (exists)
+    end
+end
+"#),
+            parse_raw_command_remotes,
+            IdemRawCommandType::Remotes(IdemRawCommandRemotes {
+                var: None,
+                hosts: "prod-*,db1".to_string(),
+                become_user: None,
+                params: vec![
+                    IdemParamType::KeyValue("forks".to_string(), IdemValueType::Integer(10).into()).into(),
+                ],
+                statements: vec![
+                    IdemRawCommandType::WithBlock(
+                        IdemResourceType::Directory("./srv".to_string()),
+                        None,
+                        vec![
+                            IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                                paths: vec![
+                                    IdemPath(None, IdemPathLocalPartType::Directory("./app".to_string())).into(),
+                                ],
+                                params: vec![
+                                    IdemParamType::FlagKeyword("exists".to_string()).into(),
+                                ]
+                            }).into()
+                        ]
+                    ).into(),
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_command_system() {
+        test_parser!(CompleteStr(r#"system (make -C "src (copy)") (timeout=60)"#), parse_raw_command,
+            IdemRawCommandType::System(IdemRawCommandSystem {
+                command: r#"make -C "src (copy)""#.to_string(),
+                capture: true,
+                params: vec![
+                    IdemParamType::KeyValue("timeout".to_string(), IdemValueType::Integer(60).into()).into(),
+                ],
+            }).into()
+        );
+
+        test_parser!(CompleteStr(r"$((test -d (build)))"), parse_raw_command,
+            IdemRawCommandType::System(IdemRawCommandSystem {
+                command: "test -d (build)".to_string(),
+                capture: false,
+                params: vec![],
+            }).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_command_if() {
+        let os = || IdemValueType::Variable("os".to_string(), vec![]);
+        let status = |name: &str| IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+            paths: vec![IdemPath(None, IdemPathLocalPartType::File(format!("./{}", name))).into()],
+            params: vec![IdemParamType::FlagKeyword("exists".to_string()).into()],
+        }).into();
+
+        test_parser!(CompleteStr(r#"
+if $os == "debian" and not ./etc/fedora.conf exists
+    ./debian (exists)
+else if (( system (test -d /etc/pacman.d) )) or $os != arch
+    ./arch (exists)
+else
+    ./other (exists)
+end"#), parse_raw_command,
+            IdemRawCommandType::If(IdemRawCommandIf {
+                condition: IdemCondition::And(
+                    Box::new(IdemCondition::Compare(os().into(), IdemCompareOp::Eq, IdemValueType::LitString("debian".to_string()).into())),
+                    Box::new(IdemCondition::Not(Box::new(IdemCondition::Exists(
+                        IdemValueType::PathSpec(IdemPath(None, IdemPathLocalPartType::File("./etc/fedora.conf".to_string())).into()).into()
+                    )))),
+                ),
+                statements: vec![status("debian")],
+                otherwise: vec![IdemRawCommandType::If(IdemRawCommandIf {
+                    condition: IdemCondition::Or(
+                        Box::new(IdemCondition::Value(IdemValueType::Command(Box::new(IdemRawCommandType::System(IdemRawCommandSystem {
+                            command: "test -d /etc/pacman.d".to_string(),
+                            capture: true,
+                            params: vec![],
+                        }).into())).into())),
+                        Box::new(IdemCondition::Compare(os().into(), IdemCompareOp::Ne,
+                            IdemValueType::PathSpec(IdemPath(None, IdemPathLocalPartType::File("arch".to_string())).into()).into())),
+                    ),
+                    statements: vec![status("arch")],
+                    otherwise: vec![status("other")],
+                }).into()],
+            }).into()
+        );
+
+        test_parser!(CompleteStr("unless $count >= 2\n./x (exists)\nend"), parse_raw_command,
+            IdemRawCommandType::If(IdemRawCommandIf {
+                condition: IdemCondition::Not(Box::new(IdemCondition::Compare(
+                    IdemValueType::Variable("count".to_string(), vec![]).into(), IdemCompareOp::Ge, IdemValueType::Integer(2).into(),
+                ))),
+                statements: vec![status("x")],
+                otherwise: vec![],
+            }).into()
+        );
+
+        // An `if` on the line after `else` has its own `end`
+        let (rest, script) = parse_raw_script(CompleteStr("if $a\n./x (exists)\nelse\n    if $b\n    ./y (exists)\n    end\nend\n")).unwrap();
+        assert!(rest.trim().is_empty() && script.len() == 1, "{:?}", rest);
+    }
+
+    #[test]
+    fn test_parse_raw_command_handler() {
+        test_parser!(CompleteStr("handler restart_nginx\n    system (systemctl restart nginx)\nend"), parse_raw_command,
+            IdemRawCommandType::Handler(IdemRawCommandHandler {
+                name: "restart_nginx".to_string(),
+                statements: vec![IdemRawCommandType::System(IdemRawCommandSystem {
+                    command: "systemctl restart nginx".to_string(),
+                    capture: true,
+                    params: vec![],
+                }).into()],
+            }).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_command_try() {
+        let system = |command: &str| IdemRawCommandType::System(IdemRawCommandSystem {
+            command: command.to_string(),
+            capture: true,
+            params: vec![],
+        }).into();
+
+        test_parser!(CompleteStr("try\n    system (deploy)\nrescue err\n    system (rollback)\nalways\n    system (notify)\nend"), parse_raw_command,
+            IdemRawCommandType::Try(IdemRawCommandTry {
+                statements: vec![system("deploy")],
+                rescue: Some(IdemRescue { var: Some("err".to_string()), statements: vec![system("rollback")] }),
+                always: vec![system("notify")],
+            }).into()
+        );
+
+        test_parser!(CompleteStr("try\n    system (deploy)\nrescue\nend"), parse_raw_command,
+            IdemRawCommandType::Try(IdemRawCommandTry {
+                statements: vec![system("deploy")],
+                rescue: Some(IdemRescue { var: None, statements: vec![] }),
+                always: vec![],
+            }).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_command_include_import() {
+        test_parser!(CompleteStr("include ./common.idem\n./x (exists)"), parse_raw_command,
+            IdemRawCommandType::Include(IdemRawCommandInclude { path: "./common.idem".to_string(), statements: vec![] }).into()
+        );
+        test_parser!(CompleteStr("import lib/web-app.idem as web"), parse_raw_command,
+            IdemRawCommandType::Import(IdemRawCommandImport {
+                path: "lib/web-app.idem".to_string(),
+                name: "web".to_string(),
+                statements: vec![],
+            }).into()
+        );
+
+        // Imported commands are called by their qualified names
+        test_parser!(CompleteStr("(web.deploy, when = $x)"), parse_params, vec![
+            IdemParamType::Call("web.deploy".to_string(), vec![]).into(),
+            IdemParamType::When(IdemCondition::Value(IdemValueType::Variable("x".to_string(), vec![]).into())).into(),
+        ]);
+        test_parser!(CompleteStr("(web.deploy \"1.0\")"), parse_params, vec![
+            IdemParamType::Call("web.deploy".to_string(), vec![
+                IdemParamType::Positional(IdemValueType::LitString("1.0".to_string()).into()).into(),
+            ]).into(),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_raw_command_assert() {
+        test_parser!(CompleteStr("assert $count >= 2\n./x (exists)"), parse_raw_command,
+            IdemRawCommandType::Assert(IdemCondition::Compare(
+                IdemValueType::Variable("count".to_string(), vec![]).into(), IdemCompareOp::Ge, IdemValueType::Integer(2).into(),
+            ), "$count >= 2".to_string()).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_file_spans() {
+        let source = "./a (exists)\nif $x\n  ./b (content = \"é\", mode = \"755\")\nend\n";
+        let script = parse_file(3, source).unwrap().1;
+        assert_eq!(script[0].span, Span { file: 3, start: 0, end: 12, line: 1, column: 1 });
+        assert_eq!(script[1].span, Span { file: 3, start: 13, end: 59, line: 2, column: 1 });
+
+        let statement = match script[1].node {
+            IdemRawCommandType::If(ref obj) => &obj.statements[0],
+            _ => unreachable!(),
+        };
+        assert_eq!(statement.span, Span { file: 3, start: 21, end: 55, line: 3, column: 3 });
+        match statement.node {
+            IdemRawCommandType::WithPaths(ref obj) => {
+                assert_eq!(obj.paths[0].span, Span { file: 3, start: 21, end: 24, line: 3, column: 3 });
+                // Columns count characters, not bytes
+                assert_eq!(obj.params[1].span, Span { file: 3, start: 42, end: 54, line: 3, column: 23 });
+                match obj.params[1].node {
+                    IdemParamType::KeyValue(_, ref value) => assert_eq!(value.span.column, 30),
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        // Nodes parsed from anything else have an empty span
+        let script = parse_raw_script(CompleteStr(source)).unwrap().1;
+        assert_eq!(script[1].span, Span::default());
+    }
+
+    #[test]
+    fn test_parse_param_when() {
+        test_parser!(CompleteStr(r#"./motd (content = "hi", when = $os == "debian")"#), parse_raw_command_with_paths,
+            IdemRawCommandWithPaths {
+                paths: vec![IdemPath(None, IdemPathLocalPartType::File("./motd".to_string())).into()],
+                params: vec![
+                    IdemParamType::KeyValue("content".to_string(), IdemValueType::LitString("hi".to_string()).into()).into(),
+                    IdemParamType::When(IdemCondition::Compare(
+                        IdemValueType::Variable("os".to_string(), vec![]).into(), IdemCompareOp::Eq, IdemValueType::LitString("debian".to_string()).into(),
+                    )).into(),
+                ],
+            }
+        );
+
+        // Guards follow the arguments of a call
+        test_parser!(CompleteStr("./app (sync ./src/, unless = $dry_run)"), parse_raw_command_with_paths,
+            IdemRawCommandWithPaths {
+                paths: vec![IdemPath(None, IdemPathLocalPartType::File("./app".to_string())).into()],
+                params: vec![
+                    IdemParamType::Call("sync".to_string(), vec![
+                        IdemParamType::Positional(IdemValueType::PathSpec(IdemPath(None, IdemPathLocalPartType::Directory("./src".to_string())).into()).into()).into(),
+                    ]).into(),
+                    IdemParamType::When(IdemCondition::Not(Box::new(IdemCondition::Value(IdemValueType::Variable("dry_run".to_string(), vec![]).into())))).into(),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_command_let() {
+        test_parser!(CompleteStr(r#"let version = "1.2""#), parse_raw_command,
+            IdemRawCommandType::Let("version".to_string(), IdemValueType::LitString("1.2".to_string()).into()).into()
+        );
+
+        test_parser!(CompleteStr("let home = $ENV.HOME"), parse_raw_command,
+            IdemRawCommandType::Let("home".to_string(), IdemValueType::Variable("ENV".to_string(), vec![
+                IdemAccessor::Key("HOME".to_string()),
+            ]).into()).into()
+        );
+
+        // A file named `let` is still a path
+        assert!(matches!(parse_raw_command(CompleteStr("./let (exists)")), Ok((_, Spanned { node: IdemRawCommandType::WithPaths(_), .. }))));
+    }
+
+    #[test]
+    fn test_parse_value_expressions() {
+        test_parser!(CompleteStr(r#"(( ./file (wc) ))"#), parse_value,
+            IdemValueType::Command(Box::new(IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                paths: vec![IdemPath(None, IdemPathLocalPartType::File("./file".to_string())).into()],
+                params: vec![IdemParamType::FlagKeyword("wc".to_string()).into()],
+            }).into())).into()
+        );
+
+        test_parser!(CompleteStr(r#"[(()), (( "a" )), $c.ports[-1], { name = true, "x-y" = [] }]"#), parse_value,
+            IdemValueType::List(vec![
+                IdemValueType::Empty.into(),
+                IdemValueType::LitString("a".to_string()).into(),
+                IdemValueType::Variable("c".to_string(), vec![
+                    IdemAccessor::Key("ports".to_string()),
+                    IdemAccessor::Index(-1),
+                ]).into(),
+                IdemValueType::Map(vec![
+                    ("name".to_string(), IdemValueType::Boolean(true).into()),
+                    ("x-y".to_string(), IdemValueType::List(vec![]).into()),
+                ]).into(),
+            ]).into()
+        );
+
+        test_parser!(CompleteStr(" c[1] "), parse_interpolation,
+            IdemValueType::Variable("c".to_string(), vec![IdemAccessor::Index(1)]).into()
+        );
+
+        // Numbers out of range do not parse
+        assert!(parse_value_integer(CompleteStr("99999999999999999999")).is_err());
+        assert!(parse_interpolation(CompleteStr("x[99999999999999999999]")).is_err());
+        assert!(parse_raw_script(CompleteStr("./x (content = 99999999999999999999)\n")).is_err());
+    }
+
+    #[test]
+    fn test_parse_raw_command_def() {
+        test_parser!(CompleteStr(r#"
+def sync({ dest, (recurse|-r) = false, ...rest })
+    return $dest
+end
+"#), parse_raw_command,
+            IdemRawCommandType::Def(IdemRawCommandDef {
+                name: "sync".to_string(),
+                params: IdemDefParams::Keyword(vec![
+                    IdemDefParam { names: vec!["dest".to_string()], default: None, splat: false },
+                    IdemDefParam { names: vec!["recurse".to_string(), "-r".to_string()], default: Some(IdemValueType::Boolean(false).into()), splat: false },
+                    IdemDefParam { names: vec!["rest".to_string()], default: None, splat: true },
+                ]),
+                statements: vec![
+                    IdemRawCommandType::Return(IdemValueType::Variable("dest".to_string(), vec![]).into()).into(),
+                ],
+            }).into()
+        );
+
+        test_parser!(CompleteStr(r#"./src (sync dest="/srv" -rv ./extra, verbose)"#), parse_raw_command,
+            IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                paths: vec![IdemPath(None, IdemPathLocalPartType::File("./src".to_string())).into()],
+                params: vec![IdemParamType::Call("sync".to_string(), vec![
+                    IdemParamType::KeyValue("dest".to_string(), IdemValueType::LitString("/srv".to_string()).into()).into(),
+                    IdemParamType::ShortFlags(vec!['r', 'v']).into(),
+                    IdemParamType::Positional(IdemValueType::PathSpec(
+                        IdemPath(None, IdemPathLocalPartType::File("./extra".to_string())).into()
+                    ).into()).into(),
+                    IdemParamType::FlagKeyword("verbose".to_string()).into(),
+                ]).into()],
+            }).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_command_defblock() {
+        let (rest, script) = parse_raw_script(CompleteStr(r#"
+defblock deploy [app "to" env] ({ (force|-f) = false }) do
+    call $block
+end
+deploy "web" to staging (-f)
+    ./release/ (exists)
+end
+"#)).unwrap();
+
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(script[1], IdemRawCommandType::BlockCall(IdemRawCommandBlockCall {
+            name: "deploy".to_string(),
+            words: vec![
+                IdemValueType::LitString("web".to_string()).into(),
+                IdemValueType::LitString("staging".to_string()).into(),
+            ],
+            args: vec![IdemParamType::ShortFlags(vec!['f']).into()],
+            block: Some(vec![
+                IdemRawCommandType::WithPaths(IdemRawCommandWithPaths {
+                    paths: vec![IdemPath(None, IdemPathLocalPartType::Directory("./release".to_string())).into()],
+                    params: vec![IdemParamType::FlagKeyword("exists".to_string()).into()],
+                }).into(),
+            ]),
+        }).into());
+
+        // Without the tag it is not an invocation
+        assert!(parse_raw_command_block_call(CompleteStr("deploy web staging\nend")).is_err());
+
+        // Keywords belong to the file defining them
+        let source = "defblock stage [env] do\n    call $block\nend\nstage prod\n    ./x (exists)\nend\n";
+        assert_eq!(parse_file(0, source).unwrap().0, CompleteStr(""));
+        let (rest, _) = parse_file(1, "stage prod\n    ./x (exists)\nend\n").unwrap();
+        assert_ne!(rest, CompleteStr(""));
+
+        // A keyword defined in a statement that fails to parse is forgotten
+        assert!(parse_raw_command(CompleteStr("if $x\n    defblock ship [env] do\n        call $block\n    end\n")).is_err());
+        assert!(parse_raw_command_block_call(CompleteStr("ship prod\nend")).is_err());
+    }
+
+    #[test]
+    fn test_parse_raw_command_edit_lines() {
+        let (rest, command) = parse_raw_command(CompleteStr(r#"with ./etc/app.ini (edit = lines, ignore_whitespace)
+    + [blocka]
+    [main]
+    - config1 = "value"
+    + config0 = "value"
+    +   config2 = "value"
+end
+"#)).unwrap();
+
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(command, IdemRawCommandType::Edit(IdemRawCommandEdit {
+            path: IdemPath(None, IdemPathLocalPartType::File("./etc/app.ini".to_string())).into(),
+            params: vec![
+                IdemParamType::KeyValue(
+                    "edit".to_string(),
+                    IdemValueType::PathSpec(IdemPath(None, IdemPathLocalPartType::File("lines".to_string())).into()).into(),
+                ).into(),
+                IdemParamType::FlagKeyword("ignore_whitespace".to_string()).into(),
+            ],
+            body: IdemEditBody::Lines(IdemEdit { commands: vec![
+                IdemEditCommandType::InsertBefore("[main]".to_string(), "[blocka]".to_string()),
+                IdemEditCommandType::Context("[main]".to_string()),
+                IdemEditCommandType::Remove("config1 = \"value\"".to_string()),
+                IdemEditCommandType::InsertAfter("[main]".to_string(), "config0 = \"value\"".to_string()),
+                IdemEditCommandType::InsertAfter("config0 = \"value\"".to_string(), "  config2 = \"value\"".to_string()),
+            ]}),
+        }).into());
+
+        // Without `lines`, `+` and `-` lines belong to a template
+        let template = "*filter\n-A INPUT -p tcp --dport 22 -j ACCEPT\n-- comment\n";
+        assert_eq!(parse_edit_body(template, false), IdemEditBody::Blocks(template.to_string()));
+    }
+
+    #[test]
+    fn test_parse_substitution() {
+        assert_eq!(parse_substitution(r"@#Existing line to change# s/change/modify/g"), Some(IdemEditCommandType::Replace(
+            Some(IdemLineSelector::Line("Existing line to change".to_string())),
+            IdemReplace { regexp: "change".to_string(), replacement: "modify".to_string(), global: true },
+        )));
+        assert_eq!(parse_substitution(r"/^PermitRootLogin/ s|(yes\|no)|\|no|i"), Some(IdemEditCommandType::Replace(
+            Some(IdemLineSelector::Regexp("^PermitRootLogin".to_string())),
+            IdemReplace { regexp: r"(?i)(yes\|no)".to_string(), replacement: "|no".to_string(), global: false },
+        )));
+        assert_eq!(parse_substitution(r"s/a\/b/c/"), Some(IdemEditCommandType::Replace(
+            None,
+            IdemReplace { regexp: "a/b".to_string(), replacement: "c".to_string(), global: false },
+        )));
+
+        // Context lines that only look like substitutions
+        assert_eq!(parse_substitution("s = 1"), None);
+        assert_eq!(parse_substitution("s/a/b/x"), None);
+        assert_eq!(parse_substitution("/usr/bin"), None);
+    }
+
+    #[test]
+    fn test_parse_raw_command_edit_param() {
+        let (rest, command) = parse_raw_command(CompleteStr(r#"/etc/passwd (edit = {
+    $+ new line to add to file (/^new line/)
+
+    @#Existing line to change# s/change/modify/g
+    $- (/^games:/)
+    $+ 127.0.1.1 (web) (/^127\.0\.1\.1/, insertafter = /^127\.0\.0\.1/)
+}, ignore_whitespace)
+"#)).unwrap();
+
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(command, IdemRawCommandType::Edit(IdemRawCommandEdit {
+            path: IdemPath(None, IdemPathLocalPartType::File("/etc/passwd".to_string())).into(),
+            params: vec![
+                IdemParamType::FlagKeyword("edit".to_string()).into(),
+                IdemParamType::FlagKeyword("ignore_whitespace".to_string()).into(),
+            ],
+            body: IdemEditBody::Lines(IdemEdit { commands: vec![
+                IdemEditCommandType::LineInFile(IdemLineInFile {
+                    line: "new line to add to file".to_string(),
+                    present: true,
+                    regexp: Some("^new line".to_string()),
+                    insert: IdemLineInsert::End,
+                }),
+                IdemEditCommandType::Replace(
+                    Some(IdemLineSelector::Line("Existing line to change".to_string())),
+                    IdemReplace { regexp: "change".to_string(), replacement: "modify".to_string(), global: true },
+                ),
+                IdemEditCommandType::LineInFile(IdemLineInFile {
+                    line: "".to_string(),
+                    present: false,
+                    regexp: Some("^games:".to_string()),
+                    insert: IdemLineInsert::End,
+                }),
+                IdemEditCommandType::LineInFile(IdemLineInFile {
+                    line: "127.0.1.1 (web)".to_string(),
+                    present: true,
+                    regexp: Some(r"^127\.0\.1\.1".to_string()),
+                    insert: IdemLineInsert::After(r"^127\.0\.0\.1".to_string()),
+                }),
+            ]}),
+        }).into());
+
+        assert_eq!(parse_line_in_file("$+ (/^x/)"), None);
+    }
+
+    #[test]
+    fn test_parse_edit_body_json() {
+        assert_eq!(parse_edit_body(r#"@/path/to/object
+{
+    "a4": "value"
+}
+@/name = "web"
+@/old (removed)
+@/ {"inline": true}
+"#, false), IdemEditBody::Json(vec![
+            IdemJsonEdit::Merge("/path/to/object".to_string(), "\n{\n    \"a4\": \"value\"\n}\n".to_string()),
+            IdemJsonEdit::Set("/name".to_string(), "\"web\"\n".to_string()),
+            IdemJsonEdit::Remove("/old".to_string()),
+            IdemJsonEdit::Merge("/".to_string(), "{\"inline\": true}\n".to_string()),
+        ]));
+
+        // A substitution on the lines equal to `/etc`
+        assert!(matches!(parse_edit_body("@/etc/ s/a/b/\n", false), IdemEditBody::Lines(_)));
+    }
 }
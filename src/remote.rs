@@ -0,0 +1,336 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::value::{Param, Value};
+use super::traits::*;
+use super::escalate::EscalationConfig;
+use super::errors::{Error, Result as ExecResult};
+
+pub const DEFAULT_FORKS: usize = 5;
+
+/// Opens an `Exec` driver for the named host.
+pub type Connector = Arc<dyn Fn(&str) -> ExecResult<Box<dyn Exec + Send>> + Send + Sync>;
+
+/// Settings shared by every host a script runs on.
+#[derive(Clone)]
+pub struct RemoteConfig {
+    pub connector: Option<Connector>,
+    pub inventory: Vec<String>,
+    pub forks: usize,
+    pub escalation: EscalationConfig,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            connector: None,
+            inventory: vec![],
+            forks: DEFAULT_FORKS,
+            escalation: EscalationConfig::default(),
+        }
+    }
+}
+
+/// A host count, either absolute (`5`) or relative to the number of targeted hosts (`"10%"`).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HostCount {
+    Count(usize),
+    Percent(usize),
+}
+
+impl HostCount {
+    fn from_value(key: &str, value: &Value) -> ExecResult<Self> {
+        let invalid = || Error::message(format!("Invalid value for {}: {}", key, value.literal()));
+
+        match value {
+            Value::Integer(n) if *n >= 0 => Ok(HostCount::Count(*n as usize)),
+            Value::String(s) => {
+                let s = s.trim();
+                if let Some(pct) = s.strip_suffix('%') {
+                    pct.trim().parse().map(HostCount::Percent).map_err(|_| invalid())
+                } else {
+                    s.parse().map(HostCount::Count).map_err(|_| invalid())
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Percentages round up so that `serial="10%"` of 5 hosts still makes progress.
+    pub fn resolve(&self, total: usize) -> usize {
+        match *self {
+            HostCount::Count(n) => n,
+            HostCount::Percent(pct) => (total * pct).div_ceil(100),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RolloutOptions {
+    pub forks: usize,
+    pub serial: Option<HostCount>,
+    pub max_fail: Option<HostCount>,
+}
+
+impl RolloutOptions {
+    pub fn from_params(forks: usize, params: &[Param]) -> ExecResult<Self> {
+        let mut opts = RolloutOptions { forks, serial: None, max_fail: None };
+
+        for param in params {
+            match param {
+                Param::KeyValue(key, value) => match key.as_str() {
+                    "forks" => match HostCount::from_value(key, value)? {
+                        HostCount::Count(n) => opts.forks = n,
+                        HostCount::Percent(_) => return Err(Error::message("forks must be a host count")),
+                    },
+                    "serial" => opts.serial = Some(HostCount::from_value(key, value)?),
+                    "max_fail" => opts.max_fail = Some(HostCount::from_value(key, value)?),
+                    _ => return Err(Error::message(format!("Unknown remotes parameter: {}", key))),
+                },
+                _ => return Err(Error::message(format!("Unknown remotes parameter: {:?}", param))),
+            }
+        }
+
+        Ok(opts)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum HostStatus {
+    Ok,
+    Failed(String),
+    /// Not attempted because the rollout was aborted.
+    Skipped,
+}
+
+/// Matches a `*`/`?` glob against a host name.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Resolves a comma separated list of host names and globs against the inventory.
+///
+/// Names without glob characters are used as given, even if they are not in the inventory.
+pub fn resolve_hosts(pattern: &str, inventory: &[String]) -> ExecResult<Vec<String>> {
+    let mut hosts: Vec<String> = vec![];
+
+    for part in pattern.split(',').filter(|p| !p.is_empty()) {
+        if !part.contains(['*', '?']) {
+            if !hosts.iter().any(|h| h == part) { hosts.push(part.to_string()); }
+            continue;
+        }
+
+        let part: Vec<char> = part.chars().collect();
+        let matched: Vec<&String> = inventory.iter()
+            .filter(|h| glob_match(&part, &h.chars().collect::<Vec<_>>()))
+            .collect();
+        if matched.is_empty() {
+            let part: String = part.into_iter().collect();
+            return Err(Error::message(format!("No known hosts match {}", part)));
+        }
+        for host in matched {
+            if !hosts.contains(host) { hosts.push(host.to_string()); }
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Splits the hosts into the batches given by `serial`, or a single batch.
+pub fn plan_batches(hosts: &[String], serial: Option<HostCount>) -> Vec<Vec<String>> {
+    let size = serial.map(|s| s.resolve(hosts.len()).max(1)).unwrap_or(hosts.len()).max(1);
+    hosts.chunks(size).map(|c| c.to_vec()).collect()
+}
+
+/// Runs `run_host` for every host, at most `opts.forks` at a time and batch by batch.
+///
+/// Each host writes into its own buffer, which is printed to `out` as one group once
+/// the host finishes. When more than `max_fail` hosts have failed no further hosts are
+/// started and the remaining ones are reported as skipped.
+pub fn run_rollout<F>(
+    hosts: &[String],
+    opts: &RolloutOptions,
+    out: &mut (dyn Write + Send),
+    run_host: F,
+) -> ExecResult<Vec<(String, HostStatus)>>
+    where F: Fn(&str, &mut Vec<u8>) -> ExecResult<()> + Sync
+{
+    let max_fail = opts.max_fail.map(|m| m.resolve(hosts.len()));
+    let failures = AtomicUsize::new(0);
+    let abort = AtomicBool::new(false);
+    let out = Mutex::new(out);
+    let mut results = vec![];
+
+    for batch in plan_batches(hosts, opts.serial) {
+        let next = AtomicUsize::new(0);
+        let statuses: Mutex<Vec<Option<HostStatus>>> = Mutex::new(vec![None; batch.len()]);
+        let workers = opts.forks.max(1).min(batch.len());
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    if abort.load(Ordering::SeqCst) { break; }
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    if idx >= batch.len() { break; }
+
+                    let host = &batch[idx];
+                    let mut buf = vec![];
+                    let status = match run_host(host, &mut buf) {
+                        Ok(()) => HostStatus::Ok,
+                        Err(e) => {
+                            let failed = failures.fetch_add(1, Ordering::SeqCst) + 1;
+                            if max_fail.is_some_and(|m| failed > m) {
+                                abort.store(true, Ordering::SeqCst);
+                            }
+                            HostStatus::Failed(e.to_string())
+                        }
+                    };
+
+                    let mut out = out.lock().unwrap();
+                    let _ = write_host_group(&mut **out, host, &status, &buf);
+                    statuses.lock().unwrap()[idx] = Some(status);
+                });
+            }
+        });
+
+        let statuses = statuses.into_inner().unwrap();
+        results.extend(batch.into_iter().zip(statuses)
+            .map(|(host, status)| (host, status.unwrap_or(HostStatus::Skipped))));
+    }
+
+    Ok(results)
+}
+
+fn write_host_group(out: &mut dyn Write, host: &str, status: &HostStatus, buf: &[u8]) -> ExecResult<()> {
+    match status {
+        HostStatus::Failed(e) => writeln!(out, "== {} (failed: {}) ==", host, e)?,
+        _ => writeln!(out, "== {} ==", host)?,
+    }
+    out.write_all(buf)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Turns the per-host results of a rollout into the result of the `remotes` block.
+pub fn summarize(results: &[(String, HostStatus)]) -> ExecResult<()> {
+    let failed: Vec<&str> = results.iter()
+        .filter(|(_, s)| matches!(s, HostStatus::Failed(_)))
+        .map(|(h, _)| h.as_str())
+        .collect();
+    let skipped = results.iter().filter(|(_, s)| *s == HostStatus::Skipped).count();
+
+    if skipped > 0 {
+        Err(Error::message(format!("Rollout aborted after {} failed host(s) ({}), {} host(s) skipped",
+            failed.len(), failed.join(", "), skipped)))
+    } else if !failed.is_empty() {
+        Err(Error::message(format!("{} of {} host(s) failed: {}", failed.len(), results.len(), failed.join(", "))))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn hosts(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("h{}", i)).collect()
+    }
+
+    #[test]
+    fn test_resolve_hosts() {
+        let inventory = vec!["prod-1".to_string(), "prod-2".to_string(), "stage-1".to_string()];
+
+        assert_eq!(resolve_hosts("prod-*", &inventory).unwrap(), vec!["prod-1", "prod-2"]);
+        assert_eq!(resolve_hosts("stage-?,prod-2,other", &inventory).unwrap(), vec!["stage-1", "prod-2", "other"]);
+        assert!(resolve_hosts("dev-*", &inventory).is_err());
+    }
+
+    #[test]
+    fn test_plan_batches() {
+        let sizes = |serial| plan_batches(&hosts(10), serial).iter().map(|b| b.len()).collect::<Vec<_>>();
+
+        assert_eq!(sizes(None), vec![10]);
+        assert_eq!(sizes(Some(HostCount::Count(4))), vec![4, 4, 2]);
+        assert_eq!(sizes(Some(HostCount::Percent(25))), vec![3, 3, 3, 1]);
+        assert_eq!(sizes(Some(HostCount::Percent(1))), vec![1; 10]);
+    }
+
+    #[test]
+    fn test_rollout_bounded_by_forks() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let opts = RolloutOptions { forks: 3, serial: None, max_fail: None };
+        let mut out = vec![];
+
+        let results = run_rollout(&hosts(8), &opts, &mut out, |host, buf| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            writeln!(buf, "first line from {}", host)?;
+            thread::sleep(Duration::from_millis(20));
+            writeln!(buf, "second line from {}", host)?;
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert!(results.iter().all(|(_, s)| *s == HostStatus::Ok));
+
+        // Output of each host is printed as one group
+        let out = String::from_utf8(out).unwrap();
+        for host in hosts(8) {
+            let expected = format!("== {} ==\nfirst line from {}\nsecond line from {}\n", host, host, host);
+            assert!(out.contains(&expected), "output of {} is not grouped:\n{}", host, out);
+        }
+    }
+
+    #[test]
+    fn test_rollout_serial_batches() {
+        let order = Mutex::new(vec![]);
+        let opts = RolloutOptions { forks: 10, serial: Some(HostCount::Count(2)), max_fail: None };
+
+        run_rollout(&hosts(5), &opts, &mut vec![], |host, _| {
+            order.lock().unwrap().push(host.to_string());
+            Ok(())
+        }).unwrap();
+
+        // Hosts of a batch may finish in any order, but batches never overlap
+        let mut order = order.into_inner().unwrap();
+        order[0..2].sort();
+        order[2..4].sort();
+        assert_eq!(order, hosts(5));
+    }
+
+    #[test]
+    fn test_rollout_max_fail_aborts() {
+        let opts = RolloutOptions { forks: 1, serial: Some(HostCount::Count(2)), max_fail: Some(HostCount::Count(1)) };
+
+        let results = run_rollout(&hosts(6), &opts, &mut vec![], |host, _| {
+            if host == "h2" || host == "h3" {
+                Err(Error::message("boom"))
+            } else {
+                Ok(())
+            }
+        }).unwrap();
+
+        assert_eq!(results, vec![
+            ("h1".to_string(), HostStatus::Ok),
+            ("h2".to_string(), HostStatus::Failed("boom".to_string())),
+            ("h3".to_string(), HostStatus::Failed("boom".to_string())),
+            ("h4".to_string(), HostStatus::Skipped),
+            ("h5".to_string(), HostStatus::Skipped),
+            ("h6".to_string(), HostStatus::Skipped),
+        ]);
+        assert!(summarize(&results).is_err());
+    }
+}
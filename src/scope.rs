@@ -0,0 +1,85 @@
+//! Variables of a script, from those it starts with to those of the innermost block.
+
+use std::collections::HashMap;
+
+use super::value::Value;
+
+/// A chain of frames: the first holds the variables of the script, and each block being
+/// run has its own after it. Names are looked up from the innermost frame out, and
+/// bound in the innermost one, so they go away when their block ends.
+#[derive(Clone, Debug)]
+pub struct Scope {
+    frames: Vec<HashMap<String, Value>>,
+    /// Given on the command line, these take the place of what `let` binds them to.
+    overrides: HashMap<String, Value>,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Scope { frames: vec![HashMap::new()], overrides: HashMap::new() }
+    }
+}
+
+impl Scope {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.frames.iter().rev().find_map(|frame| frame.get(name))
+    }
+
+    pub fn bind(&mut self, name: &str, value: Value) {
+        self.frames.last_mut().unwrap().insert(name.to_string(), value);
+    }
+
+    /// Binds `name` for the whole script, in place of any `let` of it.
+    pub fn bind_override(&mut self, name: &str, value: Value) {
+        self.frames[0].insert(name.to_string(), value.clone());
+        self.overrides.insert(name.to_string(), value);
+    }
+
+    pub fn overridden(&self, name: &str) -> Option<&Value> {
+        self.overrides.get(name)
+    }
+
+    pub fn push(&mut self) {
+        self.frames.push(HashMap::new());
+    }
+
+    /// Ends the innermost block, the variables of the script are never dropped.
+    pub fn pop(&mut self) {
+        if self.frames.len() > 1 {
+            self.frames.pop();
+        }
+    }
+
+    /// The value of each name as seen from the innermost frame.
+    pub fn visible(&self) -> HashMap<String, Value> {
+        self.frames.iter().flat_map(|frame| frame.iter().map(|(name, value)| (name.to_string(), value.clone()))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope() {
+        let mut scope = Scope::default();
+        scope.bind("host", Value::from("web"));
+        scope.bind_override("version", Value::from("1.2"));
+
+        scope.push();
+        scope.bind("host", Value::from("db"));
+        scope.bind("port", Value::Integer(5432));
+        assert_eq!(scope.get("host"), Some(&Value::from("db")));
+        assert_eq!(scope.visible()["host"], Value::from("db"));
+        assert_eq!(scope.get("version"), Some(&Value::from("1.2")));
+
+        scope.pop();
+        assert_eq!(scope.get("host"), Some(&Value::from("web")));
+        assert_eq!(scope.get("port"), None);
+
+        // The variables of the script stay
+        scope.pop();
+        assert_eq!(scope.get("host"), Some(&Value::from("web")));
+        assert_eq!(scope.overridden("version"), Some(&Value::from("1.2")));
+    }
+}
@@ -0,0 +1,461 @@
+//! Templates for file contents, rendered with the variables of the script.
+//!
+//! `{{ expr }}` is replaced with the value of an expression, which can go through
+//! filters such as `{{ hosts | join(", ") }}`. `{% for x in expr %}` repeats what is
+//! up to `{% endfor %}` for each item, and `{% if expr %}` keeps what is up to
+//! `{% elif expr %}`, `{% else %}` or `{% endif %}` when the expression is true. A line
+//! holding only a tag or a `{# comment #}` is left out of the output.
+
+use std::collections::{BTreeMap, HashMap};
+
+use nom::types::CompleteStr;
+
+use super::ast::{IdemPath, IdemPathLocalPartType, IdemValueType, Spanned};
+use super::edit::json::Json;
+use super::errors::{Error, ErrorKind, Result as ExecResult};
+use super::parser::parse_interpolation;
+use super::value::Value;
+
+/// An error and the line of the template it is on.
+type Result<T> = std::result::Result<T, (usize, String)>;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Text(String),
+    Expr(usize, String),
+    Tag(usize, String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    Expr(usize, String),
+    For { line: usize, var: String, expr: String, body: Vec<Node> },
+    /// The condition and body of the `if` and each `elif`, then the `else` body.
+    If { branches: Vec<(usize, String, Vec<Node>)>, otherwise: Vec<Node> },
+}
+
+/// Where `pattern` is in `text`, outside of quotes and brackets.
+fn find_outside(text: &str, pattern: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') | (None, '[') | (None, '{') => depth += 1,
+            (None, ')') | (None, ']') | (None, '}') => depth -= 1,
+            (None, _) if depth == 0 && text[i..].starts_with(pattern) => return Some(i),
+            _ => {},
+        }
+    }
+    None
+}
+
+fn tokenize(template: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut text = String::new();
+    let mut pos = 0;
+    let mut line = 1;
+
+    while let Some(start) = ["{{", "{%", "{#"].iter().filter_map(|open| template[pos..].find(open)).min().map(|i| pos + i) {
+        text.push_str(&template[pos..start]);
+        line += template[pos..start].matches('\n').count();
+
+        let open = &template[start..start + 2];
+        let close = match open {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let end = template[start + 2..].find(close)
+            .ok_or_else(|| (line, format!("{} is not closed with {}", open, close)))? + start + 2;
+        let inner = template[start + 2..end].trim().to_string();
+        let tag_line = line;
+        line += template[start..end].matches('\n').count();
+        pos = end + 2;
+
+        if open == "{{" {
+            tokens.push(Token::Text(std::mem::take(&mut text)));
+            tokens.push(Token::Expr(tag_line, inner));
+            continue;
+        }
+
+        // A tag or comment alone on its line takes the line with it
+        let line_start = template[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = template[pos..].find('\n').map_or(template.len(), |i| pos + i);
+        if template[line_start..start].trim().is_empty() && template[pos..line_end].trim().is_empty() {
+            let trimmed = text.trim_end_matches([' ', '\t']).len();
+            text.truncate(trimmed);
+            if line_end < template.len() {
+                line += 1;
+            }
+            pos = (line_end + 1).min(template.len());
+        }
+        if open == "{%" {
+            tokens.push(Token::Text(std::mem::take(&mut text)));
+            tokens.push(Token::Tag(tag_line, inner));
+        }
+    }
+
+    text.push_str(&template[pos..]);
+    tokens.push(Token::Text(text));
+    Ok(tokens)
+}
+
+/// `for x in items` is `("for", "x in items")`.
+fn split_tag(tag: &str) -> (&str, &str) {
+    match tag.find(char::is_whitespace) {
+        Some(i) => (&tag[..i], tag[i..].trim()),
+        None => (tag, ""),
+    }
+}
+
+/// A tag ending a block, such as `endfor`, and its line.
+type EndTag = Option<(usize, String)>;
+
+/// The nodes up to the end of the template or a tag ending a block, which is returned
+/// with them.
+fn parse_nodes(tokens: &[Token], pos: &mut usize) -> Result<(Vec<Node>, EndTag)> {
+    let mut nodes = vec![];
+
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+        let (line, tag) = match token {
+            Token::Text(text) => {
+                nodes.push(Node::Text(text.to_string()));
+                continue;
+            }
+            Token::Expr(line, expr) => {
+                nodes.push(Node::Expr(*line, expr.to_string()));
+                continue;
+            }
+            Token::Tag(line, tag) => (*line, tag),
+        };
+
+        match split_tag(tag) {
+            ("for", args) => {
+                let (var, expr) = match find_outside(args, " in ") {
+                    Some(i) => (args[..i].trim(), args[i + 4..].trim()),
+                    None => return Err((line, format!("Expected {{% for name in expr %}}, found {{% {} %}}", tag))),
+                };
+                let (body, end) = parse_nodes(tokens, pos)?;
+                match end {
+                    Some((_, ref end)) if end == "endfor" => {},
+                    _ => return Err((line, "{% for %} is not closed with {% endfor %}".to_string())),
+                }
+                nodes.push(Node::For { line, var: var.to_string(), expr: expr.to_string(), body });
+            }
+
+            ("if", condition) => {
+                let mut branches = vec![];
+                let mut condition = (line, condition.to_string());
+                let mut otherwise = vec![];
+                loop {
+                    let (body, end) = parse_nodes(tokens, pos)?;
+                    branches.push((condition.0, condition.1, body));
+                    match end.as_ref().map(|(line, end)| (*line, split_tag(end))) {
+                        Some((line, ("elif", next))) => condition = (line, next.to_string()),
+                        Some((_, ("else", _))) => {
+                            let (body, end) = parse_nodes(tokens, pos)?;
+                            match end {
+                                Some((_, ref end)) if end == "endif" => {},
+                                _ => return Err((line, "{% if %} is not closed with {% endif %}".to_string())),
+                            }
+                            otherwise = body;
+                            break;
+                        }
+                        Some((_, ("endif", _))) => break,
+                        _ => return Err((line, "{% if %} is not closed with {% endif %}".to_string())),
+                    }
+                }
+                nodes.push(Node::If { branches, otherwise });
+            }
+
+            ("endfor", _) | ("endif", _) | ("elif", _) | ("else", _) => return Ok((nodes, Some((line, tag.to_string())))),
+
+            _ => return Err((line, format!("Unknown tag {{% {} %}}", tag))),
+        }
+    }
+
+    Ok((nodes, None))
+}
+
+fn parse_template(template: &str) -> Result<Vec<Node>> {
+    let tokens = tokenize(template)?;
+    let mut pos = 0;
+    match parse_nodes(&tokens, &mut pos)? {
+        (nodes, None) => Ok(nodes),
+        (_, Some((line, tag))) => Err((line, format!("Unexpected {{% {} %}}", tag))),
+    }
+}
+
+fn to_json(value: &Value) -> Json {
+    match value {
+        Value::Empty => Json::Null,
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Integer(n) => Json::Number(n.to_string()),
+        Value::List(list) => Json::Array(list.iter().map(to_json).collect()),
+        Value::Map(map) => Json::Object(map.iter().map(|(key, value)| (key.to_string(), to_json(value))).collect()),
+        value => Json::String(value.to_string()),
+    }
+}
+
+struct Renderer {
+    vars: HashMap<String, Value>,
+}
+
+impl Renderer {
+    /// The value of a literal or variable, `None` if the variable or a key of it is undefined.
+    fn value_of(&self, line: usize, value: &IdemValueType) -> Result<Option<Value>> {
+        Ok(Some(match value {
+            IdemValueType::Variable(name, accessors) => {
+                let mut value = match self.vars.get(name) {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                for accessor in accessors {
+                    value = match value.get(accessor) {
+                        Some(value) => value,
+                        None => return Ok(None),
+                    };
+                }
+                value.clone()
+            }
+            IdemValueType::LitString(s) | IdemValueType::ExtendedString(s) => Value::String(s.to_string()),
+            IdemValueType::Integer(n) => Value::Integer(*n),
+            IdemValueType::Boolean(b) => Value::Bool(*b),
+            IdemValueType::Empty => Value::Empty,
+            IdemValueType::PathSpec(Spanned { node: IdemPath(_, IdemPathLocalPartType::File(path)), .. }) => Value::Path(path.to_string()),
+            IdemValueType::PathSpec(Spanned { node: IdemPath(_, IdemPathLocalPartType::Directory(dir)), .. }) => Value::Path(format!("{}/", dir)),
+            IdemValueType::List(values) => {
+                let mut list = vec![];
+                for value in values {
+                    list.push(self.value_of(line, value)?.unwrap_or(Value::Empty));
+                }
+                Value::List(list)
+            }
+            IdemValueType::Map(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    map.insert(key.to_string(), self.value_of(line, value)?.unwrap_or(Value::Empty));
+                }
+                Value::Map(map)
+            }
+            IdemValueType::Command(_) => return Err((line, "Commands can not run in templates".to_string())),
+        }))
+    }
+
+    /// A value without filters, quoted strings are taken as they are.
+    fn operand(&self, line: usize, text: &str) -> Result<Option<Value>> {
+        let text = text.trim();
+        for quote in &['"', '\''] {
+            if text.len() >= 2 && text.starts_with(*quote) && text.ends_with(*quote) && !text[1..text.len() - 1].contains(*quote) {
+                return Ok(Some(Value::String(text[1..text.len() - 1].to_string())));
+            }
+        }
+        match parse_interpolation(CompleteStr(text)) {
+            Ok((_, value)) => self.value_of(line, &value),
+            Err(_) => Err((line, format!("Invalid expression: {}", text))),
+        }
+    }
+
+    /// The value of `expr | filter | filter(arg)`, `None` if it is undefined.
+    fn expression(&self, line: usize, expr: &str) -> Result<Option<Value>> {
+        let mut parts = vec![];
+        let mut rest = expr;
+        while let Some(i) = find_outside(rest, "|") {
+            parts.push(&rest[..i]);
+            rest = &rest[i + 1..];
+        }
+        parts.push(rest);
+
+        let mut value = self.operand(line, parts[0])?;
+        for filter in &parts[1..] {
+            value = Some(self.filter(line, filter.trim(), value, parts[0].trim())?);
+        }
+        Ok(value)
+    }
+
+    fn filter(&self, line: usize, filter: &str, value: Option<Value>, name: &str) -> Result<Value> {
+        let (filter, arg) = match filter.find('(') {
+            Some(i) if filter.ends_with(')') => (filter[..i].trim(), Some(filter[i + 1..filter.len() - 1].trim())),
+            _ => (filter, None),
+        };
+        let arg = match arg.filter(|arg| !arg.is_empty()) {
+            Some(arg) => Some(self.operand(line, arg)?.ok_or_else(|| (line, format!("{} is not defined", arg)))?),
+            None => None,
+        };
+
+        if filter == "default" {
+            return Ok(match value {
+                Some(Value::Empty) | None => arg.unwrap_or(Value::Empty),
+                Some(value) => value,
+            });
+        }
+        let value = value.ok_or_else(|| (line, format!("{} is not defined", name)))?;
+
+        match filter {
+            "upper" => Ok(Value::String(value.to_string().to_uppercase())),
+            "join" => match value {
+                Value::List(list) => {
+                    let separator = arg.map(|arg| arg.to_string()).unwrap_or_default();
+                    Ok(Value::String(list.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(&separator)))
+                }
+                value => Err((line, format!("join needs a list, not {}", value.literal()))),
+            },
+            "indent" => {
+                let width = match arg {
+                    Some(Value::Integer(n)) if n >= 0 => n as usize,
+                    None => 4,
+                    Some(arg) => return Err((line, format!("indent needs a number of spaces, not {}", arg.literal()))),
+                };
+                let text = value.to_string();
+                let lines: Vec<String> = text.split('\n').enumerate()
+                    .map(|(i, l)| if i == 0 || l.is_empty() { l.to_string() } else { format!("{}{}", " ".repeat(width), l) })
+                    .collect();
+                Ok(Value::String(lines.join("\n")))
+            }
+            "to_json" => Ok(Value::String(to_json(&value).to_string())),
+            _ => Err((line, format!("Unknown filter: {}", filter))),
+        }
+    }
+
+    /// `expr`, `not expr`, `a == b` or `a != b`, where an undefined value is false.
+    fn condition(&self, line: usize, condition: &str) -> Result<bool> {
+        let condition = condition.trim();
+        if let Some(rest) = condition.strip_prefix("not ") {
+            return Ok(!self.condition(line, rest)?);
+        }
+        for &(op, equal) in &[("==", true), ("!=", false)] {
+            if let Some(i) = find_outside(condition, op) {
+                let a = self.expression(line, &condition[..i])?.map(|value| value.to_string());
+                let b = self.expression(line, &condition[i + 2..])?.map(|value| value.to_string());
+                return Ok((a == b) == equal);
+            }
+        }
+        Ok(self.expression(line, condition)?.is_some_and(|value| value.is_true()))
+    }
+
+    fn render(&mut self, nodes: &[Node], out: &mut String) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Expr(line, expr) => {
+                    let value = self.expression(*line, expr)?.ok_or_else(|| (*line, format!("{} is not defined", expr)))?;
+                    out.push_str(&value.to_string());
+                }
+                Node::For { line, var, expr, body } => {
+                    let items = self.expression(*line, expr)?.ok_or_else(|| (*line, format!("{} is not defined", expr)))?.into_items();
+                    let shadowed: Vec<(&str, Option<Value>)> = vec![(var, self.vars.remove(var)), ("loop", self.vars.remove("loop"))];
+
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let mut info = BTreeMap::new();
+                        info.insert("index".to_string(), Value::Integer(i as i64 + 1));
+                        info.insert("first".to_string(), Value::Bool(i == 0));
+                        info.insert("last".to_string(), Value::Bool(i + 1 == count));
+                        self.vars.insert("loop".to_string(), Value::Map(info));
+                        self.vars.insert(var.to_string(), item);
+                        self.render(body, out)?;
+                    }
+
+                    for (name, value) in shadowed {
+                        self.vars.remove(name);
+                        if let Some(value) = value {
+                            self.vars.insert(name.to_string(), value);
+                        }
+                    }
+                }
+                Node::If { branches, otherwise } => {
+                    let mut chosen = otherwise;
+                    for (line, condition, body) in branches {
+                        if self.condition(*line, condition)? {
+                            chosen = body;
+                            break;
+                        }
+                    }
+                    self.render(chosen, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders a template read from `name` with the variables in scope, errors give the
+/// line of the template.
+pub fn render_template(name: &str, template: &str, vars: &HashMap<String, Value>) -> ExecResult<String> {
+    let nodes = parse_template(template)
+        .map_err(|(line, e)| Error::new(ErrorKind::Parse, format!("{} line {}: {}", name, line, e)))?;
+    let mut out = String::new();
+    Renderer { vars: vars.clone() }.render(&nodes, &mut out)
+        .map_err(|(line, e)| Error::message(format!("{} line {}: {}", name, line, e)))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HashMap<String, Value> {
+        let mut site = BTreeMap::new();
+        site.insert("name".to_string(), Value::from("blog"));
+        site.insert("port".to_string(), Value::Integer(8080));
+
+        let mut vars = HashMap::new();
+        vars.insert("sites".to_string(), Value::List(vec![Value::Map(site.clone()), Value::Map(site)]));
+        vars.insert("hosts".to_string(), Value::List(vec![Value::from("web1"), Value::from("web2")]));
+        vars.insert("tls".to_string(), Value::Bool(true));
+        vars
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template = "\
+{# generated #}
+upstream app {
+    {% for host in hosts %}
+    server {{ host }}:80;{% if loop.last %} # last{% endif %}
+    {% endfor %}
+}
+{% if not tls %}
+listen 80;
+{% elif sites[0].port == 8080 %}
+listen 443 ssl; # {{ sites[0].name | upper }}
+{% else %}
+listen 8443 ssl;
+{% endif %}
+names {{ hosts | join(\", \") }}; {{ missing | default(\"none\") }}
+";
+        assert_eq!(render_template("nginx.conf.tpl", template, &vars()).unwrap(), "\
+upstream app {
+    server web1:80;
+    server web2:80; # last
+}
+listen 443 ssl; # BLOG
+names web1, web2; none
+");
+    }
+
+    #[test]
+    fn test_render_filters() {
+        let vars = vars();
+        assert_eq!(render_template("t", "{{ hosts | to_json }}", &vars).unwrap(), r#"["web1", "web2"]"#);
+        assert_eq!(render_template("t", "x:\n  {{ \"a\nb\" | indent(2) }}", &vars).unwrap(), "x:\n  a\n  b");
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let vars = vars();
+        let err = render_template("app.tpl", "a\n{{ nope }}\n", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "app.tpl line 2: nope is not defined");
+        let err = render_template("app.tpl", "a\n\n{% for h in hosts %}\n{{ h }}\n", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "app.tpl line 3: {% for %} is not closed with {% endfor %}");
+        let err = render_template("app.tpl", "{{ hosts | shout }}", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "app.tpl line 1: Unknown filter: shout");
+        let err = render_template("app.tpl", "\n{% endif %}", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "app.tpl line 2: Unexpected {% endif %}");
+    }
+}
@@ -1,14 +1,119 @@
 
+use std::time::Duration;
+
 use super::errors::Result as ExecResult;
+use super::escalate::Escalation;
 
 pub enum FileContents {
     StaticString(String)
 }
 
+/// An external command to run on the target.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Process {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    /// Relative to the driver's working directory.
+    pub cwd: Option<String>,
+    pub stdin: Option<String>,
+    /// The process is killed once it runs longer than this.
+    pub timeout: Option<Duration>,
+}
+
+impl Process {
+    pub fn new(program: &str) -> Self {
+        Process { program: program.to_string(), ..Process::default() }
+    }
+}
+
+/// The exit status and captured output of a command run on the target.
+///
+/// A process killed by a signal has the status 128 + the signal number.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CommandOutput {
+    pub status: i32,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
 pub trait Exec {
     fn change_directory(&mut self, dir: &str) -> ExecResult<()>;
-    fn ensure_directory(&mut self, local_part: &str) -> ExecResult<()>;
-    fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<()>;
-    fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<()>;
+    // The `ensure_` operations return whether they changed anything.
+    fn ensure_directory(&mut self, local_part: &str) -> ExecResult<bool>;
+    fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<bool>;
+    fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<bool>;
     fn get_cwd(&mut self) -> ExecResult<String>;
+    fn path_exists(&mut self, local_part: &str) -> ExecResult<bool>;
+    /// The contents of a file, or `None` if it does not exist.
+    fn read_file(&mut self, local_part: &str) -> ExecResult<Option<String>>;
+    /// Creates a new temporary directory, or an empty file, whose name ends with
+    /// `suffix`, returning its absolute path.
+    fn create_temporary(&mut self, directory: bool, suffix: &str) -> ExecResult<String>;
+    /// Removes a file, or a directory and everything in it. A missing path is not an error.
+    fn remove_path(&mut self, local_part: &str) -> ExecResult<()>;
+
+    fn execute(&mut self, process: &Process) -> ExecResult<CommandOutput>;
+
+    /// Runs `script` by passing it on stdin to `interpreter`, such as `bash` or `python3`.
+    fn run_script(&mut self, interpreter: &str, script: &str) -> ExecResult<CommandOutput> {
+        self.execute(&Process {
+            stdin: Some(script.to_string()),
+            ..Process::new(interpreter)
+        })
+    }
+
+    /// Opens a driver on the same host whose operations run as another user.
+    fn escalate(&mut self, escalation: &Escalation) -> ExecResult<Box<dyn Exec + Send>>;
+}
+
+impl<T: Exec + ?Sized> Exec for Box<T> {
+    fn change_directory(&mut self, dir: &str) -> ExecResult<()> {
+        (**self).change_directory(dir)
+    }
+
+    fn ensure_directory(&mut self, local_part: &str) -> ExecResult<bool> {
+        (**self).ensure_directory(local_part)
+    }
+
+    fn ensure_file_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+        (**self).ensure_file_exists(local_part)
+    }
+
+    fn ensure_file_contents(&mut self, local_part: &str, contents: FileContents) -> ExecResult<bool> {
+        (**self).ensure_file_contents(local_part, contents)
+    }
+
+    fn get_cwd(&mut self) -> ExecResult<String> {
+        (**self).get_cwd()
+    }
+
+    fn path_exists(&mut self, local_part: &str) -> ExecResult<bool> {
+        (**self).path_exists(local_part)
+    }
+
+    fn read_file(&mut self, local_part: &str) -> ExecResult<Option<String>> {
+        (**self).read_file(local_part)
+    }
+
+    fn create_temporary(&mut self, directory: bool, suffix: &str) -> ExecResult<String> {
+        (**self).create_temporary(directory, suffix)
+    }
+
+    fn remove_path(&mut self, local_part: &str) -> ExecResult<()> {
+        (**self).remove_path(local_part)
+    }
+
+    fn execute(&mut self, process: &Process) -> ExecResult<CommandOutput> {
+        (**self).execute(process)
+    }
+
+    fn run_script(&mut self, interpreter: &str, script: &str) -> ExecResult<CommandOutput> {
+        (**self).run_script(interpreter, script)
+    }
+
+    fn escalate(&mut self, escalation: &Escalation) -> ExecResult<Box<dyn Exec + Send>> {
+        (**self).escalate(escalation)
+    }
 }
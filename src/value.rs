@@ -0,0 +1,196 @@
+//! Values produced by statements and expressions.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::ast::{IdemAccessor, IdemRawCommandType, Spanned};
+use super::traits::CommandOutput;
+
+/// Why a command failed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Failure {
+    /// An external command exited with a non-zero status.
+    ResultCode(i32),
+    /// An external command ran past its timeout and was killed.
+    TimedOut,
+    /// A statement raised an error, such as a file that could not be written.
+    Error(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::ResultCode(code) => write!(f, "ResultCode({})", code),
+            Failure::TimedOut => write!(f, "TimedOut"),
+            Failure::Error(message) => write!(f, "Error({:?})", message),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    /// The empty value `(())`, returned by commands that succeed without a result.
+    Empty,
+    Bool(bool),
+    Integer(i64),
+    String(String),
+    /// A path on the current host, directories end with `/`.
+    Path(String),
+    Host(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    Failure(Failure),
+    /// The block given to a block command, run with `call`.
+    Block(Vec<Spanned<IdemRawCommandType>>),
+}
+
+impl Value {
+    /// The result of an external command, following unix conventions for the exit
+    /// status. With `capture` a successful command returns its output.
+    pub fn from_output(output: &CommandOutput, capture: bool) -> Self {
+        match output {
+            CommandOutput { timed_out: true, .. } => Value::Failure(Failure::TimedOut),
+            CommandOutput { status: 0, .. } if capture => Value::String(output.stdout.to_string()),
+            CommandOutput { status: 0, .. } => Value::Empty,
+            CommandOutput { status, .. } => Value::Failure(Failure::ResultCode(*status)),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        !matches!(self, Value::Failure(_))
+    }
+
+    /// Empty values, `false`, `0`, empty strings and collections and failures are false.
+    pub fn is_true(&self) -> bool {
+        match self {
+            Value::Empty | Value::Bool(false) | Value::Integer(0) | Value::Failure(_) => false,
+            Value::String(s) => !s.is_empty(),
+            Value::List(list) => !list.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// Looks up `.key` in a map or `[index]` in a list, negative indexes count from
+    /// the end.
+    pub fn get(&self, accessor: &IdemAccessor) -> Option<&Value> {
+        match (self, accessor) {
+            (Value::Map(map), IdemAccessor::Key(key)) => map.get(key),
+            (Value::List(list), IdemAccessor::Index(i)) => {
+                let i = if *i < 0 { list.len() as i64 + i } else { *i };
+                usize::try_from(i).ok().and_then(|i| list.get(i))
+            }
+            _ => None,
+        }
+    }
+
+    /// The values an `each` loop iterates over. Maps give a `{ key, value }` map per
+    /// entry, and any other value is iterated once.
+    pub fn into_items(self) -> Vec<Value> {
+        match self {
+            Value::List(list) => list,
+            Value::Map(map) => map.into_iter()
+                .map(|(key, value)| {
+                    let mut entry = BTreeMap::new();
+                    entry.insert("key".to_string(), Value::String(key));
+                    entry.insert("value".to_string(), value);
+                    Value::Map(entry)
+                })
+                .collect(),
+            Value::Empty => vec![],
+            value => vec![value],
+        }
+    }
+
+    /// The value as it is written in a script, with strings quoted.
+    pub fn literal(&self) -> String {
+        match self {
+            Value::Empty => "(())".to_string(),
+            Value::String(s) => format!("{:?}", s),
+            Value::List(list) => {
+                let items: Vec<String> = list.iter().map(|v| v.literal()).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Map(map) => {
+                let entries: Vec<String> = map.iter().map(|(k, v)| format!("{} = {}", k, v.literal())).collect();
+                format!("{{ {} }}", entries.join(", "))
+            }
+            value => value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Empty => Ok(()),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::String(s) | Value::Path(s) | Value::Host(s) => write!(f, "{}", s),
+            Value::List(_) | Value::Map(_) => write!(f, "{}", self.literal()),
+            Value::Failure(failure) => write!(f, "Failure({})", failure),
+            Value::Block(_) => write!(f, "<block>"),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+/// A statement param with its value evaluated.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Param {
+    Flag(String),
+    KeyValue(String, Value),
+    Positional(Value),
+}
+
+pub fn find_value<'a>(params: &'a [Param], key: &str) -> Option<&'a Value> {
+    params.iter().find_map(|param| match param {
+        Param::KeyValue(ref k, ref value) if k == key => Some(value),
+        _ => None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_output() {
+        let output = |status, timed_out| CommandOutput { status, timed_out, stdout: "built\n".to_string(), ..CommandOutput::default() };
+
+        assert_eq!(Value::from_output(&output(0, false), false), Value::Empty);
+        assert_eq!(Value::from_output(&output(0, false), true), Value::String("built\n".to_string()));
+        assert_eq!(Value::from_output(&output(2, false), true), Value::Failure(Failure::ResultCode(2)));
+        assert_eq!(Value::from_output(&output(137, true), false), Value::Failure(Failure::TimedOut));
+        assert_eq!(Value::Failure(Failure::ResultCode(2)).to_string(), "Failure(ResultCode(2))");
+    }
+
+    #[test]
+    fn test_access_and_display() {
+        let mut map = BTreeMap::new();
+        map.insert("name".to_string(), Value::from("web"));
+        map.insert("ports".to_string(), Value::List(vec![Value::Integer(80), Value::Integer(443)]));
+        let value = Value::Map(map);
+
+        let ports = value.get(&IdemAccessor::Key("ports".to_string())).unwrap();
+        assert_eq!(ports.get(&IdemAccessor::Index(-1)), Some(&Value::Integer(443)));
+        assert_eq!(ports.get(&IdemAccessor::Index(2)), None);
+        assert_eq!(value.get(&IdemAccessor::Index(0)), None);
+        assert_eq!(value.to_string(), r#"{ name = "web", ports = [80, 443] }"#);
+        assert_eq!(Value::from("web").to_string(), "web");
+        assert_eq!(Value::List(vec![Value::Empty]).to_string(), "[(())]");
+        assert_eq!(Value::Empty.to_string(), "");
+    }
+}
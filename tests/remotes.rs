@@ -0,0 +1,214 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Writes executable scripts standing in for commands on the host.
+fn stand_ins(dir: &Path, scripts: &[(&str, &str)]) {
+    fs::create_dir_all(dir).unwrap();
+    for (name, script) in scripts {
+        let path = dir.join(name);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+// ssh runs its arguments joined by spaces with the shell of the host, here the
+// directory of the host under `root`.
+fn ssh_stand_in(root: &Path) -> String {
+    format!("#!/bin/sh\nhost=$1\nshift\ncd {}/$host && exec sh -c \"$*\"\n", root.display())
+}
+
+// A `PATH` finding the stand-ins in `dir` first, then `idemsh`.
+fn stand_in_path(dir: &Path) -> String {
+    let bin_dir: PathBuf = Path::new(env!("CARGO_BIN_EXE_idemsh")).parent().unwrap().into();
+    format!("{}:{}:{}", dir.display(), bin_dir.display(), std::env::var("PATH").unwrap())
+}
+
+// Every "host" is a local `idemsh agent` process rooted in its own directory.
+#[test]
+fn test_remotes_with_local_agents() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testing/remotes");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let script = root.join("script.idem");
+    fs::write(&script, r#"
+remotes host in agent-* (serial=2)
+    ./conf/ (exists)
+    ./conf/afile (exists)
+end
+"#).unwrap();
+
+    let agent_command = format!("{} agent --root {}/{{host}}", env!("CARGO_BIN_EXE_idemsh"), root.display());
+    let output = Command::new(env!("CARGO_BIN_EXE_idemsh"))
+        .arg("run")
+        .arg(&script)
+        .args(["--forks", "2", "--hosts", "agent-1,agent-2,agent-3,other"])
+        .args(["--agent-command", &agent_command])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    for host in &["agent-1", "agent-2", "agent-3"] {
+        assert!(root.join(host).join("conf/afile").is_file(), "{} was not provisioned", host);
+    }
+    assert!(!root.join("other").exists());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("== agent-3 ==\nchanged: ./conf/ (exists)\nchanged: ./conf/afile (exists)\n"), "{}", stdout);
+}
+
+// The escalation command is configured to leave a marker instead of switching users.
+#[test]
+fn test_remotes_become_with_local_agents() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testing/remotes-become");
+    let _ = fs::remove_dir_all(&root);
+    for host in &["agent-1", "agent-2"] {
+        fs::create_dir_all(root.join(host)).unwrap();
+    }
+    let bin = root.join("bin");
+    stand_ins(&bin, &[
+        ("ssh", &ssh_stand_in(&root)),
+    ]);
+
+    let script = root.join("script.idem");
+    fs::write(&script, r#"
+remotes agent-* as deploy
+    ./etc/ (exists)
+    ./etc/motd (content="hello", become=admin)
+end
+"#).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_idemsh"))
+        .arg("run")
+        .arg(&script)
+        .args(["--hosts", "agent-1,agent-2"])
+        .args(["--become-method", "touch became-{user} && {command}"])
+        .env("PATH", stand_in_path(&bin))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    for host in &["agent-1", "agent-2"] {
+        let host = root.join(host);
+        assert!(host.join("became-deploy").is_file());
+        assert!(host.join("became-admin").is_file());
+        assert_eq!(fs::read_to_string(host.join("etc/motd")).unwrap(), "hello");
+    }
+}
+
+// sudo is a stand-in recording its arguments, then running the command after `--`.
+#[test]
+fn test_remotes_become_with_sudo_over_ssh() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testing/remotes-sudo");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("agent-1")).unwrap();
+    let bin = root.join("bin");
+    stand_ins(&bin, &[
+        ("ssh", &ssh_stand_in(&root)),
+        ("sudo", "#!/bin/sh\nprintf '%s\\n' \"$*\" >> sudo-args\nwhile [ \"$1\" != -- ]; do shift; done\nshift\nexec \"$@\"\n"),
+    ]);
+
+    let script = root.join("script.idem");
+    fs::write(&script, r#"
+remotes agent-1
+    ./etc/ (exists, become=app)
+end
+"#).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_idemsh"))
+        .arg("run")
+        .arg(&script)
+        .args(["--hosts", "agent-1"])
+        .env("PATH", stand_in_path(&bin))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let host = root.join("agent-1");
+    assert_eq!(fs::read_to_string(host.join("sudo-args")).unwrap(), "-n -u app -- idemsh agent\n");
+    assert!(host.join("etc").is_dir());
+}
+
+#[test]
+fn test_remotes_using_with_local_agents() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testing/remotes-using");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let script = root.join("script.idem");
+    fs::write(&script, r#"
+remotes host in agent-1,agent-2 using sh (creates="./marker")
+    for i in 1 2; do
+        echo "{{ host }} $i" >> ./marker
+    done
+    cat ./marker
+end
+"#).unwrap();
+
+    let run = || Command::new(env!("CARGO_BIN_EXE_idemsh"))
+        .arg("run")
+        .arg(&script)
+        .args(["--agent-command", &format!("{} agent --root {}/{{host}}", env!("CARGO_BIN_EXE_idemsh"), root.display())])
+        .output()
+        .unwrap();
+
+    let output = run();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(root.join("agent-2/marker")).unwrap(), "agent-2 1\nagent-2 2\n");
+    assert!(String::from_utf8(output.stdout).unwrap().contains("== agent-1 ==\nagent-1 1\nagent-1 2\nok: using sh\n"));
+
+    // The second run is skipped by the creates guard
+    let output = run();
+    assert!(String::from_utf8(output.stdout).unwrap().contains("== agent-1 ==\nskipped: using sh (./marker exists)\n"));
+    assert_eq!(fs::read_to_string(root.join("agent-2/marker")).unwrap(), "agent-2 1\nagent-2 2\n");
+}
+
+// Ctrl-C stops the script at its next statement, and the temporary directory on the
+// host is still removed through its agent.
+#[test]
+fn test_remotes_temporary_removed_on_interrupt() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testing/remotes-temporary");
+    let _ = fs::remove_dir_all(&root);
+    let tmp = root.join("tmp");
+    fs::create_dir_all(&tmp).unwrap();
+
+    let script = root.join("script.idem");
+    fs::write(&script, r#"
+remotes host in agent-1
+    with ~~build/ as d
+        $d/started (content = "yes")
+        system (sleep 1)
+        ./unreached (exists)
+    end
+end
+"#).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_idemsh"))
+        .arg("run")
+        .arg(&script)
+        .args(["--agent-command", &format!("{} agent --root {}/{{host}}", env!("CARGO_BIN_EXE_idemsh"), root.display())])
+        .env("TMPDIR", &tmp)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Interrupt once the block has started
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !fs::read_dir(&tmp).unwrap().any(|dir| dir.unwrap().path().join("started").is_file()) {
+        assert!(Instant::now() < deadline, "the block did not start");
+        thread::sleep(Duration::from_millis(10));
+    }
+    Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(!output.status.success());
+    let printed = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    assert!(printed.contains("Interrupted"), "{}", printed);
+    assert_eq!(fs::read_dir(&tmp).unwrap().count(), 0, "the temporary directory was not removed");
+    assert!(!root.join("agent-1/unreached").exists());
+}